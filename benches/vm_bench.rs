@@ -0,0 +1,82 @@
+//! VM dispatch loop benchmarks
+//!
+//! CI compares results with the baseline in `benches/baseline` and fails if
+//! any benchmark is at least 10% slower.  To refresh it, run the CI workflow
+//! manually and copy the `main` directories of its `bench-baseline` artifact
+//! there, or save one locally with `cargo bench --bench vm_bench --
+//! --save-baseline main` and copy `target/criterion/*/*/main`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use enaa::asm::parse;
+use enaa::programs::DECRYPTER_SOURCE;
+use enaa::vm::{run, VmBuilder};
+
+fn count_down(c: &mut Criterion) {
+    let mut group = c.benchmark_group("count_down");
+    for n in [1_000, 10_000, 100_000] {
+        let program = parse(&format!("push {}\nloop: dec\ndup\nbnez loop\nexit", n)).unwrap();
+        group.throughput(Throughput::Elements(n));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &program, |b, program| {
+            b.iter(|| run(program, "").unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn decrypter(c: &mut Criterion) {
+    let program = parse(DECRYPTER_SOURCE).unwrap();
+    let mut group = c.benchmark_group("decrypter");
+    for len in [256, 1024, 4096] {
+        let input = "a".repeat(len);
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(len), &input, |b, input| {
+            b.iter(|| run(&program, input).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn arithmetic(c: &mut Criterion) {
+    let mut group = c.benchmark_group("arithmetic");
+    for n in [1_000, 10_000, 100_000] {
+        let source = format!(
+            "push 0\npush {}\nloop: swap\npush 7\nadd\npush 11\nadd\nswap\ndec\ndup\nbnez loop\nexit",
+            n
+        );
+        let program = parse(&source).unwrap();
+        group.throughput(Throughput::Elements(n));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &program, |b, program| {
+            b.iter(|| run(program, "").unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// Run a short program 100 000 times, creating a VM for each run or
+/// resetting the same one.
+fn rerun(c: &mut Criterion) {
+    let program = parse(DECRYPTER_SOURCE).unwrap();
+    let mut group = c.benchmark_group("rerun");
+    group.throughput(Throughput::Elements(100_000));
+    group.bench_function("fresh", |b| {
+        b.iter(|| {
+            for _ in 0..100_000 {
+                run(&program, "abc").unwrap();
+            }
+        })
+    });
+    group.bench_function("reset", |b| {
+        let mut vm = VmBuilder::new(&program).build();
+        b.iter(|| {
+            for _ in 0..100_000 {
+                vm.reset("abc");
+                vm.run().unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, count_down, decrypter, arithmetic, rerun);
+criterion_main!(benches);
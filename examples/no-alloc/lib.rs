@@ -0,0 +1,92 @@
+//! Caesar decrypter running on `VmFixed` without heap allocator.
+//!
+//! Build with `cargo build --target thumbv7m-none-eabi`.
+
+#![no_std]
+
+use enaa::io::{OutputError, VmOutput};
+use enaa::vm::{Opcode, VmDefault};
+
+/// `enaa::programs::DECRYPTER_SOURCE` assembled.
+const DECRYPTER: [u8; 27] = [
+    Opcode::Push as u8,
+    4,
+    Opcode::Popa as u8,
+    Opcode::In as u8,
+    Opcode::Dup as u8,
+    Opcode::BneRel as u8,
+    1,
+    Opcode::Exit as u8,
+    Opcode::Pusha as u8,
+    Opcode::Add as u8,
+    Opcode::Dup as u8,
+    Opcode::Push as u8,
+    b'z',
+    Opcode::BleRel as u8,
+    3,
+    Opcode::Push as u8,
+    26,
+    Opcode::Sub as u8,
+    Opcode::Out as u8,
+    Opcode::Pusha as u8,
+    Opcode::Inc as u8,
+    Opcode::Push as u8,
+    26,
+    Opcode::Rem as u8,
+    Opcode::Popa as u8,
+    Opcode::JmpRel as u8,
+    -24i8 as u8,
+];
+
+/// Output written to a byte buffer as UTF-8.
+struct Buffer<'a> {
+    bytes: &'a mut [u8],
+    len: usize,
+}
+
+impl VmOutput for Buffer<'_> {
+    fn write_u32(&mut self, v: u32) -> Result<(), OutputError> {
+        let ch = char::from_u32(v).ok_or(OutputError::new("invalid code point"))?;
+        let end = self.len + ch.len_utf8();
+        let dest = self
+            .bytes
+            .get_mut(self.len..end)
+            .ok_or(OutputError::new("output buffer full"))?;
+        ch.encode_utf8(dest);
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Decrypt the `input_len` bytes of UTF-8 at `input` into the `output_len`
+/// bytes at `output` and return the number of bytes written, or -1 on error.
+///
+/// # Safety
+///
+/// `input` and `output` must be valid for reads and writes respectively of
+/// their length.
+#[no_mangle]
+pub unsafe extern "C" fn enaa_decrypt(
+    input: *const u8,
+    input_len: usize,
+    output: *mut u8,
+    output_len: usize,
+) -> isize {
+    let input = core::slice::from_raw_parts(input, input_len);
+    let Ok(input) = core::str::from_utf8(input) else {
+        return -1;
+    };
+    let mut buffer = Buffer {
+        bytes: core::slice::from_raw_parts_mut(output, output_len),
+        len: 0,
+    };
+    match VmDefault::new().run(&DECRYPTER, input, &mut buffer) {
+        Ok(()) => buffer.len as isize,
+        Err(_) => -1,
+    }
+}
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
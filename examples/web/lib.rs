@@ -0,0 +1,3 @@
+//! Browser bindings of the VM, built with `wasm-pack`
+
+pub use enaa::wasm::*;
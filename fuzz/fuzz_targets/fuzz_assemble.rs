@@ -0,0 +1,39 @@
+//! Assemble arbitrary instruction sequences.
+//!
+//! Every 4 input bytes make an instruction: opcode, optional label, operand
+//! kind and operand.  This covers undefined and duplicate labels, operands of
+//! any width on any opcode, including register indices and relative branches
+//! out of range, and widening of branches in programs over 256 bytes.  All
+//! must be reported as errors rather than panics, and assembled programs must
+//! disassemble.
+//!
+//! Run with `cargo fuzz run fuzz_assemble fuzz/seeds/fuzz_assemble` from the
+//! repository root.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use enaa::asm::{assemble, disassemble, Insn};
+use enaa::vm::Opcode;
+
+fuzz_target!(|data: &[u8]| {
+    let opcodes: Vec<_> = Opcode::all().collect();
+    let insns: Vec<_> = data
+        .chunks_exact(4)
+        .map(|chunk| {
+            let mut insn = Insn::new(opcodes[chunk[0] as usize % opcodes.len()]);
+            if chunk[1] & 0x80 != 0 {
+                insn = insn.set_label(format!("l{}", chunk[1] & 7));
+            }
+            match chunk[2] % 3 {
+                0 => insn,
+                1 => insn.set_value(u32::from(chunk[3]) << (8 * (chunk[2] / 3 % 4))),
+                _ => insn.set_target(format!("l{}", chunk[3] & 7)),
+            }
+        })
+        .collect();
+    if let Ok(bytecode) = assemble(&insns) {
+        assert!(bytecode.is_empty() || disassemble(&bytecode).is_ok());
+    }
+});
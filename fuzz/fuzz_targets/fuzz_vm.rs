@@ -0,0 +1,26 @@
+//! Run arbitrary bytes as bytecode.
+//!
+//! Covers invalid opcodes, truncated operands, branches out of the program,
+//! values that are not Unicode scalar values reaching `Out`, stack and heap
+//! accesses out of bounds and self-patching code.  All must be reported as
+//! errors rather than panics.  Steps are bounded so that loops terminate.
+//!
+//! Run with `cargo fuzz run fuzz_vm fuzz/seeds/fuzz_vm` from the repository
+//! root.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use enaa::vm::{run_with_options, VmOptions};
+
+fuzz_target!(|program: &[u8]| {
+    if program.is_empty() {
+        return;
+    }
+    let opts = VmOptions {
+        max_steps: Some(10_000),
+        ..VmOptions::default()
+    };
+    let _ = run_with_options(program, "Hello, world!\n", &opts);
+});
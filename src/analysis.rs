@@ -0,0 +1,372 @@
+//! Static bytecode analysis
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::asm::*;
+use crate::vm::*;
+
+/// Single instruction decoded from bytecode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decoded {
+    pub offset: usize,
+    pub opcode: Opcode,
+    pub operand: Option<u32>,
+}
+
+impl Decoded {
+    /// Offset of the instruction following this one.
+    pub fn next(&self) -> usize {
+        self.offset + 1 + self.opcode.operand_len()
+    }
+
+    /// Offset this instruction may jump to.
+    pub fn target(&self) -> Option<usize> {
+        if self.opcode.is_branch() {
+            self.operand
+                .map(|operand| self.opcode.branch_target(self.offset, operand))
+        } else {
+            None
+        }
+    }
+
+    /// Offsets of the instructions that may execute after this one.
+    pub fn successors(&self) -> Vec<usize> {
+        let mut successors = Vec::new();
+        if !self.opcode.is_terminator() {
+            successors.push(self.next());
+        }
+        if let Some(target) = self.target() {
+            successors.push(target);
+        }
+        successors
+    }
+}
+
+/// Decode bytecode sequence into instructions.
+pub fn decode(bytecode: &[u8]) -> anyhow::Result<Vec<Decoded>> {
+    let mut insns = Vec::new();
+    let mut offset = 0;
+    while offset < bytecode.len() {
+        let opcode = Opcode::try_from(bytecode[offset])
+            .with_context(|| format!("decoding offset {}", offset))?;
+        let operand_len = opcode.operand_len();
+        let operand = if operand_len == 0 {
+            None
+        } else {
+            let bytes = bytecode
+                .get(offset + 1..offset + 1 + operand_len)
+                .ok_or_else(|| anyhow!("missing operand for {:?} at offset {}", opcode, offset))?;
+            Some(bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u32))
+        };
+        let insn = Decoded {
+            offset,
+            opcode,
+            operand,
+        };
+        offset = insn.next();
+        insns.push(insn);
+    }
+    Ok(insns)
+}
+
+/// Tell which decoded instructions are reachable from offset 0.
+///
+/// Branches to offsets that do not start an instruction are ignored.
+pub fn reachable(decoded: &[Decoded]) -> Vec<bool> {
+    let mut live = vec![false; decoded.len()];
+    let mut worklist = vec![0];
+    while let Some(offset) = worklist.pop() {
+        if let Ok(index) = decoded.binary_search_by_key(&offset, |d| d.offset) {
+            if !live[index] {
+                live[index] = true;
+                worklist.extend(decoded[index].successors());
+            }
+        }
+    }
+    live
+}
+
+/// Strip instructions unreachable from offset 0 and update branch targets
+/// to the new offsets.
+///
+/// Programs that patch their own code are rejected as the addresses they
+/// patch would move.
+pub fn eliminate_dead_code(bytecode: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let decoded = decode(bytecode)?;
+    let live: Vec<_> = decoded
+        .iter()
+        .zip(reachable(&decoded))
+        .filter_map(|(insn, live)| live.then_some(insn))
+        .collect();
+    if live.iter().any(|insn| insn.opcode == Opcode::Patch) {
+        bail!("cannot move code patched at run time");
+    }
+
+    let mut new_offsets = HashMap::new();
+    let mut size = 0;
+    for insn in &live {
+        new_offsets.insert(insn.offset, size);
+        size += insn.next() - insn.offset;
+    }
+
+    let mut output = Vec::with_capacity(size);
+    for insn in &live {
+        output.push(insn.opcode as u8);
+        let len = insn.opcode.operand_len();
+        let operand = match insn.target() {
+            Some(target) => {
+                let &address = new_offsets.get(&target).ok_or_else(|| {
+                    anyhow!(
+                        "branch at offset {} to invalid target {}",
+                        insn.offset,
+                        target
+                    )
+                })?;
+                // Removing code only brings instructions closer so the
+                // updated target fits in the original operand.
+                if insn.opcode.is_relative() {
+                    (address as isize - (output.len() + len) as isize) as u32
+                } else {
+                    address as u32
+                }
+            }
+            None => insn.operand.unwrap_or(0),
+        };
+        output.extend(&operand.to_le_bytes()[..len]);
+    }
+    Ok(output)
+}
+
+/// Potential problem found by [`dry_run`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Finding {
+    /// Branch at `offset` jumps to `target` which is not the start of an
+    /// instruction.
+    InvalidTarget { offset: usize, target: usize },
+
+    /// Execution may run past the end of the program after the instruction
+    /// at `offset`.
+    FallsOffEnd { offset: usize },
+
+    /// Instruction at `offset` may pop from an empty stack.
+    StackUnderflow { offset: usize },
+
+    /// Stack depth when reaching `offset` depends on the path taken.
+    StackImbalance { offset: usize },
+
+    /// Instruction at `offset` is never executed.
+    Unreachable { offset: usize },
+}
+
+/// Result of statically checking a program.
+#[derive(Debug, Clone)]
+pub struct DryRunReport {
+    /// Assembled program.
+    pub bytecode: Vec<u8>,
+
+    /// Deepest stack reached on any analyzed path.
+    pub max_stack_depth: usize,
+
+    /// Problems found, if any.
+    pub findings: Vec<Finding>,
+}
+
+impl DryRunReport {
+    /// Was the program found free of problems?
+    pub fn is_ok(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+/// Assemble and statically check a program without executing it.
+///
+/// Assembly errors such as undefined labels are returned as `Err`.  Problems
+/// found by analyzing the assembled bytecode are listed in the report.
+pub fn dry_run(insns: &[Insn]) -> anyhow::Result<DryRunReport> {
+    let bytecode = assemble(insns)?;
+    let decoded = decode(&bytecode)?;
+
+    let index_of = |offset: usize| decoded.binary_search_by_key(&offset, |d| d.offset).ok();
+    let mut findings = Vec::new();
+    let mut report = |finding: Finding| {
+        if !findings.contains(&finding) {
+            findings.push(finding);
+        }
+    };
+
+    // Propagate stack depth along all execution paths from offset 0.
+    let mut depths: Vec<Option<usize>> = vec![None; decoded.len()];
+    let mut max_stack_depth = 0;
+    let mut worklist = if decoded.is_empty() {
+        vec![]
+    } else {
+        vec![(0, 0)]
+    };
+    while let Some((index, depth)) = worklist.pop() {
+        let insn = &decoded[index];
+        match depths[index] {
+            Some(d) if d != depth => {
+                report(Finding::StackImbalance {
+                    offset: insn.offset,
+                });
+                continue;
+            }
+            Some(_) => continue,
+            None => depths[index] = Some(depth),
+        }
+
+        let (pops, pushes) = insn.opcode.stack_effect();
+        if pops > depth {
+            report(Finding::StackUnderflow {
+                offset: insn.offset,
+            });
+        }
+        let depth = depth.saturating_sub(pops) + pushes;
+        max_stack_depth = max_stack_depth.max(depth);
+
+        for successor in insn.successors() {
+            match index_of(successor) {
+                Some(i) => worklist.push((i, depth)),
+                None if successor == bytecode.len() => report(Finding::FallsOffEnd {
+                    offset: insn.offset,
+                }),
+                None => report(Finding::InvalidTarget {
+                    offset: insn.offset,
+                    target: successor,
+                }),
+            }
+        }
+    }
+
+    for (insn, depth) in decoded.iter().zip(&depths) {
+        if depth.is_none() {
+            report(Finding::Unreachable {
+                offset: insn.offset,
+            });
+        }
+    }
+
+    Ok(DryRunReport {
+        bytecode,
+        max_stack_depth,
+        findings,
+    })
+}
+
+/// Describe in one line what a program appears to do.
+///
+/// This is a best-effort guess based on the opcodes present in the bytecode
+/// and on its backward branches, which are counted as loops.
+pub fn summarize(bytecode: &[u8]) -> String {
+    let decoded = match decode(bytecode) {
+        Ok(decoded) => decoded,
+        Err(err) => return format!("undecodable bytecode: {:#}", err),
+    };
+
+    let count = |opcodes: &[Opcode]| {
+        decoded
+            .iter()
+            .filter(|d| opcodes.contains(&d.opcode))
+            .count()
+    };
+    let reads = count(&[Opcode::In, Opcode::InOr]);
+    let writes = count(&[Opcode::Out, Opcode::OutPeek]) + 2 * count(&[Opcode::Out2]);
+    let arithmetic = decoded.iter().any(|d| d.opcode.is_arithmetic());
+    let uses_aux = count(&[Opcode::Pusha, Opcode::Popa, Opcode::Pushr, Opcode::Popr]) > 0;
+    let loops = decoded
+        .iter()
+        .filter(|d| d.target().is_some_and(|t| t <= d.offset))
+        .count();
+
+    let mut actions = Vec::new();
+    if reads > 0 {
+        actions.push("reads input chars");
+    }
+    if arithmetic {
+        actions.push("applies arithmetic transformation");
+    }
+    match writes {
+        0 => {}
+        n if n == reads => actions.push("outputs same count of chars"),
+        _ => actions.push("outputs chars"),
+    }
+
+    let mut clauses = vec![if actions.is_empty() {
+        "performs no I/O".to_string()
+    } else {
+        actions.join(", ")
+    }];
+    if uses_aux {
+        clauses.push("uses aux as accumulator".to_string());
+    }
+    clauses.push(match loops {
+        0 => "has no loops".to_string(),
+        1 => "has 1 loop".to_string(),
+        n => format!("has {} loops", n),
+    });
+    clauses.join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{assemble, parse};
+    use crate::vm::run;
+
+    #[test]
+    fn summarize_decrypter() {
+        let program = assemble(&crate::programs::decrypter()).unwrap();
+        assert_eq!(
+            summarize(&program),
+            "reads input chars, applies arithmetic transformation, outputs same count of chars; \
+             uses aux as accumulator; has 1 loop"
+        );
+    }
+
+    #[test]
+    fn summarize_program_without_io() {
+        let program = parse("push 3\nloop: dec\ndup\nbnez loop\nagain: jmp again").unwrap();
+        assert_eq!(
+            summarize(&program),
+            "applies arithmetic transformation; has 2 loops"
+        );
+        assert_eq!(
+            summarize(&[Opcode::Exit as u8]),
+            "performs no I/O; has no loops"
+        );
+    }
+
+    #[test]
+    fn summarize_undecodable_program() {
+        assert!(summarize(&[Opcode::Push as u8]).starts_with("undecodable bytecode"));
+    }
+
+    #[test]
+    fn dead_suffix_is_removed() {
+        let decrypter = assemble(&crate::programs::decrypter()).unwrap();
+        let mut padded = decrypter.clone();
+        padded.extend(parse("push 9\nout\nexit").unwrap());
+        let stripped = eliminate_dead_code(&padded).unwrap();
+        assert_eq!(stripped, decrypter);
+        for input in ["", "abc", "thequickbrownfoxjumpsoverthelazydog"] {
+            assert_eq!(run(&stripped, input).unwrap(), run(&padded, input).unwrap());
+        }
+    }
+
+    #[test]
+    fn branches_over_dead_code_are_retargeted() {
+        let program = parse("jmpw over\npush 'x'\nout\nover: push 'a'\nout\nexit").unwrap();
+        let stripped = eliminate_dead_code(&program).unwrap();
+        assert_eq!(stripped.len(), program.len() - 3);
+        assert_eq!(run(&stripped, "").unwrap(), "a");
+    }
+
+    #[test]
+    fn self_patching_code_is_not_moved() {
+        let program = parse("push 0\npush 0\npatch\nexit").unwrap();
+        let e = eliminate_dead_code(&program).unwrap_err();
+        assert_eq!(e.to_string(), "cannot move code patched at run time");
+    }
+}
@@ -1,22 +1,165 @@
 //! Pseudo-assembler and disassembler
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::num::IntErrorKind;
+use std::ops::Range;
 
-use anyhow::Context;
+use anyhow::{anyhow, bail, Context};
 
+use crate::analysis;
 use crate::vm::*;
 
+/// Assembly error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmError {
+    /// No opcode has this mnemonic.
+    UnknownMnemonic(String),
+
+    /// Operand of instruction at index `insn_index` does not fit in its
+    /// operand bytes.
+    OperandOutOfRange { insn_index: usize, value: u32 },
+
+    /// Instruction at index `insn_index` refers to a label no instruction has.
+    UndefinedLabel { insn_index: usize, label: String },
+
+    /// Label is attached to both instructions at indices `first` and `second`.
+    DuplicateLabel {
+        label: String,
+        first: usize,
+        second: usize,
+    },
+
+    /// Label is not attached to any instruction.
+    DanglingLabel(String),
+
+    /// Assembled program is longer than the configured maximum.
+    ProgramTooLarge { size: usize, max: usize },
+
+    /// Instruction at index `insn_index` calls a macro no definition has.
+    UndefinedMacro { insn_index: usize, name: String },
+
+    /// Instruction at index `insn_index` has no operand but its opcode needs
+    /// one.
+    MissingOperand { insn_index: usize },
+
+    /// Instruction at index `insn_index` has an operand but its opcode takes
+    /// none.
+    UnexpectedOperand { insn_index: usize },
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmError::UnknownMnemonic(mnemonic) => {
+                let valid: Vec<_> = Opcode::all().map(Opcode::mnemonic).collect();
+                write!(
+                    f,
+                    "unknown mnemonic `{}` (expected one of: {})",
+                    mnemonic,
+                    valid.join(", ")
+                )
+            }
+            AsmError::OperandOutOfRange { insn_index, value } => {
+                write!(
+                    f,
+                    "operand {} of instruction {} is out of range",
+                    value, insn_index
+                )
+            }
+            AsmError::UndefinedLabel { insn_index, label } => {
+                write!(
+                    f,
+                    "instruction {} refers to undefined label `{}`",
+                    insn_index, label
+                )
+            }
+            AsmError::DuplicateLabel {
+                label,
+                first,
+                second,
+            } => write!(
+                f,
+                "label `{}` is attached to both instructions {} and {}",
+                label, first, second
+            ),
+            AsmError::DanglingLabel(label) => {
+                write!(f, "label `{}` is not attached to any instruction", label)
+            }
+            AsmError::ProgramTooLarge { size, max } => {
+                write!(f, "program size {} exceeds maximum {}", size, max)
+            }
+            AsmError::UndefinedMacro { insn_index, name } => {
+                write!(
+                    f,
+                    "instruction {} calls undefined macro `{}`",
+                    insn_index, name
+                )
+            }
+            AsmError::MissingOperand { insn_index } => {
+                write!(f, "instruction {} is missing its operand", insn_index)
+            }
+            AsmError::UnexpectedOperand { insn_index } => {
+                write!(f, "instruction {} takes no operand", insn_index)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+impl AsmError {
+    /// Index of the instruction this error is about, if any.
+    fn insn_index(&self) -> Option<usize> {
+        match *self {
+            AsmError::OperandOutOfRange { insn_index, .. }
+            | AsmError::UndefinedLabel { insn_index, .. }
+            | AsmError::UndefinedMacro { insn_index, .. }
+            | AsmError::MissingOperand { insn_index }
+            | AsmError::UnexpectedOperand { insn_index } => Some(insn_index),
+            AsmError::DuplicateLabel { second, .. } => Some(second),
+            AsmError::UnknownMnemonic(_)
+            | AsmError::DanglingLabel(_)
+            | AsmError::ProgramTooLarge { .. } => None,
+        }
+    }
+}
+
+impl Opcode {
+    /// Parse case-insensitive assembly mnemonic.
+    ///
+    /// `bnez` is accepted as an alias of `bne`.
+    pub fn try_from_mnemonic(s: &str) -> Result<Opcode, AsmError> {
+        let lower = s.to_lowercase();
+        if lower == "bnez" {
+            return Ok(Opcode::Bnez);
+        }
+        Opcode::all()
+            .find(|opcode| opcode.mnemonic() == lower)
+            .ok_or_else(|| AsmError::UnknownMnemonic(s.to_string()))
+    }
+}
+
 /// Single assembly instruction with optional label and operand to assemble.
+///
+/// An instruction can also carry an opaque tag for use by tools built on top
+/// of the assembler.  Tags are never emitted into bytecodes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Insn {
-    label: Option<&'static str>,
+    label: Option<Cow<'static, str>>,
     opcode: Opcode,
     operand: Operand,
+    tag: Option<u64>,
 }
 
 /// Instruction operand.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Operand {
     None,
-    Target(&'static str),
+    Target(Cow<'static, str>),
     Value(u32),
 }
 
@@ -26,76 +169,3024 @@ impl Insn {
             label: None,
             opcode,
             operand: Operand::None,
+            tag: None,
         }
     }
 
-    pub const fn set_label(self, label: &'static str) -> Insn {
+    pub fn set_label(self, label: impl Into<Cow<'static, str>>) -> Insn {
         Insn {
-            label: Some(label),
+            label: Some(label.into()),
             opcode: self.opcode,
             operand: self.operand,
+            tag: self.tag,
         }
     }
 
-    pub const fn set_value(self, value: u32) -> Insn {
+    pub fn set_value(self, value: u32) -> Insn {
         Insn {
             label: self.label,
             opcode: self.opcode,
             operand: Operand::Value(value),
+            tag: self.tag,
+        }
+    }
+
+    /// Set operand of `Pushi` to the sign extension of `value`.
+    pub fn set_value_i8(self, value: i8) -> Insn {
+        self.set_value(value as i32 as u32)
+    }
+
+    pub fn set_target(self, label: impl Into<Cow<'static, str>>) -> Insn {
+        Insn {
+            label: self.label,
+            opcode: self.opcode,
+            operand: Operand::Target(label.into()),
+            tag: self.tag,
         }
     }
 
-    pub const fn set_target(self, label: &'static str) -> Insn {
+    pub fn set_tag(self, tag: u64) -> Insn {
         Insn {
             label: self.label,
             opcode: self.opcode,
-            operand: Operand::Target(label),
+            operand: self.operand,
+            tag: Some(tag),
         }
     }
+
+    pub const fn tag(&self) -> Option<u64> {
+        self.tag
+    }
 }
 
-/// Assemble a sequence of instructions into a sequence of bytecodes.
-pub fn assemble(source: &[Insn]) -> anyhow::Result<Vec<u8>> {
-    let mut labels = HashMap::new();
-    let mut relocations = Vec::new();
-    let mut bytecodes = Vec::new();
-    for insn in source.iter() {
-        if let Some(label) = insn.label {
-            labels.insert(label, bytecodes.len());
+/// Build unlabeled instruction from opcode and optional immediate value.
+impl From<(Opcode, Option<u32>)> for Insn {
+    fn from((opcode, value): (Opcode, Option<u32>)) -> Insn {
+        let insn = Insn::new(opcode);
+        match value {
+            Some(value) => insn.set_value(value),
+            None => insn,
         }
-        bytecodes.push(insn.opcode as u8);
-        match insn.operand {
-            Operand::None => (),
-            Operand::Target(label) => {
-                relocations.push((label, bytecodes.len()));
-                bytecodes.push(0)
+    }
+}
+
+/// Build labeled instruction from label, opcode and optional immediate value.
+impl From<(&'static str, Opcode, Option<u32>)> for Insn {
+    fn from((label, opcode, value): (&'static str, Opcode, Option<u32>)) -> Insn {
+        Insn::from((opcode, value)).set_label(label)
+    }
+}
+
+/// Chainable builder of instruction sequences.
+///
+/// There is one method per opcode, except for wide, relative and implicit
+/// operand variants which the assembler selects.  `label()` attaches a label to the next
+/// instruction.  Labels may be computed at run time, so this also suits
+/// generated programs.
+#[derive(Clone, Default)]
+pub struct ProgramBuilder {
+    insns: Vec<Insn>,
+    pending_label: Option<Cow<'static, str>>,
+    dangling_labels: Vec<Cow<'static, str>>,
+    hot_paths: Vec<HotPathAnnotation>,
+}
+
+/// Bytecode range from `start` included to `end` excluded that profiling
+/// found worth compiling.
+///
+/// Nothing consumes annotations yet.  They document programs until a JIT
+/// compiler exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HotPathAnnotation {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl ProgramBuilder {
+    pub fn new() -> ProgramBuilder {
+        ProgramBuilder::default()
+    }
+
+    /// Return built instructions, failing if some referenced label is not
+    /// defined or some label is not attached to an instruction.
+    pub fn build(self) -> Result<Vec<Insn>, AsmError> {
+        self.build_with_metadata().map(|(insns, _)| insns)
+    }
+
+    /// Like [`ProgramBuilder::build`] but also return the hot paths
+    /// annotated with [`ProgramBuilder::mark_hot_path`].
+    pub fn build_with_metadata(mut self) -> Result<(Vec<Insn>, Vec<HotPathAnnotation>), AsmError> {
+        if let Some(label) = self.pending_label.take() {
+            self.dangling_labels.push(label);
+        }
+        if let Some(label) = self.dangling_labels.first() {
+            return Err(AsmError::DanglingLabel(label.to_string()));
+        }
+        for (insn_index, insn) in self.insns.iter().enumerate() {
+            if let Operand::Target(target) = &insn.operand {
+                if insn.opcode != Opcode::MacroCall
+                    && !self.insns.iter().any(|i| i.label.as_ref() == Some(target))
+                {
+                    return Err(AsmError::UndefinedLabel {
+                        insn_index,
+                        label: target.to_string(),
+                    });
+                }
             }
-            Operand::Value(value) => bytecodes.push(value as u8),
         }
+        Ok((self.insns, self.hot_paths))
     }
 
-    for (label, offset) in relocations {
-        bytecodes[offset] = *labels.get(label).context("look up label")? as u8;
+    /// Build instructions and assemble them with [`assemble`].
+    pub fn assemble(self) -> anyhow::Result<Vec<u8>> {
+        assemble(&self.build()?)
     }
 
-    Ok(bytecodes)
-}
+    /// Attach label to the next instruction.
+    pub fn label(mut self, name: impl Into<Cow<'static, str>>) -> ProgramBuilder {
+        if let Some(previous) = self.pending_label.replace(name.into()) {
+            self.dangling_labels.push(previous);
+        }
+        self
+    }
 
-pub fn pretty_print(source: &[Insn]) -> anyhow::Result<String> {
-    let mut output = String::new();
-    for insn in source {
-        if let Some(label) = insn.label {
-            output.push_str(&format!("{}:\t", label))
-        } else {
-            output.push('\t')
-        };
-        output.push_str(&format!("{:?}", insn.opcode));
-        match insn.operand {
-            Operand::None => (),
-            Operand::Target(label) => output.push_str(&format!(" {}", label)),
-            Operand::Value(n) => output.push_str(&format!(" {}", n)),
+    /// Annotate bytecode `range` of the assembled program as a hot path.
+    ///
+    /// Annotations do not change the assembled program.
+    pub fn mark_hot_path(mut self, range: Range<usize>) -> ProgramBuilder {
+        self.hot_paths.push(HotPathAnnotation {
+            start: range.start,
+            end: range.end,
+        });
+        self
+    }
+
+    /// Hot paths annotated so far, in annotation order.
+    pub fn hot_paths(&self) -> &[HotPathAnnotation] {
+        &self.hot_paths
+    }
+
+    /// Append arbitrary instruction.
+    pub fn insn(mut self, insn: Insn) -> ProgramBuilder {
+        let insn = match self.pending_label.take() {
+            Some(label) => insn.set_label(label),
+            None => insn,
         };
-        output.push('\n');
+        self.insns.push(insn);
+        self
+    }
+
+    fn op(self, opcode: Opcode) -> ProgramBuilder {
+        self.insn(Insn::new(opcode))
+    }
+
+    fn branch(self, opcode: Opcode, target: impl Into<Cow<'static, str>>) -> ProgramBuilder {
+        self.insn(Insn::new(opcode).set_target(target))
+    }
+
+    /// `in` is a keyword.
+    pub fn in_op(self) -> ProgramBuilder {
+        self.op(Opcode::In)
+    }
+
+    pub fn out(self) -> ProgramBuilder {
+        self.op(Opcode::Out)
+    }
+
+    pub fn out_peek(self) -> ProgramBuilder {
+        self.op(Opcode::OutPeek)
+    }
+
+    pub fn in_or(self, fallback: u32) -> ProgramBuilder {
+        self.insn(Insn::new(Opcode::InOr).set_value(fallback))
+    }
+
+    pub fn in_byte(self) -> ProgramBuilder {
+        self.op(Opcode::InByte)
+    }
+
+    pub fn out_byte(self) -> ProgramBuilder {
+        self.op(Opcode::OutByte)
+    }
+
+    pub fn in_n(self, channel: u32) -> ProgramBuilder {
+        self.insn(Insn::new(Opcode::InN).set_value(channel))
+    }
+
+    pub fn out_n(self, channel: u32) -> ProgramBuilder {
+        self.insn(Insn::new(Opcode::OutN).set_value(channel))
+    }
+
+    pub fn dup(self) -> ProgramBuilder {
+        self.op(Opcode::Dup)
+    }
+
+    pub fn dup2(self) -> ProgramBuilder {
+        self.op(Opcode::Dup2)
+    }
+
+    pub fn swap(self) -> ProgramBuilder {
+        self.op(Opcode::Swap)
+    }
+
+    pub fn rot(self) -> ProgramBuilder {
+        self.op(Opcode::Rot)
+    }
+
+    pub fn drop(self) -> ProgramBuilder {
+        self.op(Opcode::Drop)
+    }
+
+    pub fn over(self) -> ProgramBuilder {
+        self.op(Opcode::Over)
+    }
+
+    pub fn add(self) -> ProgramBuilder {
+        self.op(Opcode::Add)
+    }
+
+    pub fn sub(self) -> ProgramBuilder {
+        self.op(Opcode::Sub)
+    }
+
+    pub fn mul(self) -> ProgramBuilder {
+        self.op(Opcode::Mul)
+    }
+
+    pub fn div(self) -> ProgramBuilder {
+        self.op(Opcode::Div)
+    }
+
+    pub fn rem(self) -> ProgramBuilder {
+        self.op(Opcode::Rem)
+    }
+
+    pub fn and(self) -> ProgramBuilder {
+        self.op(Opcode::And)
+    }
+
+    pub fn or(self) -> ProgramBuilder {
+        self.op(Opcode::Or)
+    }
+
+    pub fn xor(self) -> ProgramBuilder {
+        self.op(Opcode::Xor)
+    }
+
+    /// `not` would be confused with `std::ops::Not::not`.
+    pub fn not_op(self) -> ProgramBuilder {
+        self.op(Opcode::Not)
+    }
+
+    /// `neg` would be confused with `std::ops::Neg::neg`.
+    pub fn neg_op(self) -> ProgramBuilder {
+        self.op(Opcode::Neg)
+    }
+
+    pub fn inc(self) -> ProgramBuilder {
+        self.op(Opcode::Inc)
+    }
+
+    pub fn dec(self) -> ProgramBuilder {
+        self.op(Opcode::Dec)
+    }
+
+    /// `shl` would be confused with `std::ops::Shl::shl`.
+    pub fn shl_op(self) -> ProgramBuilder {
+        self.op(Opcode::Shl)
+    }
+
+    /// `shr` would be confused with `std::ops::Shr::shr`.
+    pub fn shr_op(self) -> ProgramBuilder {
+        self.op(Opcode::Shr)
+    }
+
+    pub fn sar(self) -> ProgramBuilder {
+        self.op(Opcode::Sar)
+    }
+
+    pub fn bne(self, target: impl Into<Cow<'static, str>>) -> ProgramBuilder {
+        self.branch(Opcode::Bne, target)
+    }
+
+    pub fn bnez(self, target: impl Into<Cow<'static, str>>) -> ProgramBuilder {
+        self.branch(Opcode::Bnez, target)
+    }
+
+    pub fn beqz(self, target: impl Into<Cow<'static, str>>) -> ProgramBuilder {
+        self.branch(Opcode::Beqz, target)
+    }
+
+    pub fn blt(self, target: impl Into<Cow<'static, str>>) -> ProgramBuilder {
+        self.branch(Opcode::Blt, target)
+    }
+
+    pub fn exit(self) -> ProgramBuilder {
+        self.op(Opcode::Exit)
+    }
+
+    pub fn nop(self) -> ProgramBuilder {
+        self.op(Opcode::Nop)
+    }
+
+    pub fn push(self, value: u32) -> ProgramBuilder {
+        self.insn(Insn::new(Opcode::Push).set_value(value))
+    }
+
+    pub fn pushi(self, value: i8) -> ProgramBuilder {
+        self.insn(Insn::new(Opcode::Pushi).set_value_i8(value))
+    }
+
+    pub fn jmp(self, target: impl Into<Cow<'static, str>>) -> ProgramBuilder {
+        self.branch(Opcode::Jmp, target)
+    }
+
+    pub fn beq(self, target: impl Into<Cow<'static, str>>) -> ProgramBuilder {
+        self.branch(Opcode::Beq, target)
+    }
+
+    pub fn call(self, target: impl Into<Cow<'static, str>>) -> ProgramBuilder {
+        self.branch(Opcode::Call, target)
+    }
+
+    pub fn ret(self) -> ProgramBuilder {
+        self.op(Opcode::Ret)
+    }
+
+    pub fn pusha(self) -> ProgramBuilder {
+        self.op(Opcode::Pusha)
+    }
+
+    pub fn popa(self) -> ProgramBuilder {
+        self.op(Opcode::Popa)
+    }
+
+    pub fn load(self) -> ProgramBuilder {
+        self.op(Opcode::Load)
+    }
+
+    pub fn store(self) -> ProgramBuilder {
+        self.op(Opcode::Store)
+    }
+
+    pub fn patch(self) -> ProgramBuilder {
+        self.op(Opcode::Patch)
+    }
+
+    pub fn pushr(self, index: u32) -> ProgramBuilder {
+        self.insn(Insn::new(Opcode::Pushr).set_value(index))
+    }
+
+    pub fn popr(self, index: u32) -> ProgramBuilder {
+        self.insn(Insn::new(Opcode::Popr).set_value(index))
+    }
+
+    pub fn bgt(self, target: impl Into<Cow<'static, str>>) -> ProgramBuilder {
+        self.branch(Opcode::Bgt, target)
+    }
+
+    pub fn ble(self, target: impl Into<Cow<'static, str>>) -> ProgramBuilder {
+        self.branch(Opcode::Ble, target)
+    }
+
+    pub fn blts(self, target: impl Into<Cow<'static, str>>) -> ProgramBuilder {
+        self.branch(Opcode::Blts, target)
+    }
+
+    pub fn bgts(self, target: impl Into<Cow<'static, str>>) -> ProgramBuilder {
+        self.branch(Opcode::Bgts, target)
+    }
+
+    pub fn bles(self, target: impl Into<Cow<'static, str>>) -> ProgramBuilder {
+        self.branch(Opcode::Bles, target)
+    }
+
+    pub fn bges(self, target: impl Into<Cow<'static, str>>) -> ProgramBuilder {
+        self.branch(Opcode::Bges, target)
+    }
+
+    /// Call subroutine at `offset` in segment `segment` once linked with
+    /// [`link`].
+    pub fn call_extern(self, segment: u8, offset: u8) -> ProgramBuilder {
+        let operand = u32::from_le_bytes([segment, offset, 0, 0]);
+        self.insn(Insn::new(Opcode::CallExtern).set_value(operand))
+    }
+
+    /// Call macro expanded by [`assemble_with_macros`].
+    pub fn macro_call(self, name: impl Into<Cow<'static, str>>) -> ProgramBuilder {
+        self.branch(Opcode::MacroCall, name)
+    }
+
+    pub fn out2(self) -> ProgramBuilder {
+        self.op(Opcode::Out2)
+    }
+
+    pub fn select(self) -> ProgramBuilder {
+        self.op(Opcode::Select)
+    }
+
+    pub fn trace(self, marker: u32) -> ProgramBuilder {
+        self.insn(Insn::new(Opcode::Trace).set_value(marker))
+    }
+
+    #[cfg(feature = "debug-opcodes")]
+    pub fn regs(self) -> ProgramBuilder {
+        self.op(Opcode::Regs)
+    }
+
+    #[cfg(feature = "debug-opcodes")]
+    pub fn assert(self) -> ProgramBuilder {
+        self.op(Opcode::Assert)
+    }
+}
+
+/// Instruction sequence built in place, for programs generated at run time.
+///
+/// Unlike [`ProgramBuilder`], methods borrow the program mutably so it can be
+/// extended from loops and conditionals.  Methods appending a branch to a
+/// label end in `_to`.  Labels are copied, so they may be formatted on the
+/// fly.
+#[derive(Clone, Default)]
+pub struct Program {
+    builder: ProgramBuilder,
+}
+
+impl Program {
+    pub fn new() -> Program {
+        Program::default()
+    }
+
+    /// Assemble the program built so far with [`assemble`].
+    pub fn assemble(&self) -> anyhow::Result<Vec<u8>> {
+        self.builder.clone().assemble()
+    }
+
+    /// Append instructions with a [`ProgramBuilder`] method.
+    fn with(&mut self, f: impl FnOnce(ProgramBuilder) -> ProgramBuilder) -> &mut Program {
+        self.builder = f(std::mem::take(&mut self.builder));
+        self
+    }
+
+    /// Attach label to the next instruction.
+    pub fn label(&mut self, name: &str) -> &mut Program {
+        let name = name.to_owned();
+        self.with(|b| b.label(name))
+    }
+
+    /// Append arbitrary instruction.
+    pub fn insn(&mut self, insn: Insn) -> &mut Program {
+        self.with(|b| b.insn(insn))
+    }
+
+    /// `in` is a keyword.
+    pub fn in_op(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::in_op)
+    }
+
+    pub fn out(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::out)
+    }
+
+    pub fn out_peek(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::out_peek)
+    }
+
+    pub fn in_byte(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::in_byte)
+    }
+
+    pub fn out_byte(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::out_byte)
+    }
+
+    pub fn dup(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::dup)
+    }
+
+    pub fn dup2(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::dup2)
+    }
+
+    pub fn swap(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::swap)
+    }
+
+    pub fn rot(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::rot)
+    }
+
+    pub fn drop(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::drop)
+    }
+
+    pub fn over(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::over)
+    }
+
+    pub fn add(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::add)
+    }
+
+    pub fn sub(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::sub)
+    }
+
+    pub fn mul(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::mul)
+    }
+
+    pub fn div(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::div)
+    }
+
+    pub fn rem(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::rem)
+    }
+
+    pub fn and(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::and)
+    }
+
+    pub fn or(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::or)
+    }
+
+    pub fn xor(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::xor)
+    }
+
+    pub fn not_op(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::not_op)
+    }
+
+    pub fn neg_op(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::neg_op)
+    }
+
+    pub fn inc(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::inc)
+    }
+
+    pub fn dec(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::dec)
+    }
+
+    pub fn shl_op(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::shl_op)
+    }
+
+    pub fn shr_op(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::shr_op)
+    }
+
+    pub fn sar(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::sar)
+    }
+
+    pub fn exit(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::exit)
+    }
+
+    pub fn nop(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::nop)
+    }
+
+    pub fn ret(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::ret)
+    }
+
+    pub fn pusha(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::pusha)
+    }
+
+    pub fn popa(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::popa)
+    }
+
+    pub fn load(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::load)
+    }
+
+    pub fn store(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::store)
+    }
+
+    pub fn patch(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::patch)
+    }
+
+    pub fn out2(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::out2)
+    }
+
+    pub fn select(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::select)
+    }
+
+    pub fn push_val(&mut self, value: u32) -> &mut Program {
+        self.with(|b| b.push(value))
+    }
+
+    pub fn pushi(&mut self, value: i8) -> &mut Program {
+        self.with(|b| b.pushi(value))
+    }
+
+    pub fn in_or(&mut self, fallback: u32) -> &mut Program {
+        self.with(|b| b.in_or(fallback))
+    }
+
+    pub fn in_n(&mut self, channel: u32) -> &mut Program {
+        self.with(|b| b.in_n(channel))
+    }
+
+    pub fn out_n(&mut self, channel: u32) -> &mut Program {
+        self.with(|b| b.out_n(channel))
+    }
+
+    pub fn pushr(&mut self, index: u32) -> &mut Program {
+        self.with(|b| b.pushr(index))
+    }
+
+    pub fn popr(&mut self, index: u32) -> &mut Program {
+        self.with(|b| b.popr(index))
+    }
+
+    pub fn trace(&mut self, marker: u32) -> &mut Program {
+        self.with(|b| b.trace(marker))
+    }
+
+    pub fn jmp_to(&mut self, label: &str) -> &mut Program {
+        let label = label.to_owned();
+        self.with(|b| b.jmp(label))
+    }
+
+    pub fn bne_to(&mut self, label: &str) -> &mut Program {
+        let label = label.to_owned();
+        self.with(|b| b.bne(label))
+    }
+
+    pub fn bnez_to(&mut self, label: &str) -> &mut Program {
+        let label = label.to_owned();
+        self.with(|b| b.bnez(label))
+    }
+
+    pub fn beqz_to(&mut self, label: &str) -> &mut Program {
+        let label = label.to_owned();
+        self.with(|b| b.beqz(label))
+    }
+
+    pub fn blt_to(&mut self, label: &str) -> &mut Program {
+        let label = label.to_owned();
+        self.with(|b| b.blt(label))
+    }
+
+    pub fn beq_to(&mut self, label: &str) -> &mut Program {
+        let label = label.to_owned();
+        self.with(|b| b.beq(label))
+    }
+
+    pub fn bgt_to(&mut self, label: &str) -> &mut Program {
+        let label = label.to_owned();
+        self.with(|b| b.bgt(label))
+    }
+
+    pub fn ble_to(&mut self, label: &str) -> &mut Program {
+        let label = label.to_owned();
+        self.with(|b| b.ble(label))
+    }
+
+    pub fn blts_to(&mut self, label: &str) -> &mut Program {
+        let label = label.to_owned();
+        self.with(|b| b.blts(label))
+    }
+
+    pub fn bgts_to(&mut self, label: &str) -> &mut Program {
+        let label = label.to_owned();
+        self.with(|b| b.bgts(label))
+    }
+
+    pub fn bles_to(&mut self, label: &str) -> &mut Program {
+        let label = label.to_owned();
+        self.with(|b| b.bles(label))
+    }
+
+    pub fn bges_to(&mut self, label: &str) -> &mut Program {
+        let label = label.to_owned();
+        self.with(|b| b.bges(label))
+    }
+
+    pub fn call_to(&mut self, label: &str) -> &mut Program {
+        let label = label.to_owned();
+        self.with(|b| b.call(label))
+    }
+
+    /// Call subroutine at `offset` in segment `segment` once linked with
+    /// [`link`].
+    pub fn call_extern(&mut self, segment: u8, offset: u8) -> &mut Program {
+        self.with(|b| b.call_extern(segment, offset))
+    }
+
+    /// Call macro expanded by [`assemble_with_macros`].
+    pub fn macro_call(&mut self, name: &str) -> &mut Program {
+        let name = name.to_owned();
+        self.with(|b| b.macro_call(name))
+    }
+
+    #[cfg(feature = "debug-opcodes")]
+    pub fn regs(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::regs)
+    }
+
+    #[cfg(feature = "debug-opcodes")]
+    pub fn assert(&mut self) -> &mut Program {
+        self.with(ProgramBuilder::assert)
+    }
+}
+
+/// Assemble a sequence of instructions into a sequence of bytecodes.
+///
+/// Programs may exceed 256 bytes: branches to labels out of reach of an 8-bit
+/// operand use their wide variant, and those without one, as well as
+/// relative branches written explicitly, fail with
+/// [`AsmError::OperandOutOfRange`] rather than wrapping around.
+///
+/// This uses the default [`AssemblerBuilder`] configuration.
+pub fn assemble(source: &[Insn]) -> anyhow::Result<Vec<u8>> {
+    AssemblerBuilder::new().build().assemble(source)
+}
+
+/// Assemble a sequence of instructions into a sequence of bytecodes and
+/// report all errors found rather than only the first one.
+///
+/// This uses the default [`AssemblerBuilder`] configuration.
+pub fn assemble_checked(source: &[Insn]) -> Result<Vec<u8>, Vec<AsmError>> {
+    AssemblerBuilder::new().build().assemble_checked(source)
+}
+
+/// Named instruction sequence substituted for `MacroCall` instructions by
+/// [`assemble_with_macros`].
+#[derive(Clone)]
+pub struct MacroDef {
+    pub name: Cow<'static, str>,
+    pub body: Vec<Insn>,
+}
+
+/// Expand macro calls in `source` and assemble the result.
+///
+/// A label attached to a call moves to the first instruction of the body.
+/// Labels defined in the body get a `#N` suffix unique to each expansion so
+/// that a macro can be called several times.  Macro bodies cannot call
+/// macros: such calls are reported as undefined.
+pub fn assemble_with_macros(macros: &[MacroDef], source: &[Insn]) -> anyhow::Result<Vec<u8>> {
+    assemble(&expand_macros(macros, source)?)
+}
+
+/// Substitute named constants in `source` and assemble the result.
+///
+/// Operands of non-branch instructions that target one of the names in
+/// `constants` become the value of that constant.  Constants therefore
+/// shadow labels of the same name, except as branch targets.
+pub fn assemble_with_consts(constants: &[(&str, u32)], source: &[Insn]) -> anyhow::Result<Vec<u8>> {
+    assemble(&substitute_consts(constants, source))
+}
+
+/// Replace operands naming a constant with its value.
+fn substitute_consts(constants: &[(&str, u32)], source: &[Insn]) -> Vec<Insn> {
+    source
+        .iter()
+        .map(|insn| match &insn.operand {
+            Operand::Target(name) if !insn.opcode.is_branch() => {
+                match constants.iter().find(|(constant, _)| constant == name) {
+                    Some(&(_, value)) => insn.clone().set_value(value),
+                    None => insn.clone(),
+                }
+            }
+            _ => insn.clone(),
+        })
+        .collect()
+}
+
+/// Replace every `MacroCall` instruction with the body of its macro.
+fn expand_macros(macros: &[MacroDef], source: &[Insn]) -> Result<Vec<Insn>, AsmError> {
+    let mut expanded = Vec::with_capacity(source.len());
+    for (expansion, (insn_index, insn)) in source.iter().enumerate().enumerate() {
+        let (Opcode::MacroCall, Operand::Target(name)) = (insn.opcode, &insn.operand) else {
+            expanded.push(insn.clone());
+            continue;
+        };
+        let def = macros.iter().find(|def| def.name == *name).ok_or_else(|| {
+            AsmError::UndefinedMacro {
+                insn_index,
+                name: name.to_string(),
+            }
+        })?;
+        let locals: HashSet<_> = def.body.iter().filter_map(|i| i.label.as_deref()).collect();
+        let rename = |label: &Cow<'static, str>| -> Cow<'static, str> {
+            if locals.contains(label.as_ref()) {
+                format!("{}#{}", label, expansion).into()
+            } else {
+                label.clone()
+            }
+        };
+
+        // The call label needs an instruction of its own if the body starts
+        // with a label or is empty.
+        let mut call_label = insn.label.clone();
+        if def.body.first().is_none_or(|first| first.label.is_some()) {
+            if let Some(label) = call_label.take() {
+                expanded.push(Insn::new(Opcode::Nop).set_label(label));
+            }
+        }
+        for body_insn in &def.body {
+            let mut body_insn = body_insn.clone();
+            body_insn.label = match &body_insn.label {
+                Some(label) => Some(rename(label)),
+                None => call_label.take(),
+            };
+            if let Operand::Target(target) = &body_insn.operand {
+                body_insn.operand = Operand::Target(rename(target));
+            }
+            expanded.push(body_insn);
+        }
+    }
+    Ok(expanded)
+}
+
+/// Report in `errors` macro calls left unexpanded.
+fn check_macro_calls(source: &[Insn], errors: &mut Vec<AsmError>) {
+    for (insn_index, insn) in source.iter().enumerate() {
+        if insn.opcode == Opcode::MacroCall {
+            let name = match &insn.operand {
+                Operand::Target(name) => name.to_string(),
+                _ => String::new(),
+            };
+            errors.push(AsmError::UndefinedMacro { insn_index, name });
+        }
+    }
+}
+
+/// Report in `errors` instructions whose operand does not match what their
+/// selected opcode takes.
+fn check_operands(source: &[Insn], opcodes: &[Opcode], errors: &mut Vec<AsmError>) {
+    for (insn_index, (insn, &opcode)) in source.iter().zip(opcodes).enumerate() {
+        let takes_operand = opcode.operand_len() > 0;
+        match &insn.operand {
+            Operand::None if takes_operand => errors.push(AsmError::MissingOperand { insn_index }),
+            Operand::Value(_) if !takes_operand && opcode.implicit_value().is_none() => {
+                errors.push(AsmError::UnexpectedOperand { insn_index })
+            }
+            // Macro calls are reported by `check_macro_calls()`.
+            Operand::Target(_) if !takes_operand && opcode != Opcode::MacroCall => {
+                errors.push(AsmError::UnexpectedOperand { insn_index })
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Merge assembly errors into a single error.
+fn combine_errors(mut errors: Vec<AsmError>) -> anyhow::Error {
+    if errors.len() == 1 {
+        return errors.remove(0).into();
+    }
+    let messages: Vec<_> = errors.iter().map(AsmError::to_string).collect();
+    anyhow!("{}", messages.join("; "))
+}
+
+/// Assembler configured by [`AssemblerBuilder`].
+#[derive(Debug, Clone, Default)]
+pub struct Assembler {
+    peephole_optimizer: bool,
+    dead_code_elimination: bool,
+    max_program_size: Option<usize>,
+}
+
+impl Assembler {
+    /// Assemble a sequence of instructions into a sequence of bytecodes.
+    pub fn assemble(&self, source: &[Insn]) -> anyhow::Result<Vec<u8>> {
+        self.assemble_checked(source).map_err(combine_errors)
+    }
+
+    /// Assemble a sequence of instructions into a sequence of bytecodes and
+    /// report all errors found rather than only the first one.
+    pub fn assemble_checked(&self, source: &[Insn]) -> Result<Vec<u8>, Vec<AsmError>> {
+        let mut insns = source.to_vec();
+        if self.dead_code_elimination {
+            insns = eliminate_dead_insns(insns)?;
+        }
+        if self.peephole_optimizer {
+            insns = optimize_peephole(insns);
+        }
+
+        let bytecodes = emit(&insns)?;
+        if let Some(max) = self.max_program_size {
+            if bytecodes.len() > max {
+                return Err(vec![AsmError::ProgramTooLarge {
+                    size: bytecodes.len(),
+                    max,
+                }]);
+            }
+        }
+        Ok(bytecodes)
+    }
+}
+
+/// Assembler configuration.
+///
+/// All optional passes are disabled by default.
+#[derive(Debug, Clone, Default)]
+pub struct AssemblerBuilder {
+    assembler: Assembler,
+}
+
+impl AssemblerBuilder {
+    pub fn new() -> AssemblerBuilder {
+        AssemblerBuilder::default()
+    }
+
+    /// Rewrite instruction sequences that have a shorter equivalent.
+    pub fn with_peephole_optimizer(mut self, enabled: bool) -> AssemblerBuilder {
+        self.assembler.peephole_optimizer = enabled;
+        self
+    }
+
+    /// Strip instructions that cannot be reached from the entry point.
+    pub fn with_dead_code_elimination(mut self, enabled: bool) -> AssemblerBuilder {
+        self.assembler.dead_code_elimination = enabled;
+        self
+    }
+
+    /// Fail when the assembled program is longer than `size` bytes.
+    pub fn with_max_program_size(mut self, size: usize) -> AssemblerBuilder {
+        self.assembler.max_program_size = Some(size);
+        self
+    }
+
+    pub fn build(self) -> Assembler {
+        self.assembler
+    }
+}
+
+/// Translate instructions into bytecodes and resolve branch targets.
+fn emit(source: &[Insn]) -> Result<Vec<u8>, Vec<AsmError>> {
+    emit_passes(source, |_| ())
+}
+
+/// Groups of assembler passes that `emit_passes()` reports completing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    LabelScan,
+    Emission,
+    Relocation,
+}
+
+/// Run every assembler pass over `source` in order, calling `lap` each time
+/// a phase completes.
+fn emit_passes(source: &[Insn], mut lap: impl FnMut(Phase)) -> Result<Vec<u8>, Vec<AsmError>> {
+    let mut errors = Vec::new();
+    check_duplicate_labels(source, &mut errors);
+    check_macro_calls(source, &mut errors);
+    let opcodes = select_opcodes(source);
+    let labels = scan_labels(source, &opcodes);
+    lap(Phase::LabelScan);
+    check_operands(source, &opcodes, &mut errors);
+    let (mut bytecodes, relocations) = emit_bytecodes(source, &opcodes, &mut errors);
+    lap(Phase::Emission);
+    patch_targets(&mut bytecodes, &relocations, &labels, &mut errors);
+    lap(Phase::Relocation);
+    if errors.is_empty() {
+        Ok(bytecodes)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Bytes an instruction was assembled into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InsnSpan {
+    /// Index of the instruction in the source.
+    pub insn_index: usize,
+
+    /// Offset of the opcode.
+    pub byte_start: usize,
+
+    /// Offset past the last operand byte.
+    pub byte_end: usize,
+}
+
+/// Assemble a sequence of instructions and tell which bytes each one was
+/// assembled into.
+///
+/// Spans are listed in source order and cover the whole bytecode.
+pub fn assemble_with_map(source: &[Insn]) -> anyhow::Result<(Vec<u8>, Vec<InsnSpan>)> {
+    let bytecodes = emit(source).map_err(combine_errors)?;
+    let opcodes = select_opcodes(source);
+    let mut spans = Vec::with_capacity(source.len());
+    let mut offset = 0;
+    for (insn_index, (insn, &opcode)) in source.iter().zip(&opcodes).enumerate() {
+        let byte_end = offset + 1 + operand_len(insn, opcode);
+        spans.push(InsnSpan {
+            insn_index,
+            byte_start: offset,
+            byte_end,
+        });
+        offset = byte_end;
+    }
+    Ok((bytecodes, spans))
+}
+
+/// Assemble a sequence of instructions and return the offset of each label
+/// along with the bytecode.
+///
+/// Other segments call subroutines at these offsets with
+/// [`ProgramBuilder::call_extern`].
+pub fn assemble_with_exports(source: &[Insn]) -> anyhow::Result<(Vec<u8>, HashMap<String, usize>)> {
+    let (bytecodes, spans) = assemble_with_map(source)?;
+    let exports = spans
+        .iter()
+        .filter_map(|span| {
+            let label = source[span.insn_index].label.as_ref()?;
+            Some((label.to_string(), span.byte_start))
+        })
+        .collect();
+    Ok((bytecodes, exports))
+}
+
+/// Concatenate separately assembled segments into a single program.
+///
+/// Absolute branch targets are moved by the offset of their segment in the
+/// program, and fail to link if they no longer fit in their operand.
+/// Calls to other segments are written as the 3-byte placeholder
+/// `0xFF <segment> <offset>`, where `segment` is an index in `segments` and
+/// `offset` is exported by [`assemble_with_exports`], and are linked as
+/// `Callw` instructions.
+///
+/// Segments must not patch their own code nor use extension opcodes.
+pub fn link(segments: &[(&str, &[u8])]) -> anyhow::Result<Vec<u8>> {
+    let mut bases = Vec::with_capacity(segments.len());
+    let mut size = 0;
+    for (_, code) in segments {
+        bases.push(size);
+        size += code.len();
+    }
+
+    let mut linked = Vec::with_capacity(size);
+    for (&(name, code), &base) in segments.iter().zip(&bases) {
+        let mut offset = 0;
+        while offset < code.len() {
+            let context = || format!("segment `{}` offset {}", name, offset);
+            let opcode = Opcode::try_from(code[offset]).with_context(context)?;
+            let len = opcode.operand_len();
+            let operand = code
+                .get(offset + 1..offset + 1 + len)
+                .ok_or_else(|| anyhow!("{}: missing operand", context()))?;
+            if opcode == Opcode::CallExtern {
+                let (segment, target) = (operand[0] as usize, operand[1] as usize);
+                let address = match segments.get(segment) {
+                    Some((_, code)) if target < code.len() => bases[segment] + target,
+                    _ => bail!("{}: undefined external {}:{}", context(), segment, target),
+                };
+                let address = u16::try_from(address)
+                    .map_err(|_| anyhow!("{}: external address {} too far", context(), address))?;
+                linked.push(Opcode::Callw as u8);
+                linked.extend(address.to_le_bytes());
+            } else if opcode.is_branch() && !opcode.is_relative() {
+                let target = operand
+                    .iter()
+                    .rev()
+                    .fold(0, |acc, &b| (acc << 8) | b as usize);
+                let address = base + target;
+                if address >> (8 * len) != 0 {
+                    bail!("{}: branch target {} too far", context(), address);
+                }
+                linked.push(opcode as u8);
+                linked.extend(&address.to_le_bytes()[..len]);
+            } else {
+                linked.extend(&code[offset..offset + 1 + len]);
+            }
+            offset += 1 + len;
+        }
+    }
+    Ok(linked)
+}
+
+/// Bytecode that can be loaded at any address with [`relocate`].
+#[derive(Debug, Clone, Default)]
+pub struct PicBytecode {
+    /// Bytecode assembled to be loaded at address 0.
+    pub code: Vec<u8>,
+
+    /// Operands of `code` holding absolute label addresses.
+    pub relocations: Vec<RelocEntry>,
+}
+
+/// Operand holding the absolute address of a label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelocEntry {
+    /// Offset of the first operand byte in the code.
+    pub code_offset: usize,
+
+    /// Index of the instruction the label is attached to.
+    pub label_index: usize,
+}
+
+/// Assemble a sequence of instructions and record where label addresses are
+/// stored so that the bytecode can be moved with [`relocate`].
+///
+/// Relative branches need no relocation.
+pub fn assemble_pic(source: &[Insn]) -> anyhow::Result<PicBytecode> {
+    let (code, spans) = assemble_with_map(source)?;
+    let label_indices: HashMap<_, _> = source
+        .iter()
+        .enumerate()
+        .filter_map(|(insn_index, insn)| Some((insn.label.as_deref()?, insn_index)))
+        .collect();
+    let mut relocations = Vec::new();
+    for span in &spans {
+        let opcode = Opcode::try_from(code[span.byte_start])?;
+        if let Operand::Target(label) = &source[span.insn_index].operand {
+            if !opcode.is_relative() {
+                relocations.push(RelocEntry {
+                    code_offset: span.byte_start + 1,
+                    label_index: label_indices[label.as_ref()],
+                });
+            }
+        }
+    }
+    Ok(PicBytecode { code, relocations })
+}
+
+/// Copy of position-independent bytecode with label addresses moved so that
+/// it runs when loaded at address `base`, after `base` bytes of other code.
+///
+/// Fails if a moved address does not fit in its operand.
+pub fn relocate(pic: &PicBytecode, base: usize) -> anyhow::Result<Vec<u8>> {
+    let mut code = pic.code.clone();
+    for entry in &pic.relocations {
+        let opcode = entry
+            .code_offset
+            .checked_sub(1)
+            .and_then(|offset| code.get(offset))
+            .ok_or_else(|| anyhow!("relocation at offset {} outside code", entry.code_offset))
+            .and_then(|&byte| Ok(Opcode::try_from(byte)?))?;
+        let len = opcode.operand_len();
+        let slot = code
+            .get_mut(entry.code_offset..entry.code_offset + len)
+            .ok_or_else(|| anyhow!("relocation at offset {} outside code", entry.code_offset))?;
+        let address = slot.iter().rev().fold(0, |acc, &b| (acc << 8) | b as usize) + base;
+        if len < size_of::<usize>() && address >> (8 * len) != 0 {
+            bail!(
+                "relocated address {} at offset {} does not fit in {} bytes",
+                address,
+                entry.code_offset,
+                len
+            );
+        }
+        slot.copy_from_slice(&address.to_le_bytes()[..len]);
+    }
+    Ok(code)
+}
+
+/// Index of branch instruction, label name, offset and length of operand to
+/// patch with label address and whether the branch is relative.
+type Relocation<'s> = (usize, &'s str, usize, usize, bool);
+
+/// Number of operand bytes `opcode` is emitted with in `insn`.
+fn operand_len(insn: &Insn, opcode: Opcode) -> usize {
+    match insn.operand {
+        Operand::None => 0,
+        Operand::Value(_) if opcode.implicit_value().is_some() => 0,
+        Operand::Target(_) | Operand::Value(_) => opcode.operand_len(),
+    }
+}
+
+/// Pick for every instruction the opcode variant wide enough for its operand.
+///
+/// Values that do not fit in a byte select a 16-bit or 32-bit variant.
+/// `Pusha` and `Popa` given a register index become `Pushr` and `Popr`.
+/// Branches to labels start as their relative variant and fall back to the
+/// absolute one when the label is out of reach of a signed byte offset.
+///
+/// Widening a branch moves the labels that follow it, which may in turn
+/// require widening other branches, so this iterates until no branch grows.
+fn select_opcodes(source: &[Insn]) -> Vec<Opcode> {
+    let widen = |opcode: &mut Opcode| match opcode.wide() {
+        Some(wide) => {
+            *opcode = wide;
+            true
+        }
+        None => false,
+    };
+    let mut opcodes: Vec<_> = source.iter().map(|insn| insn.opcode).collect();
+    for (insn, opcode) in source.iter().zip(&mut opcodes) {
+        if let (Operand::Value(_), Some(indexed)) = (&insn.operand, opcode.indexed()) {
+            *opcode = indexed;
+        }
+        match insn.operand {
+            Operand::Value(value) => *opcode = fit_value(*opcode, value),
+            Operand::Target(_) => *opcode = opcode.relative().unwrap_or(*opcode),
+            Operand::None => (),
+        }
+    }
+    loop {
+        let labels = scan_labels(source, &opcodes);
+        let mut changed = false;
+        let mut offset = 0;
+        for (insn, opcode) in source.iter().zip(&mut opcodes) {
+            let next = offset + 1 + operand_len(insn, *opcode);
+            if let Operand::Target(label) = &insn.operand {
+                if let Some(&address) = labels.get(label.as_ref()) {
+                    // Branches written relative in the source stay so.
+                    if opcode.is_relative()
+                        && *opcode != insn.opcode
+                        && relative_offset(next, address).is_none()
+                    {
+                        *opcode = insn.opcode;
+                        changed = true;
+                    }
+                    if !opcode.is_relative() && address > u8::MAX as usize {
+                        changed |= widen(opcode);
+                    }
+                }
+            }
+            offset = next;
+        }
+        if !changed {
+            return opcodes;
+        }
+    }
+}
+
+/// Narrowest variant of `opcode` whose operand can hold `value`.
+///
+/// Values with a dedicated opcode, such as 0 and 1 for `Push`, select it.
+fn fit_value(opcode: Opcode, value: u32) -> Opcode {
+    if let Some(implicit) = opcode.implicit(value) {
+        implicit
+    } else if value > u16::MAX as u32 {
+        opcode.wide32().unwrap_or(opcode)
+    } else if value > u8::MAX as u32 {
+        opcode.wide().unwrap_or(opcode)
+    } else {
+        opcode
+    }
+}
+
+/// Report in `errors` labels attached to several instructions.
+fn check_duplicate_labels(source: &[Insn], errors: &mut Vec<AsmError>) {
+    let mut first_uses = HashMap::new();
+    for (insn_index, insn) in source.iter().enumerate() {
+        if let Some(label) = &insn.label {
+            if let Some(&first) = first_uses.get(label.as_ref()) {
+                errors.push(AsmError::DuplicateLabel {
+                    label: label.to_string(),
+                    first,
+                    second: insn_index,
+                });
+            } else {
+                first_uses.insert(label.as_ref(), insn_index);
+            }
+        }
+    }
+}
+
+/// Compute address of every label.
+fn scan_labels<'s>(source: &'s [Insn], opcodes: &[Opcode]) -> HashMap<&'s str, usize> {
+    let mut labels = HashMap::new();
+    let mut offset = 0;
+    for (insn, &opcode) in source.iter().zip(opcodes) {
+        if let Some(label) = &insn.label {
+            labels.insert(label.as_ref(), offset);
+        }
+        offset += 1 + operand_len(insn, opcode);
+    }
+    labels
+}
+
+/// Signed byte offset from `next` to `address`, if in range.
+fn relative_offset(next: usize, address: usize) -> Option<u8> {
+    let offset = address as isize - next as isize;
+    i8::try_from(offset).ok().map(|offset| offset as u8)
+}
+
+/// Little-endian encoding of operand `value` of instruction at `insn_index`
+/// on `len` bytes.
+fn encode_operand(insn_index: usize, value: u32, len: usize) -> Result<Vec<u8>, AsmError> {
+    let max = u32::MAX >> (32 - 8 * len);
+    if value > max {
+        return Err(AsmError::OperandOutOfRange { insn_index, value });
+    }
+    Ok(value.to_le_bytes()[..len].to_vec())
+}
+
+/// Encoding of immediate `value` of instruction at `insn_index` on `len`
+/// bytes, checking that it is meaningful for `opcode`.
+///
+/// `Pushi` operands are the value pushed, which must survive truncation to a
+/// signed byte.
+fn encode_value(
+    insn_index: usize,
+    opcode: Opcode,
+    value: u32,
+    len: usize,
+) -> Result<Vec<u8>, AsmError> {
+    let out_of_range = AsmError::OperandOutOfRange { insn_index, value };
+    match opcode {
+        Opcode::Pushr | Opcode::Popr if value as usize >= REGISTER_COUNT => Err(out_of_range),
+        Opcode::Pushi => match i8::try_from(value as i32) {
+            Ok(byte) => Ok(vec![byte as u8]),
+            Err(_) => Err(out_of_range),
+        },
+        _ => encode_operand(insn_index, value, len),
+    }
+}
+
+/// Translate instructions into bytecodes, leaving branch targets to
+/// relocate.
+///
+/// Operands that do not fit are left zeroed and reported in `errors`.
+fn emit_bytecodes<'s>(
+    source: &'s [Insn],
+    opcodes: &[Opcode],
+    errors: &mut Vec<AsmError>,
+) -> (Vec<u8>, Vec<Relocation<'s>>) {
+    let mut relocations = Vec::new();
+    let mut bytecodes = Vec::new();
+    for (insn_index, (insn, &opcode)) in source.iter().zip(opcodes).enumerate() {
+        bytecodes.push(opcode as u8);
+        let len = operand_len(insn, opcode);
+        match &insn.operand {
+            // Implicit values, and operands reported by `check_operands()`
+            // or `check_macro_calls()`.
+            _ if len == 0 => (),
+            Operand::None => (),
+            Operand::Target(label) => {
+                relocations.push((
+                    insn_index,
+                    label.as_ref(),
+                    bytecodes.len(),
+                    len,
+                    opcode.is_relative(),
+                ));
+                bytecodes.resize(bytecodes.len() + len, 0)
+            }
+            Operand::Value(value) => match encode_value(insn_index, opcode, *value, len) {
+                Ok(encoded) => bytecodes.extend(encoded),
+                Err(e) => {
+                    errors.push(e);
+                    bytecodes.resize(bytecodes.len() + len, 0)
+                }
+            },
+        }
+    }
+    (bytecodes, relocations)
+}
+
+/// Patch branch targets.
+///
+/// Reports in `errors` undefined labels and targets that do not fit in their
+/// operand, as happens when a branch without wide variant jumps past the
+/// first 256 bytes or a relative branch jumps too far.
+fn patch_targets(
+    bytecodes: &mut [u8],
+    relocations: &[Relocation],
+    labels: &HashMap<&str, usize>,
+    errors: &mut Vec<AsmError>,
+) {
+    for &(insn_index, label, offset, len, relative) in relocations {
+        let Some(&address) = labels.get(label) else {
+            errors.push(AsmError::UndefinedLabel {
+                insn_index,
+                label: label.to_string(),
+            });
+            continue;
+        };
+        let encoded = if relative {
+            relative_offset(offset + len, address)
+                .map(|offset| vec![offset])
+                .ok_or(AsmError::OperandOutOfRange {
+                    insn_index,
+                    value: address as u32,
+                })
+        } else {
+            encode_operand(insn_index, address as u32, len)
+        };
+        match encoded {
+            Ok(encoded) => bytecodes[offset..offset + len].copy_from_slice(&encoded),
+            Err(e) => errors.push(e),
+        }
+    }
+}
+
+/// Time spent in each assembler pass.
+#[cfg(feature = "profiling")]
+#[derive(Debug, Clone, Default)]
+pub struct AssemblerProfile {
+    pub label_scan: std::time::Duration,
+    pub emission: std::time::Duration,
+    pub relocation: std::time::Duration,
+}
+
+/// Assemble a sequence of instructions and measure how long each pass takes.
+#[cfg(feature = "profiling")]
+pub fn assemble_timed(source: &[Insn]) -> (anyhow::Result<Vec<u8>>, AssemblerProfile) {
+    use std::time::Instant;
+
+    let mut profile = AssemblerProfile::default();
+    let mut start = Instant::now();
+    let result = emit_passes(source, |phase| {
+        let elapsed = start.elapsed();
+        match phase {
+            Phase::LabelScan => profile.label_scan = elapsed,
+            Phase::Emission => profile.emission = elapsed,
+            Phase::Relocation => profile.relocation = elapsed,
+        }
+        start = Instant::now();
+    });
+    (result.map_err(combine_errors), profile)
+}
+
+/// Remove instruction sequences that have no effect and shorten others:
+/// - unlabeled jumps to the following instruction;
+/// - unlabeled additions or subtractions of zero;
+/// - additions or subtractions of one, which become `Inc` or `Dec`;
+/// - duplications followed by `Out`, which become `OutPeek`.
+fn optimize_peephole(mut insns: Vec<Insn>) -> Vec<Insn> {
+    let mut i = 0;
+    while i + 1 < insns.len() {
+        let (insn, next) = (&insns[i], &insns[i + 1]);
+        let jumps_to_next = insn.label.is_none()
+            && insn.opcode == Opcode::Jmp
+            && matches!((&insn.operand, &next.label), (Operand::Target(t), Some(l)) if t == l);
+        let adds_zero = insn.label.is_none()
+            && next.label.is_none()
+            && insn.opcode == Opcode::Push
+            && matches!(insn.operand, Operand::Value(0))
+            && matches!(next.opcode, Opcode::Add | Opcode::Sub);
+        let fused = match (insn.opcode, &insn.operand, next.opcode) {
+            (Opcode::Push, Operand::Value(1), Opcode::Add) => Some(Opcode::Inc),
+            (Opcode::Push, Operand::Value(1), Opcode::Sub) => Some(Opcode::Dec),
+            (Opcode::Dup, Operand::None, Opcode::Out) => Some(Opcode::OutPeek),
+            _ => None,
+        }
+        .filter(|_| next.label.is_none());
+        if jumps_to_next {
+            insns.remove(i);
+            i = i.saturating_sub(1);
+        } else if adds_zero {
+            insns.drain(i..i + 2);
+            i = i.saturating_sub(1);
+        } else if let Some(opcode) = fused {
+            let label = insns.remove(i).label;
+            insns[i] = Insn::new(opcode);
+            insns[i].label = label;
+            i += 1;
+        } else {
+            i += 1;
+        }
+    }
+    insns
+}
+
+/// Remove instructions unreachable from the entry point.
+fn eliminate_dead_insns(insns: Vec<Insn>) -> Result<Vec<Insn>, Vec<AsmError>> {
+    // Linker placeholders do not decode: keep everything.
+    let Ok(decoded) = analysis::decode(&emit(&insns)?) else {
+        return Ok(insns);
+    };
+    let live = analysis::reachable(&decoded);
+    Ok(insns
+        .into_iter()
+        .zip(live)
+        .filter_map(|(insn, live)| live.then_some(insn))
+        .collect())
+}
+
+/// Render instructions as assembly source with aligned columns.
+///
+/// The label column is as wide as the longest label plus its colon and a
+/// space, and numeric operands are right-aligned on the widest one.
+pub fn pretty_print(source: &[Insn]) -> anyhow::Result<String> {
+    let label_width = source
+        .iter()
+        .filter_map(|insn| insn.label.as_ref().map(|label| label.len()))
+        .max()
+        .unwrap_or(0)
+        + 2;
+    let opcode_width = source
+        .iter()
+        .map(|insn| insn.opcode.mnemonic().len())
+        .max()
+        .unwrap_or(0);
+    // `Pushi` values are shown signed, as written.
+    let value_text = |insn: &Insn, n: u32| match insn.opcode {
+        Opcode::Pushi => (n as i32).to_string(),
+        _ => n.to_string(),
+    };
+    let value_width = source
+        .iter()
+        .filter_map(|insn| match insn.operand {
+            Operand::Value(n) => Some(value_text(insn, n).len()),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+
+    let mut output = String::new();
+    for insn in source {
+        let label = insn
+            .label
+            .as_ref()
+            .map_or(String::new(), |label| format!("{}:", label));
+        let opcode = insn.opcode;
+        let line = match &insn.operand {
+            Operand::None => format!("{:<label_width$}{}", label, opcode),
+            Operand::Target(target) => {
+                format!(
+                    "{:<label_width$}{:<opcode_width$} {}",
+                    label, opcode, target
+                )
+            }
+            Operand::Value(n) => format!(
+                "{:<label_width$}{:<opcode_width$} {:>value_width$}",
+                label,
+                opcode,
+                value_text(insn, *n)
+            ),
+        };
+        output.push_str(&line);
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+/// Reconstruct assembly from raw bytecode.
+///
+/// Each line is prefixed with the hexadecimal offset of its instruction.
+/// Instructions that branches jump to are preceded by a synthesized `Lxxxx:`
+/// label named after their offset.
+pub fn disassemble(bytecode: &[u8]) -> anyhow::Result<String> {
+    if bytecode.is_empty() {
+        bail!("empty bytecode");
+    }
+    let decoded = analysis::decode(bytecode)?;
+    let targets: HashSet<usize> = decoded
+        .iter()
+        .filter_map(analysis::Decoded::target)
+        .filter(|&t| decoded.binary_search_by_key(&t, |d| d.offset).is_ok())
+        .collect();
+
+    let mut output = String::new();
+    for insn in &decoded {
+        if targets.contains(&insn.offset) {
+            output.push_str(&format!("L{:04x}:\n", insn.offset));
+        }
+        output.push_str(&format!("{:04x}: {}", insn.offset, insn.opcode));
+        match (insn.target(), insn.operand) {
+            (Some(target), _) if targets.contains(&target) => {
+                output.push_str(&format!(" L{:04x}", target))
+            }
+            (_, Some(operand)) if insn.opcode == Opcode::Pushi => {
+                output.push_str(&format!(" {}", operand as u8 as i8))
+            }
+            (_, Some(operand)) => output.push_str(&format!(" {}", operand)),
+            (_, None) => (),
+        }
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+/// Render bytecode as an assembler listing.
+///
+/// Each line holds the hexadecimal offset of an instruction, its bytes in
+/// hexadecimal, its mnemonic and its operand if any, as in
+/// `0000  08 04    push 4`.  Operands of relative branches and `Pushi` are
+/// shown as signed values.
+pub fn bytecode_listing(bytecode: &[u8]) -> anyhow::Result<String> {
+    let mut output = String::new();
+    for insn in analysis::decode(bytecode)? {
+        let bytes: Vec<_> = bytecode[insn.offset..insn.next()]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        output.push_str(&format!(
+            "{:04x}  {:<8} {}",
+            insn.offset,
+            bytes.join(" "),
+            insn.opcode
+        ));
+        match insn.operand {
+            Some(operand) if insn.opcode.is_relative() || insn.opcode == Opcode::Pushi => {
+                output.push_str(&format!(" {}", operand as u8 as i8))
+            }
+            Some(operand) => output.push_str(&format!(" {}", operand)),
+            None => (),
+        }
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+/// Render bytecode as a classic hexadecimal and ASCII dump.
+///
+/// Each line holds the hexadecimal offset of its first byte, up to 8 bytes in
+/// hexadecimal and the same bytes as ASCII with non-printable ones replaced
+/// by `.`, as in `0000: 08 04 0c 02 05 06 07     |.......|`.
+pub fn hex_dump(bytecode: &[u8]) -> String {
+    let mut output = String::new();
+    for (row, bytes) in bytecode.chunks(8).enumerate() {
+        let hex: Vec<_> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = bytes
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        output.push_str(&format!(
+            "{:04x}: {:<23}  |{}|\n",
+            row * 8,
+            hex.join(" "),
+            ascii
+        ));
+    }
+    output
+}
+
+/// Assemble instructions and render the result as an assembler listing.
+///
+/// See [`bytecode_listing`] for the format.  Labels are shown on lines of
+/// their own and branch operands as label names rather than addresses.
+pub fn listing(source: &[Insn]) -> anyhow::Result<String> {
+    let (bytecode, spans) = assemble_with_map(source)?;
+    let mut output = String::new();
+    for span in spans {
+        let insn = &source[span.insn_index];
+        if let Some(label) = &insn.label {
+            output.push_str(&format!("{}:\n", label));
+        }
+        let bytes: Vec<_> = bytecode[span.byte_start..span.byte_end]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let opcode = Opcode::try_from(bytecode[span.byte_start])?;
+        output.push_str(&format!(
+            "{:04x}  {:<8} {}",
+            span.byte_start,
+            bytes.join(" "),
+            opcode.mnemonic()
+        ));
+        match &insn.operand {
+            Operand::Target(label) => output.push_str(&format!(" {}", label)),
+            Operand::Value(_) if span.byte_end == span.byte_start + 1 => (),
+            Operand::Value(value) if opcode == Opcode::Pushi => {
+                output.push_str(&format!(" {}", *value as i32))
+            }
+            Operand::Value(value) => output.push_str(&format!(" {}", value)),
+            Operand::None => (),
+        }
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+/// Tell whether two programs only differ by the names of their labels.
+///
+/// Labels are renamed `0`, `1`, ... in order of first appearance, as
+/// definition or branch target, before comparing instructions.  Tags are
+/// ignored.
+pub fn insns_semantic_equiv(a: &[Insn], b: &[Insn]) -> bool {
+    /// Label, opcode, target and value with labels replaced by numbers.
+    type Canonical = (Option<usize>, Opcode, Option<usize>, Option<u32>);
+
+    fn canonicalize(insns: &[Insn]) -> Vec<Canonical> {
+        let mut ids = HashMap::new();
+        let mut id = |label: &str| {
+            let next = ids.len();
+            *ids.entry(label.to_string()).or_insert(next)
+        };
+        insns
+            .iter()
+            .map(|insn| {
+                let label = insn.label.as_deref().map(&mut id);
+                let (target, value) = match &insn.operand {
+                    Operand::None => (None, None),
+                    Operand::Target(t) => (Some(id(t)), None),
+                    Operand::Value(v) => (None, Some(*v)),
+                };
+                (label, insn.opcode, target, value)
+            })
+            .collect()
+    }
+    canonicalize(a) == canonicalize(b)
+}
+
+/// Assemble a program written in text form.
+///
+/// Each line holds an optional `label:` definition followed by an optional
+/// instruction made of a case-insensitive mnemonic and, if the opcode needs
+/// one, an operand.  `pusha` and `popa` accept an optional register index.
+/// Branch operands are labels, reached through a signed byte offset by
+/// relative branches such as `jmprel`.  Other operands are decimal, `0x`
+/// hexadecimal or `0b` binary integers, possibly negative decimal ones, or
+/// `'x'` character literals.  Text after `;` is ignored.
+///
+/// Opcode variants are selected as by [`assemble`]: branches use their
+/// relative form when the label is in reach and their wide form past byte
+/// 255, operands that do not fit in a byte select a wide variant, and values
+/// with a dedicated opcode such as `push 0` select it.  Variants written
+/// explicitly are kept.
+///
+/// Lines may start with a hexadecimal `offset:` prefix, which is ignored, so
+/// that the output of [`disassemble`] can be assembled back.
+///
+/// A `const NAME value` line defines a constant that non-branch operands on
+/// following lines can name instead of spelling out its value.
+pub fn parse(src: &str) -> anyhow::Result<Vec<u8>> {
+    // Translate lines into instructions, remembering the line of each so
+    // that assembly errors can point at it.
+    let mut insns = Vec::new();
+    let mut numbers = Vec::new();
+    let mut labels = HashMap::new();
+    let mut aliases = HashMap::new();
+    let mut constants = HashMap::new();
+    let mut pending_label = None;
+    for (i, text) in src.lines().enumerate() {
+        let number = i + 1;
+        let mut text = strip_comment(text).trim();
+        if let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if label.starts_with(|c: char| c.is_ascii_digit())
+                && label.chars().all(|c| c.is_ascii_hexdigit())
+            {
+                // Offset printed by `disassemble()`.
+                text = rest.trim();
+            } else if is_identifier(label) {
+                if let Some(&first) = labels.get(label) {
+                    let e = AsmError::DuplicateLabel {
+                        label: label.to_string(),
+                        first,
+                        second: insns.len(),
+                    };
+                    bail!("line {}: {}", number, e);
+                }
+                labels.insert(label, insns.len());
+                // Instructions carry a single label, so further labels of
+                // the same instruction become aliases of the first one.
+                match pending_label {
+                    Some((_, first)) => {
+                        aliases.insert(label, first);
+                    }
+                    None => pending_label = Some((number, label)),
+                }
+                text = rest.trim();
+            }
+        }
+        if text.is_empty() {
+            continue;
+        }
+        let (mnemonic, operand) = match text.split_once(char::is_whitespace) {
+            Some((mnemonic, operand)) => (mnemonic, Some(operand.trim())),
+            None => (text, None),
+        };
+        if mnemonic.eq_ignore_ascii_case("const") {
+            let (name, value) = operand
+                .and_then(|operand| operand.split_once(char::is_whitespace))
+                .ok_or_else(|| anyhow!("line {}: expected `const NAME value`", number))?;
+            if !is_identifier(name) {
+                bail!("line {}: invalid constant name `{}`", number, name);
+            }
+            let value = parse_value(value.trim()).map_err(|e| anyhow!("line {}: {}", number, e))?;
+            if constants.insert(name, value).is_some() {
+                bail!("line {}: duplicate constant `{}`", number, name);
+            }
+            continue;
+        }
+        let opcode =
+            Opcode::try_from_mnemonic(mnemonic).map_err(|e| anyhow!("line {}: {}", number, e))?;
+        let takes_operand = opcode.operand_len() > 0 || opcode.indexed().is_some();
+        let mut insn = match operand {
+            Some(operand) if !takes_operand => {
+                bail!("line {}: unexpected operand `{}`", number, operand)
+            }
+            Some(operand) if opcode.is_branch() => {
+                Insn::new(opcode).set_target(operand.to_string())
+            }
+            Some(operand) => {
+                let value = match constants.get(operand) {
+                    Some(&value) => value,
+                    None => parse_value(operand).map_err(|e| anyhow!("line {}: {}", number, e))?,
+                };
+                Insn::new(opcode).set_value(value)
+            }
+            None if opcode.operand_len() > 0 => {
+                bail!("line {}: missing operand for `{}`", number, mnemonic)
+            }
+            None => Insn::new(opcode),
+        };
+        if let Some((_, label)) = pending_label.take() {
+            insn = insn.set_label(label.to_string());
+        }
+        insns.push(insn);
+        numbers.push(number);
+    }
+    if let Some((number, label)) = pending_label {
+        bail!(
+            "line {}: {}",
+            number,
+            AsmError::DanglingLabel(label.to_string())
+        );
+    }
+    for insn in &mut insns {
+        if let Operand::Target(target) = &insn.operand {
+            if let Some(&label) = aliases.get(target.as_ref()) {
+                insn.operand = Operand::Target(label.to_string().into());
+            }
+        }
+    }
+
+    // Select opcode variants and resolve labels as for any other program.
+    // All errors are reported, each with the line it comes from.
+    emit(&insns).map_err(|errors| {
+        let messages: Vec<_> = errors
+            .iter()
+            .map(|e| match e.insn_index() {
+                Some(insn_index) => format!("line {}: {}", numbers[insn_index], e),
+                None => e.to_string(),
+            })
+            .collect();
+        anyhow!("{}", messages.join("; "))
+    })
+}
+
+/// Remove `;` comment from line, ignoring `;` in character literals and
+/// escaped quotes inside them.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, ch) in line.char_indices() {
+        match ch {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '\'' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parse integer or character literal.
+///
+/// Integers are decimal, `0x` hexadecimal or `0b` binary.  Negative decimal
+/// integers evaluate to their two's complement.  Character
+/// literals such as `'x'` accept `\n`, `\t`, `\\` and `\'` escape sequences
+/// and evaluate to the Unicode scalar value of their character.
+fn parse_value(s: &str) -> anyhow::Result<u32> {
+    if let Some(literal) = s.strip_prefix('\'').and_then(|s| s.strip_suffix('\'')) {
+        return parse_char(literal).map(|ch| ch as u32);
+    }
+    if let Some(digits) = s.strip_prefix('-') {
+        return match digits.parse::<u32>() {
+            Ok(n) if n <= 1 << 31 => Ok(n.wrapping_neg()),
+            Err(e) if *e.kind() != IntErrorKind::PosOverflow => {
+                bail!("invalid operand `{}`", s)
+            }
+            _ => bail!("integer literal `{}` out of range", s),
+        };
+    }
+    let (digits, radix) = if let Some(digits) = s.strip_prefix("0x") {
+        (digits, 16)
+    } else if let Some(digits) = s.strip_prefix("0b") {
+        (digits, 2)
+    } else {
+        (s, 10)
+    };
+    u32::from_str_radix(digits, radix).map_err(|e| match e.kind() {
+        IntErrorKind::PosOverflow => anyhow!("integer literal `{}` out of range", s),
+        _ => anyhow!("invalid operand `{}`", s),
+    })
+}
+
+/// Parse content of character literal, without its quotes.
+fn parse_char(literal: &str) -> anyhow::Result<char> {
+    let mut chars = literal.chars();
+    let ch = match chars.next() {
+        Some('\\') => match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('\\') => '\\',
+            Some('\'') => '\'',
+            Some(other) => bail!("invalid escape sequence `\\{}`", other),
+            None => bail!("invalid character literal `'{}'`", literal),
+        },
+        Some(ch) => ch,
+        None => bail!("empty character literal"),
+    };
+    if chars.next().is_some() {
+        bail!("invalid character literal `'{}'`", literal);
+    }
+    Ok(ch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::programs;
+
+    use proptest::prelude::*;
+
+    #[test]
+    fn mnemonics_parse_case_insensitively() {
+        for opcode in Opcode::all() {
+            let lower = opcode.mnemonic();
+            let mut capitalized = lower[..1].to_uppercase();
+            capitalized.push_str(&lower[1..]);
+            for spelling in [lower.to_string(), lower.to_uppercase(), capitalized] {
+                assert_eq!(
+                    Opcode::try_from_mnemonic(&spelling),
+                    Ok(opcode),
+                    "{}",
+                    spelling
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_mnemonic_error_lists_valid_mnemonics() {
+        let e = Opcode::try_from_mnemonic("frob").unwrap_err();
+        assert_eq!(e, AsmError::UnknownMnemonic("frob".to_string()));
+        let message = e.to_string();
+        assert!(message.contains("`frob`"));
+        for opcode in [Opcode::In, Opcode::Push, Opcode::Exit] {
+            assert!(message.contains(opcode.mnemonic()));
+        }
+    }
+
+    #[test]
+    fn tags_survive_clone_and_are_not_emitted() {
+        let source = vec![
+            Insn::new(Opcode::Push).set_value(7).set_tag(42),
+            Insn::new(Opcode::Out).set_tag(u64::MAX),
+            Insn::new(Opcode::Exit),
+        ];
+        let copy = source.clone();
+        let tags: Vec<_> = copy.iter().map(Insn::tag).collect();
+        assert_eq!(tags, [Some(42), Some(u64::MAX), None]);
+
+        let untagged = [
+            Insn::new(Opcode::Push).set_value(7),
+            Insn::new(Opcode::Out),
+            Insn::new(Opcode::Exit),
+        ];
+        assert_eq!(assemble(&copy).unwrap(), assemble(&untagged).unwrap());
+    }
+
+    #[test]
+    fn hot_paths_are_recorded_and_not_emitted() {
+        let counter = || {
+            ProgramBuilder::new()
+                .push(3)
+                .label("loop")
+                .dec()
+                .dup()
+                .bnez("loop")
+                .exit()
+        };
+        let annotated = counter().mark_hot_path(2..5).mark_hot_path(0..1);
+        assert_eq!(
+            annotated.hot_paths(),
+            [
+                HotPathAnnotation { start: 2, end: 5 },
+                HotPathAnnotation { start: 0, end: 1 }
+            ]
+        );
+        assert_eq!(annotated.assemble().unwrap(), counter().assemble().unwrap());
+
+        let (insns, hot_paths) = counter().mark_hot_path(2..5).build_with_metadata().unwrap();
+        assert_eq!(insns, counter().build().unwrap());
+        assert_eq!(hot_paths, [HotPathAnnotation { start: 2, end: 5 }]);
+    }
+
+    #[test]
+    fn parsed_decrypter_matches_assembled_decrypter() {
+        assert_eq!(
+            parse(programs::DECRYPTER_SOURCE).unwrap(),
+            assemble(&programs::decrypter()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_selects_relative_wide_and_implicit_forms() {
+        let bytecodes = parse("loop: push 0\npush 1\npush 300\npush 'a'\njmp loop").unwrap();
+        assert_eq!(
+            bytecodes,
+            [
+                Opcode::Push0 as u8,
+                Opcode::Push1 as u8,
+                Opcode::Pushw as u8,
+                44,
+                1,
+                Opcode::Push as u8,
+                b'a',
+                Opcode::JmpRel as u8,
+                0xf7,
+            ]
+        );
+
+        // Written explicitly, wide variants are kept.
+        let bytecodes = parse("pushw 5\nexit").unwrap();
+        assert_eq!(bytecodes, [Opcode::Pushw as u8, 5, 0, Opcode::Exit as u8]);
+    }
+
+    #[test]
+    fn parse_widens_branches_past_byte_255() {
+        let mut source = String::from("jmp end\n");
+        source.push_str(&"nop\n".repeat(300));
+        source.push_str("end: exit\n");
+        let bytecodes = parse(&source).unwrap();
+        let end = 3 + 300;
+        assert_eq!(bytecodes.len(), end + 1);
+        assert_eq!(bytecodes[0], Opcode::Jmpw as u8);
+        assert_eq!(&bytecodes[1..3], (end as u16).to_le_bytes());
+        assert_eq!(bytecodes[end], Opcode::Exit as u8);
+    }
+
+    #[test]
+    fn parse_accepts_several_labels_on_one_insn() {
+        let bytecodes = parse("a:\nb: in\ndup\nbne b\nexit").unwrap();
+        assert_eq!(bytecodes, parse("a: in\ndup\nbne a\nexit").unwrap());
+    }
+
+    #[test]
+    fn parse_errors_name_their_line() {
+        let e = parse("push 1\n\nfrob").unwrap_err().to_string();
+        assert!(e.starts_with("line 3: "), "{}", e);
+        let e = parse("push 1\nadd 2").unwrap_err().to_string();
+        assert!(e.starts_with("line 2: "), "{}", e);
+        let e = parse("jmp nowhere\nexit\nbne elsewhere")
+            .unwrap_err()
+            .to_string();
+        assert!(e.starts_with("line 1: "), "{}", e);
+        assert!(e.contains("; line 3: "), "{}", e);
+        let e = parse("pushi 200").unwrap_err().to_string();
+        assert!(e.starts_with("line 1: "), "{}", e);
+    }
+
+    #[test]
+    fn labels_may_be_computed_at_run_time() {
+        let mut source = vec![Insn::new(Opcode::Jmp).set_target(format!("case_{}", 0))];
+        for i in 0..3 {
+            source.push(
+                Insn::new(Opcode::Push)
+                    .set_value('0' as u32 + i)
+                    .set_label(format!("case_{}", i)),
+            );
+            source.push(Insn::new(Opcode::Out));
+            source.push(Insn::new(Opcode::Jmp).set_target(format!("case_{}", i + 1)));
+        }
+        source.push(Insn::new(Opcode::Exit).set_label(String::from("case_3")));
+
+        let bytecodes = assemble(&source).unwrap();
+        assert_eq!(crate::vm::run(&bytecodes, "").unwrap(), "012");
+        let text = pretty_print(&source).unwrap();
+        for i in 0..4 {
+            assert!(text.contains(&format!("case_{}", i)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn push_values_round_trip_whatever_their_width() {
+        for (value, len) in [
+            (0, 1),
+            (255, 2),
+            (256, 3),
+            (65_535, 3),
+            (65_536, 5),
+            (u32::MAX, 5),
+        ] {
+            let source = [
+                Insn::new(Opcode::Push).set_value(value),
+                Insn::new(Opcode::Exit),
+            ];
+            let bytecodes = assemble(&source).unwrap();
+            assert_eq!(bytecodes.len(), len + 1, "{}", value);
+            let mut vm = VmBuilder::new(&bytecodes).build();
+            vm.run().unwrap();
+            assert_eq!(vm.state().stack, [value]);
+        }
+    }
+
+    #[test]
+    fn push_of_byte_value_takes_two_bytes() {
+        for value in [2, 'a' as u32, 255] {
+            let bytecodes = assemble(&[Insn::new(Opcode::Push).set_value(value)]).unwrap();
+            assert_eq!(bytecodes, [Opcode::Push as u8, value as u8]);
+        }
+    }
+
+    #[test]
+    fn push32_is_selected_past_16_bits() {
+        let bytecodes = assemble(&[Insn::new(Opcode::Push).set_value(u32::MAX)]).unwrap();
+        assert_eq!(bytecodes, [Opcode::Push32 as u8, 0xff, 0xff, 0xff, 0xff]);
+        let bytecodes = assemble(&[Insn::new(Opcode::Push).set_value(0x1234_5678)]).unwrap();
+        assert_eq!(bytecodes, [Opcode::Push32 as u8, 0x78, 0x56, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn assemble_checked_reports_all_errors() {
+        let source = [
+            Insn::new(Opcode::Nop).set_label("start"),
+            Insn::new(Opcode::Jmp).set_target("nowhere"),
+            Insn::new(Opcode::Nop).set_label("start"),
+            Insn::new(Opcode::Pushi).set_value(200),
+            Insn::new(Opcode::Exit),
+        ];
+        let errors = assemble_checked(&source).unwrap_err();
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors.contains(&AsmError::UndefinedLabel {
+            insn_index: 1,
+            label: "nowhere".to_string(),
+        }));
+        assert!(errors.contains(&AsmError::DuplicateLabel {
+            label: "start".to_string(),
+            first: 0,
+            second: 2,
+        }));
+        assert!(errors.contains(&AsmError::OperandOutOfRange {
+            insn_index: 3,
+            value: 200,
+        }));
+
+        let message = assemble(&source).unwrap_err().to_string();
+        for e in &errors {
+            assert!(message.contains(&e.to_string()), "{}", message);
+        }
+    }
+
+    #[test]
+    fn duplicate_labels_are_reported_with_both_indices() {
+        let mut source = vec![Insn::new(Opcode::Nop); 7];
+        source[2] = Insn::new(Opcode::Nop).set_label("again");
+        source[5] = Insn::new(Opcode::Nop).set_label("again");
+        source[6] = Insn::new(Opcode::Jmp).set_target("again");
+        let errors = assemble_checked(&source).unwrap_err();
+        assert_eq!(
+            errors,
+            [AsmError::DuplicateLabel {
+                label: "again".to_string(),
+                first: 2,
+                second: 5,
+            }]
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "label `again` is attached to both instructions 2 and 5"
+        );
+
+        let e = parse("nop\nnop\nagain: nop\nnop\nnop\nagain: nop")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            e,
+            "line 6: label `again` is attached to both instructions 2 and 5"
+        );
+    }
+
+    #[test]
+    fn byte_operands_must_fit() {
+        let source = |value| {
+            [
+                Insn::new(Opcode::Nop),
+                Insn::new(Opcode::InOr).set_value(value),
+                Insn::new(Opcode::Exit),
+            ]
+        };
+        let bytecodes = assemble(&source(255)).unwrap();
+        assert_eq!(&bytecodes[1..3], [Opcode::InOr as u8, 255]);
+
+        let errors = assemble_checked(&source(256)).unwrap_err();
+        assert_eq!(
+            errors,
+            [AsmError::OperandOutOfRange {
+                insn_index: 1,
+                value: 256
+            }]
+        );
+        let message = errors[0].to_string();
+        assert_eq!(message, "operand 256 of instruction 1 is out of range");
+    }
+
+    #[test]
+    fn pushi_operands_must_fit_in_signed_byte() {
+        for value in [127, -128] {
+            let source = [Insn::new(Opcode::Pushi).set_value_i8(value)];
+            assert_eq!(
+                assemble(&source).unwrap(),
+                [Opcode::Pushi as u8, value as u8]
+            );
+        }
+        for value in [128, (-129i32) as u32] {
+            let source = [Insn::new(Opcode::Pushi).set_value(value)];
+            assert_eq!(
+                assemble_checked(&source),
+                Err(vec![AsmError::OperandOutOfRange {
+                    insn_index: 0,
+                    value
+                }])
+            );
+        }
+    }
+
+    /// Program starting with a jump forward over `gap` bytes.
+    fn forward_jump(gap: usize) -> Vec<u8> {
+        let mut source = vec![Insn::new(Opcode::Jmp).set_target("end")];
+        source.extend(vec![Insn::new(Opcode::Nop); gap]);
+        source.push(
+            Insn::new(Opcode::Push)
+                .set_value('T' as u32)
+                .set_label("end"),
+        );
+        source.push(Insn::new(Opcode::Out));
+        source.push(Insn::new(Opcode::Exit));
+        assemble(&source).unwrap()
+    }
+
+    /// Program jumping backward by `distance` bytes from the end of the jump,
+    /// returning its bytecodes and the index of the jump opcode.
+    fn backward_jump(distance: usize) -> (Vec<u8>, usize) {
+        let mut source = vec![
+            Insn::new(Opcode::Jmp).set_target("entry"),
+            Insn::new(Opcode::Push)
+                .set_value('T' as u32)
+                .set_label("target"),
+            Insn::new(Opcode::Out),
+            Insn::new(Opcode::Exit),
+        ];
+        // `target` is at byte 2 and the backward jump ends 2 bytes after the
+        // filler.
+        source.extend(vec![Insn::new(Opcode::Nop); distance - 6]);
+        source.push(
+            Insn::new(Opcode::Jmp)
+                .set_target("target")
+                .set_label("entry"),
+        );
+        let bytecodes = assemble(&source).unwrap();
+        let at = bytecodes.len() - 2;
+        (bytecodes, at)
+    }
+
+    #[test]
+    fn relative_branches_reach_127_bytes_forward() {
+        let bytecodes = forward_jump(127);
+        assert_eq!(&bytecodes[..2], [Opcode::JmpRel as u8, 127]);
+        assert_eq!(crate::vm::run(&bytecodes, "").unwrap(), "T");
+
+        let bytecodes = forward_jump(128);
+        assert_eq!(&bytecodes[..2], [Opcode::Jmp as u8, 130]);
+        assert_eq!(crate::vm::run(&bytecodes, "").unwrap(), "T");
+    }
+
+    #[test]
+    fn relative_branches_reach_128_bytes_backward() {
+        for distance in [127, 128] {
+            let (bytecodes, at) = backward_jump(distance);
+            assert_eq!(
+                &bytecodes[at..at + 2],
+                [Opcode::JmpRel as u8, (distance as i8).wrapping_neg() as u8]
+            );
+            assert_eq!(crate::vm::run(&bytecodes, "").unwrap(), "T");
+        }
+
+        let (bytecodes, at) = backward_jump(129);
+        assert_eq!(&bytecodes[at..at + 2], [Opcode::Jmp as u8, 2]);
+        assert_eq!(crate::vm::run(&bytecodes, "").unwrap(), "T");
+    }
+
+    #[test]
+    fn explicit_relative_branch_out_of_reach_fails() {
+        let mut source = vec![Insn::new(Opcode::JmpRel).set_target("end")];
+        source.extend(vec![Insn::new(Opcode::Nop); 128]);
+        source.push(Insn::new(Opcode::Exit).set_label("end"));
+        assert_eq!(
+            assemble_checked(&source),
+            Err(vec![AsmError::OperandOutOfRange {
+                insn_index: 0,
+                value: 130
+            }])
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn insns_round_trip_through_json() {
+        let mut source = programs::decrypter();
+        source.push(Insn::new(Opcode::Nop).set_tag(42));
+        source.push(Insn::new(Opcode::Nop).set_label(format!("generated_{}", 1)));
+        let json = serde_json::to_string(&source).unwrap();
+        let decoded: Vec<Insn> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, source);
+        assert_eq!(assemble(&decoded).unwrap(), assemble(&source).unwrap());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn opcodes_and_operands_round_trip_through_json() {
+        for opcode in Opcode::all() {
+            let json = serde_json::to_string(&opcode).unwrap();
+            assert_eq!(serde_json::from_str::<Opcode>(&json).unwrap(), opcode);
+        }
+        for operand in [
+            Operand::None,
+            Operand::Value(u32::MAX),
+            Operand::Target("loop".into()),
+        ] {
+            let json = serde_json::to_string(&operand).unwrap();
+            assert_eq!(serde_json::from_str::<Operand>(&json).unwrap(), operand);
+        }
+    }
+
+    #[test]
+    fn hex_dump_of_decrypter() {
+        let bytecodes = assemble(&programs::decrypter()).unwrap();
+        assert_eq!(
+            hex_dump(&bytecodes),
+            "0000: 08 04 0c 00 02 39 01 07  |.....9..|\n\
+             0008: 0b 03 02 08 7a 3d 03 08  |....z=..|\n\
+             0010: 1a 04 01 0b 4c 08 1a 1d  |....L...|\n\
+             0018: 0c 38 e8                 |.8.|\n"
+        );
+    }
+
+    #[test]
+    fn hex_dump_of_nothing_is_empty() {
+        assert_eq!(hex_dump(&[]), "");
+    }
+
+    /// Macro decrementing the top of stack down to zero.
+    fn countdown() -> MacroDef {
+        let body = ProgramBuilder::new()
+            .label("loop")
+            .dec()
+            .dup()
+            .bnez("loop")
+            .build()
+            .unwrap();
+        MacroDef {
+            name: "countdown".into(),
+            body,
+        }
+    }
+
+    #[test]
+    fn macro_labels_are_unique_per_expansion() {
+        let source = ProgramBuilder::new()
+            .push(3)
+            .macro_call("countdown")
+            .push(2)
+            .macro_call("countdown")
+            .add()
+            .push('a' as u32)
+            .add()
+            .out()
+            .exit()
+            .build()
+            .unwrap();
+        let expanded = expand_macros(&[countdown()], &source).unwrap();
+        let labels: Vec<_> = expanded.iter().filter_map(|i| i.label.as_deref()).collect();
+        assert_eq!(labels, ["loop#1", "loop#3"]);
+
+        let bytecodes = assemble_with_macros(&[countdown()], &source).unwrap();
+        assert_eq!(run(&bytecodes, "").unwrap(), "a");
+    }
+
+    #[test]
+    fn macro_call_label_moves_to_body() {
+        let source = ProgramBuilder::new()
+            .push(1)
+            .label("again")
+            .macro_call("countdown")
+            .build()
+            .unwrap();
+        let expanded = expand_macros(&[countdown()], &source).unwrap();
+        assert_eq!(expanded[1].opcode, Opcode::Nop);
+        assert_eq!(expanded[1].label.as_deref(), Some("again"));
+        assert_eq!(expanded[2].label.as_deref(), Some("loop#1"));
+    }
+
+    #[test]
+    fn undefined_macro_is_reported() {
+        let source = ProgramBuilder::new()
+            .push(1)
+            .macro_call("nope")
+            .build()
+            .unwrap();
+        let e = assemble_with_macros(&[countdown()], &source).unwrap_err();
+        assert_eq!(e.to_string(), "instruction 1 calls undefined macro `nope`");
+    }
+
+    /// Opcodes random programs are made of.
+    fn round_trip_opcodes() -> Vec<Opcode> {
+        (0..=u8::MAX)
+            .filter_map(|byte| Opcode::try_from(byte).ok())
+            .filter(|&opcode| !matches!(opcode, Opcode::MacroCall | Opcode::CallExtern))
+            .collect()
+    }
+
+    /// Instruction of a program of `len` instructions labeled `L0`, `L1`...
+    fn arb_insn(len: usize) -> impl Strategy<Value = Insn> {
+        let opcodes = proptest::sample::select(round_trip_opcodes());
+        (opcodes, any::<u32>(), 0..len).prop_map(|(opcode, value, target)| {
+            let insn = Insn::new(opcode);
+            if opcode.is_branch() {
+                insn.set_target(format!("L{}", target))
+            } else if opcode == Opcode::Pushi {
+                insn.set_value_i8(value as i8)
+            } else if matches!(opcode, Opcode::Pushr | Opcode::Popr) {
+                insn.set_value(value % REGISTER_COUNT as u32)
+            } else {
+                match opcode.operand_len() {
+                    0 => insn,
+                    1 => insn.set_value(value & 0xff),
+                    2 => insn.set_value(value & 0xffff),
+                    _ => insn.set_value(value),
+                }
+            }
+        })
+    }
+
+    fn arb_program() -> impl Strategy<Value = Vec<Insn>> {
+        (1..40usize)
+            .prop_flat_map(|len| proptest::collection::vec(arb_insn(len), len))
+            .prop_map(|insns| {
+                insns
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, insn)| insn.set_label(format!("L{}", i)))
+                    .collect()
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn disassembly_assembles_back_to_same_bytecode(source in arb_program()) {
+            let bytecodes = assemble(&source).unwrap();
+            let text = disassemble(&bytecodes).unwrap();
+            prop_assert_eq!(parse(&text).unwrap(), bytecodes, "{}", text);
+        }
+    }
+
+    #[test]
+    fn map_spans_cover_bytecode_contiguously() {
+        let mut sources = vec![programs::decrypter()];
+        sources.push(
+            ProgramBuilder::new()
+                .push(1)
+                .push(300)
+                .push(70_000)
+                .label("loop")
+                .pushi(-1)
+                .add()
+                .dup()
+                .bnez("loop")
+                .exit()
+                .build()
+                .unwrap(),
+        );
+        for source in sources {
+            let (bytecodes, spans) = assemble_with_map(&source).unwrap();
+            assert_eq!(bytecodes, assemble(&source).unwrap());
+            assert_eq!(spans, assemble_with_map(&source).unwrap().1);
+
+            let indices: Vec<_> = spans.iter().map(|span| span.insn_index).collect();
+            assert_eq!(indices, (0..source.len()).collect::<Vec<_>>());
+            assert_eq!(spans.first().unwrap().byte_start, 0);
+            assert_eq!(spans.last().unwrap().byte_end, bytecodes.len());
+            for pair in spans.windows(2) {
+                assert!(pair[0].byte_start < pair[0].byte_end);
+                assert_eq!(pair[0].byte_end, pair[1].byte_start);
+            }
+
+            let offsets: Vec<_> = analysis::decode(&bytecodes)
+                .unwrap()
+                .iter()
+                .map(|insn| insn.offset)
+                .collect();
+            let starts: Vec<_> = spans.iter().map(|span| span.byte_start).collect();
+            assert_eq!(starts, offsets);
+        }
+    }
+
+    #[test]
+    fn link_resolves_calls_between_segments() {
+        let b_source = ProgramBuilder::new()
+            .push('x' as u32)
+            .label("emit")
+            .push('B' as u32)
+            .out()
+            .ret()
+            .build()
+            .unwrap();
+        let (b, exports) = assemble_with_exports(&b_source).unwrap();
+        let a_source = ProgramBuilder::new()
+            .call_extern(1, exports["emit"] as u8)
+            .push('A' as u32)
+            .out()
+            .exit()
+            .build()
+            .unwrap();
+        let a = assemble(&a_source).unwrap();
+        assert_eq!(a[0], Opcode::CallExtern as u8);
+
+        let linked = link(&[("a", &a), ("b", &b)]).unwrap();
+        assert_eq!(linked[0], Opcode::Callw as u8);
+        assert_eq!(run(&linked, "").unwrap(), "BA");
+
+        let e = link(&[("a", &a)]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "segment `a` offset 0: undefined external 1:2"
+        );
+    }
+
+    #[test]
+    fn all_undefined_labels_are_reported() {
+        let source = [
+            Insn::new(Opcode::Beq).set_target("missing"),
+            Insn::new(Opcode::Nop),
+            Insn::new(Opcode::Call).set_target("absent"),
+            Insn::new(Opcode::Exit),
+        ];
+        assert_eq!(
+            assemble_checked(&source),
+            Err(vec![
+                AsmError::UndefinedLabel {
+                    insn_index: 0,
+                    label: "missing".to_string(),
+                },
+                AsmError::UndefinedLabel {
+                    insn_index: 2,
+                    label: "absent".to_string(),
+                },
+            ])
+        );
+        assert_eq!(
+            assemble(&source).unwrap_err().to_string(),
+            "instruction 0 refers to undefined label `missing`; \
+             instruction 2 refers to undefined label `absent`"
+        );
+    }
+
+    #[test]
+    fn pretty_print_decrypter() {
+        assert_eq!(
+            pretty_print(&programs::decrypter()).unwrap(),
+            "        push    4
+        popa
+loop:   in
+        dup
+        bne   decode
+        exit
+decode: pusha
+        add
+        dup
+        push  122
+        ble   out
+        push   26
+        sub
+out:    out
+        pusha
+        inc
+        push   26
+        rem
+        popa
+        jmp   loop
+"
+        );
+    }
+
+    #[test]
+    fn pretty_print_without_labels() {
+        let source = [
+            Insn::new(Opcode::Push).set_value(7),
+            Insn::new(Opcode::Pushi).set_value_i8(-3),
+            Insn::new(Opcode::Out),
+            Insn::new(Opcode::Exit),
+        ];
+        assert_eq!(
+            pretty_print(&source).unwrap(),
+            "  push   7\n  pushi -3\n  out\n  exit\n"
+        );
+        assert_eq!(
+            parse(&pretty_print(&source).unwrap()).unwrap(),
+            assemble(&source).unwrap()
+        );
+    }
+
+    #[test]
+    fn pretty_print_with_long_label() {
+        let source = [
+            Insn::new(Opcode::Push)
+                .set_value(65)
+                .set_label("a_very_long_label_name"),
+            Insn::new(Opcode::Out).set_label("x"),
+            Insn::new(Opcode::Jmp).set_target("a_very_long_label_name"),
+            Insn::new(Opcode::Push).set_value(1000),
+        ];
+        assert_eq!(
+            pretty_print(&source).unwrap(),
+            "a_very_long_label_name: push   65
+x:                      out
+                        jmp  a_very_long_label_name
+                        push 1000
+"
+        );
+    }
+
+    #[test]
+    fn builder_decrypter_equals_hand_written_one() {
+        let built = ProgramBuilder::new()
+            .push(4)
+            .popa()
+            .label("loop")
+            .in_op()
+            .dup()
+            .bne("decode")
+            .exit()
+            .label("decode")
+            .pusha()
+            .add()
+            .dup()
+            .push('z' as u32)
+            .ble("out")
+            .push(26)
+            .sub()
+            .label("out")
+            .out()
+            .pusha()
+            .inc()
+            .push(26)
+            .rem()
+            .popa()
+            .jmp("loop")
+            .build()
+            .unwrap();
+        assert_eq!(built, programs::decrypter());
+    }
+
+    #[test]
+    fn insns_compare_and_hash_by_content() {
+        let owned = Insn::new(Opcode::Jmp).set_target(String::from("loop"));
+        let borrowed = Insn::new(Opcode::Jmp).set_target("loop");
+        assert_eq!(owned, borrowed);
+
+        let set: HashSet<Insn> = [
+            owned,
+            borrowed,
+            Insn::new(Opcode::Jmp).set_target("loop").set_label("here"),
+            Insn::new(Opcode::Push).set_value(1),
+            Insn::new(Opcode::Push).set_value(1),
+            Insn::new(Opcode::Push).set_value(2),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(set.len(), 4);
+        assert!(set.contains(&Insn::new(Opcode::Push).set_value(2)));
+        assert!(!set.contains(&Insn::new(Opcode::Push).set_value(3)));
+    }
+
+    #[test]
+    fn cloned_program_can_be_modified() {
+        let original = programs::decrypter();
+        let mut modified = original.clone();
+        modified[0] = Insn::new(Opcode::Push).set_value(5);
+        assert_ne!(modified, original);
+        assert_eq!(modified[1..], original[1..]);
+        assert_eq!(original, programs::decrypter());
+    }
+
+    #[test]
+    fn macro_expansion_yields_expected_insns() {
+        let source = ProgramBuilder::new()
+            .push(2)
+            .macro_call("countdown")
+            .exit()
+            .build()
+            .unwrap();
+        assert_eq!(
+            expand_macros(&[countdown()], &source).unwrap(),
+            [
+                Insn::new(Opcode::Push).set_value(2),
+                Insn::new(Opcode::Dec).set_label("loop#1"),
+                Insn::new(Opcode::Dup),
+                Insn::new(Opcode::Bne).set_target("loop#1"),
+                Insn::new(Opcode::Exit),
+            ]
+        );
+    }
+
+    #[test]
+    fn constants_are_substituted_into_values() {
+        let source = [
+            Insn::new(Opcode::Push).set_target("KEY").set_label("KEY"),
+            Insn::new(Opcode::Push).set_target("ALPHABET"),
+            Insn::new(Opcode::Jmp).set_target("KEY"),
+        ];
+        let bytecodes = assemble_with_consts(&[("KEY", 4), ("ALPHABET", 26)], &source).unwrap();
+        assert_eq!(
+            bytecodes,
+            [
+                Opcode::Push as u8,
+                4,
+                Opcode::Push as u8,
+                26,
+                Opcode::JmpRel as u8,
+                -6i8 as u8
+            ]
+        );
+    }
+
+    #[test]
+    fn const_directive_defines_constants() {
+        let with_consts = parse("const KEY 4\nconst Z 'z'\npush KEY\npush Z\nexit").unwrap();
+        assert_eq!(with_consts, parse("push 4\npush 'z'\nexit").unwrap());
+
+        let e = parse("const KEY 4\nCONST KEY 5").unwrap_err();
+        assert_eq!(e.to_string(), "line 2: duplicate constant `KEY`");
+        let e = parse("const 4KEY 4").unwrap_err();
+        assert_eq!(e.to_string(), "line 1: invalid constant name `4KEY`");
+    }
+
+    #[test]
+    fn relocated_pic_runs_at_its_base() {
+        let source = ProgramBuilder::new()
+            .call("sub")
+            .exit()
+            .label("sub")
+            .push('x' as u32)
+            .out()
+            .ret()
+            .build()
+            .unwrap();
+        let pic = assemble_pic(&source).unwrap();
+        assert_eq!(
+            pic.relocations,
+            [RelocEntry {
+                code_offset: 1,
+                label_index: 2,
+            }]
+        );
+        assert_eq!(relocate(&pic, 0).unwrap(), pic.code);
+
+        let mut program = parse("push 'a'\nout\nnop\nnop").unwrap();
+        let base = program.len();
+        program.extend(relocate(&pic, base).unwrap());
+        assert_eq!(run(&program, "").unwrap(), "ax");
+
+        let e = relocate(&pic, 300).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "relocated address 303 at offset 1 does not fit in 1 bytes"
+        );
+    }
+
+    #[test]
+    fn tuples_convert_like_builder() {
+        assert_eq!(Insn::from((Opcode::Dup, None)), Insn::new(Opcode::Dup));
+        assert_eq!(
+            Insn::from((Opcode::Push, Some(7))),
+            Insn::new(Opcode::Push).set_value(7)
+        );
+        assert_eq!(
+            Insn::from(("start", Opcode::Push, Some(7))),
+            Insn::new(Opcode::Push).set_value(7).set_label("start")
+        );
+        assert_eq!(
+            Insn::from(("end", Opcode::Exit, None)),
+            Insn::new(Opcode::Exit).set_label("end")
+        );
+
+        let from_tuples: Vec<Insn> = [
+            ("top", Opcode::Push, Some('a' as u32)),
+            ("print", Opcode::Out, None),
+            ("done", Opcode::Exit, None),
+        ]
+        .into_iter()
+        .map(Insn::from)
+        .collect();
+        let built = ProgramBuilder::new()
+            .label("top")
+            .push('a' as u32)
+            .label("print")
+            .out()
+            .label("done")
+            .exit()
+            .build()
+            .unwrap();
+        assert_eq!(from_tuples, built);
+    }
+
+    /// `programs::decrypter()` with labels renamed by `rename`.
+    fn renamed_decrypter(rename: impl Fn(&str) -> &'static str) -> Vec<Insn> {
+        programs::decrypter()
+            .into_iter()
+            .map(|mut insn| {
+                insn.label = insn.label.map(|label| rename(&label).into());
+                if let Operand::Target(target) = &insn.operand {
+                    insn.operand = Operand::Target(rename(target).into());
+                }
+                insn
+            })
+            .collect()
+    }
+
+    #[test]
+    fn renamed_labels_are_equivalent() {
+        let renamed = renamed_decrypter(|label| match label {
+            "loop" => "start",
+            "decode" => "shift",
+            _ => "emit",
+        });
+        assert!(insns_semantic_equiv(&programs::decrypter(), &renamed));
+        let tagged: Vec<_> = renamed.into_iter().map(|insn| insn.set_tag(1)).collect();
+        assert!(insns_semantic_equiv(&programs::decrypter(), &tagged));
+    }
+
+    #[test]
+    fn different_control_flow_is_not_equivalent() {
+        // Both branches now go to the same label.
+        let merged = renamed_decrypter(|_| "same");
+        assert!(!insns_semantic_equiv(&programs::decrypter(), &merged));
+
+        let mut rekeyed = programs::decrypter();
+        rekeyed[0] = Insn::new(Opcode::Push).set_value(5);
+        assert!(!insns_semantic_equiv(&programs::decrypter(), &rekeyed));
+
+        let shorter = &programs::decrypter()[1..];
+        assert!(!insns_semantic_equiv(&programs::decrypter(), shorter));
+    }
+
+    #[test]
+    fn listing_snapshot() {
+        let expected = "\
+0000  08 04    push 4
+0002  0c       popa
+loop:
+0003  00       in
+0004  02       dup
+0005  39 01    bnerel decode
+0007  07       exit
+decode:
+0008  0b       pusha
+0009  03       add
+000a  02       dup
+000b  08 7a    push 122
+000d  3d 03    blerel out
+000f  08 1a    push 26
+0011  04       sub
+out:
+0012  01       out
+0013  0b       pusha
+0014  4c       inc
+0015  08 1a    push 26
+0017  1d       rem
+0018  0c       popa
+0019  38 e8    jmprel loop
+";
+        assert_eq!(listing(&crate::programs::decrypter()).unwrap(), expected);
+    }
+
+    #[test]
+    fn bytecode_listing_snapshot() {
+        let expected = "\
+0000  08 04    push 4
+0002  0c       popa
+0003  00       in
+0004  02       dup
+0005  39 01    bnerel 1
+0007  07       exit
+0008  0b       pusha
+0009  03       add
+000a  02       dup
+000b  08 7a    push 122
+000d  3d 03    blerel 3
+000f  08 1a    push 26
+0011  04       sub
+0012  01       out
+0013  0b       pusha
+0014  4c       inc
+0015  08 1a    push 26
+0017  1d       rem
+0018  0c       popa
+0019  38 e8    jmprel -24
+";
+        let bytecode = assemble(&crate::programs::decrypter()).unwrap();
+        assert_eq!(bytecode_listing(&bytecode).unwrap(), expected);
+    }
+
+    #[test]
+    fn programs_past_byte_255_never_wrap_targets() {
+        // Beq to the last byte of a 258-byte program is widened rather than
+        // truncated to address 1.
+        let mut source = vec![
+            Insn::new(Opcode::Push0),
+            Insn::new(Opcode::Push0),
+            Insn::new(Opcode::Beq).set_target("end"),
+        ];
+        source.extend(vec![Insn::new(Opcode::Nop); 252]);
+        source.push(Insn::new(Opcode::Exit).set_label("end"));
+        let bytecodes = assemble(&source).unwrap();
+        assert_eq!(bytecodes.len(), 258);
+        assert_eq!(bytecodes[2], Opcode::Beqw as u8);
+        assert_eq!(&bytecodes[3..5], 257u16.to_le_bytes());
+        assert_eq!(crate::vm::run(&bytecodes, "").unwrap(), "");
+
+        // An 8-bit operand cannot hold such a target.
+        let mut patched = bytecodes.clone();
+        let labels = HashMap::from([("end", 257)]);
+        let mut errors = Vec::new();
+        patch_targets(
+            &mut patched,
+            &[(2, "end", 3, 1, false)],
+            &labels,
+            &mut errors,
+        );
+        assert_eq!(
+            errors,
+            [AsmError::OperandOutOfRange {
+                insn_index: 2,
+                value: 257
+            }]
+        );
+        assert_eq!(patched, bytecodes);
+
+        let assembler = AssemblerBuilder::new().with_max_program_size(257).build();
+        assert_eq!(
+            assembler.assemble_checked(&source),
+            Err(vec![AsmError::ProgramTooLarge {
+                size: 258,
+                max: 257
+            }])
+        );
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn assemble_timed_runs_the_same_checks_as_assemble() {
+        let source = [
+            Insn::new(Opcode::Nop).set_label("again"),
+            Insn::new(Opcode::Nop).set_label("again"),
+            Insn::new(Opcode::Jmp).set_target("again"),
+        ];
+        let (result, _) = assemble_timed(&source);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            assemble(&source).unwrap_err().to_string()
+        );
+
+        let source = [Insn::new(Opcode::Push).set_value(7), Insn::new(Opcode::Out)];
+        let (result, _) = assemble_timed(&source);
+        assert_eq!(result.unwrap(), assemble(&source).unwrap());
+    }
+
+    #[test]
+    fn program_builds_loops_at_run_time() {
+        for count in 1..4 {
+            let mut program = Program::new();
+            program.push_val(count);
+            for ch in ['a', 'b'] {
+                let label = format!("loop_{}", ch);
+                program
+                    .dup()
+                    .label(&label)
+                    .push_val(ch as u32)
+                    .out()
+                    .dec()
+                    .dup()
+                    .bnez_to(&label)
+                    .drop();
+            }
+            program.exit();
+            let bytecodes = program.assemble().unwrap();
+            let expected = "a".repeat(count as usize) + &"b".repeat(count as usize);
+            assert_eq!(crate::vm::run(&bytecodes, "").unwrap(), expected);
+        }
+
+        let mut program = Program::new();
+        program.jmp_to("nowhere");
+        assert!(program.assemble().is_err());
     }
-    Ok(output)
 }
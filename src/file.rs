@@ -0,0 +1,119 @@
+//! Bytecode file format
+//!
+//! A file holds the magic bytes `ENAA`, a version byte, the little-endian
+//! 16-bit length of the program and the program bytecodes.
+
+use std::io::{self, Read, Write};
+
+use anyhow::{bail, Context};
+
+const MAGIC: &[u8; 4] = b"ENAA";
+
+const VERSION: u8 = 0;
+
+/// Write `bytecode` to `w` in file format.
+///
+/// Fails with [`io::ErrorKind::InvalidInput`] if the program is longer than
+/// 65535 bytes.
+pub fn write_bytecode(bytecode: &[u8], w: &mut impl Write) -> io::Result<()> {
+    let len = u16::try_from(bytecode.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "program size {} exceeds maximum {}",
+                bytecode.len(),
+                u16::MAX
+            ),
+        )
+    })?;
+    w.write_all(MAGIC)?;
+    w.write_all(&[VERSION])?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(bytecode)
+}
+
+/// Read bytecode in file format from `r`.
+pub fn read_bytecode(r: &mut impl Read) -> anyhow::Result<Vec<u8>> {
+    let mut header = [0; 7];
+    r.read_exact(&mut header).context("reading header")?;
+    if &header[..4] != MAGIC {
+        bail!("not a bytecode file");
+    }
+    if header[4] != VERSION {
+        bail!("unsupported bytecode file version {}", header[4]);
+    }
+    let len = u16::from_le_bytes([header[5], header[6]]) as usize;
+    let mut bytecode = vec![0; len];
+    r.read_exact(&mut bytecode).context("reading bytecode")?;
+    Ok(bytecode)
+}
+
+/// Parse `data` as a bytecode file if it starts with the magic bytes, or take
+/// it as raw bytecode otherwise.
+pub fn read_bytecode_or_raw(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if data.starts_with(MAGIC) {
+        read_bytecode(&mut &data[..])
+    } else {
+        Ok(data.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::programs;
+
+    fn to_file(bytecode: &[u8]) -> Vec<u8> {
+        let mut file = Vec::new();
+        write_bytecode(bytecode, &mut file).unwrap();
+        file
+    }
+
+    #[test]
+    fn programs_round_trip() {
+        let decrypter = assemble(&programs::decrypter()).unwrap();
+        for bytecode in [&[][..], &[0], &decrypter, &[0xff; 300], &vec![7; 65_535]] {
+            let file = to_file(bytecode);
+            assert_eq!(file.len(), 7 + bytecode.len());
+            assert_eq!(read_bytecode(&mut &file[..]).unwrap(), bytecode);
+            assert_eq!(read_bytecode_or_raw(&file).unwrap(), bytecode);
+        }
+    }
+
+    #[test]
+    fn oversized_program_is_rejected() {
+        let e = write_bytecode(&vec![0; 65_536], &mut Vec::new()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn wrong_magic_is_rejected() {
+        let mut file = to_file(&[1, 2, 3]);
+        file[0] = b'X';
+        let e = read_bytecode(&mut &file[..]).unwrap_err();
+        assert_eq!(e.to_string(), "not a bytecode file");
+        // Without magic, data is raw bytecode.
+        assert_eq!(read_bytecode_or_raw(&file).unwrap(), file);
+    }
+
+    #[test]
+    fn truncated_file_is_rejected() {
+        let file = to_file(&[1, 2, 3]);
+        for len in 0..file.len() {
+            assert!(read_bytecode(&mut &file[..len]).is_err(), "{}", len);
+        }
+        let e = read_bytecode(&mut &file[..5]).unwrap_err();
+        assert_eq!(e.to_string(), "reading header");
+        let e = read_bytecode(&mut &file[..8]).unwrap_err();
+        assert_eq!(e.to_string(), "reading bytecode");
+    }
+
+    #[test]
+    fn version_mismatch_is_rejected() {
+        let mut file = to_file(&[1, 2, 3]);
+        file[4] = VERSION + 1;
+        let e = read_bytecode(&mut &file[..]).unwrap_err();
+        assert_eq!(e.to_string(), "unsupported bytecode file version 1");
+    }
+}
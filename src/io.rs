@@ -0,0 +1,264 @@
+//! VM input and output channels
+//!
+//! Values exchanged are those handled by character I/O instructions, by
+//! default Unicode scalar values.  Strings are channels themselves: a `&str`
+//! is consumed from its front and a `String` accumulates output.
+
+#[cfg(feature = "std")]
+use alloc::collections::VecDeque;
+#[cfg(feature = "alloc")]
+use alloc::string::{String, ToString};
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, Write};
+
+/// Channel a VM reads input values from.
+pub trait VmInput {
+    /// Return next input value or `None` on end of input.
+    fn next_u32(&mut self) -> Option<u32>;
+
+    /// Can `next_u32()` be called without waiting for more input?
+    ///
+    /// Channels fed incrementally, such as by an interactive front end, return
+    /// `false` while they are empty but not closed.  [`crate::vm::Vm::step`]
+    /// then reports [`crate::vm::StepOutcome::NeedsInput`].
+    fn input_ready(&mut self) -> bool {
+        true
+    }
+}
+
+/// Channel a VM writes output values to.
+pub trait VmOutput {
+    /// Append value to output.
+    ///
+    /// Errors stop the VM with [`crate::vm::VmError::Output`].
+    fn write_u32(&mut self, v: u32) -> Result<(), OutputError>;
+
+    /// Discard output buffered so far, if any.
+    ///
+    /// Channels that do not buffer output, such as stdout, ignore this.
+    fn clear_output(&mut self) {}
+
+    /// Number of characters buffered in output, or 0 if output is not
+    /// buffered.
+    fn output_len(&self) -> usize {
+        0
+    }
+
+    /// Output buffered so far, or an empty string if output is not buffered.
+    fn output_str(&self) -> &str {
+        ""
+    }
+
+    /// Discard buffered output past the first `len` characters, if any.
+    fn truncate_output(&mut self, _len: usize) {}
+}
+
+/// Failure of an output channel to accept a value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputError(Message);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Message {
+    Static(&'static str),
+    #[cfg(feature = "alloc")]
+    Owned(String),
+}
+
+impl OutputError {
+    pub const fn new(message: &'static str) -> OutputError {
+        OutputError(Message::Static(message))
+    }
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Message::Static(message) => f.write_str(message),
+            #[cfg(feature = "alloc")]
+            Message::Owned(message) => f.write_str(message),
+        }
+    }
+}
+
+impl core::error::Error for OutputError {}
+
+#[cfg(feature = "alloc")]
+impl From<anyhow::Error> for OutputError {
+    fn from(e: anyhow::Error) -> OutputError {
+        OutputError(Message::Owned(e.to_string()))
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for OutputError {
+    fn from(e: io::Error) -> OutputError {
+        OutputError(Message::Owned(e.to_string()))
+    }
+}
+
+impl<T: VmInput + ?Sized> VmInput for &mut T {
+    fn next_u32(&mut self) -> Option<u32> {
+        (**self).next_u32()
+    }
+
+    fn input_ready(&mut self) -> bool {
+        (**self).input_ready()
+    }
+}
+
+impl<T: VmOutput + ?Sized> VmOutput for &mut T {
+    fn write_u32(&mut self, v: u32) -> Result<(), OutputError> {
+        (**self).write_u32(v)
+    }
+
+    fn clear_output(&mut self) {
+        (**self).clear_output()
+    }
+
+    fn output_len(&self) -> usize {
+        (**self).output_len()
+    }
+
+    fn output_str(&self) -> &str {
+        (**self).output_str()
+    }
+
+    fn truncate_output(&mut self, len: usize) {
+        (**self).truncate_output(len)
+    }
+}
+
+impl VmInput for &str {
+    fn next_u32(&mut self) -> Option<u32> {
+        let mut chars = self.chars();
+        let ch = chars.next()?;
+        *self = chars.as_str();
+        Some(ch as u32)
+    }
+}
+
+impl VmInput for core::str::Chars<'_> {
+    fn next_u32(&mut self) -> Option<u32> {
+        self.next().map(u32::from)
+    }
+}
+
+/// Values that are not Unicode scalar values cannot be appended.
+#[cfg(feature = "alloc")]
+impl VmOutput for String {
+    fn write_u32(&mut self, v: u32) -> Result<(), OutputError> {
+        self.push(to_char(v)?);
+        Ok(())
+    }
+
+    fn clear_output(&mut self) {
+        self.clear()
+    }
+
+    fn output_len(&self) -> usize {
+        self.chars().count()
+    }
+
+    fn output_str(&self) -> &str {
+        self
+    }
+
+    fn truncate_output(&mut self, len: usize) {
+        truncate_chars(self, len)
+    }
+}
+
+/// Input read line by line from stdin.
+///
+/// Read errors are treated as end of input.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct StdinInput {
+    pending: VecDeque<char>,
+}
+
+#[cfg(feature = "std")]
+impl StdinInput {
+    pub fn new() -> StdinInput {
+        StdinInput::default()
+    }
+}
+
+#[cfg(feature = "std")]
+impl VmInput for StdinInput {
+    fn next_u32(&mut self) -> Option<u32> {
+        if self.pending.is_empty() {
+            let mut line = String::new();
+            match io::stdin().lock().read_line(&mut line) {
+                Ok(0) | Err(_) => return None,
+                Ok(_) => self.pending.extend(line.chars()),
+            }
+        }
+        self.pending.pop_front().map(u32::from)
+    }
+}
+
+/// Output written to stdout.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct StdoutOutput;
+
+#[cfg(feature = "std")]
+impl VmOutput for StdoutOutput {
+    fn write_u32(&mut self, v: u32) -> Result<(), OutputError> {
+        write!(io::stdout(), "{}", to_char(v)?)?;
+        Ok(())
+    }
+}
+
+/// Character whose Unicode scalar value is `v`.
+#[cfg(feature = "alloc")]
+fn to_char(v: u32) -> anyhow::Result<char> {
+    char::from_u32(v).ok_or_else(|| anyhow::anyhow!("invalid code point {}", v))
+}
+
+/// Keep the first `len` characters of `s`.
+#[cfg(feature = "alloc")]
+fn truncate_chars(s: &mut String, len: usize) {
+    if let Some((offset, _)) = s.char_indices().nth(len) {
+        s.truncate(offset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn str_input_is_consumed_from_front() {
+        let mut input = "aé€";
+        assert_eq!(input.next_u32(), Some('a' as u32));
+        assert_eq!(input, "é€");
+        assert_eq!(input.next_u32(), Some('é' as u32));
+        assert_eq!(input.next_u32(), Some('€' as u32));
+        assert_eq!(input.next_u32(), None);
+        assert_eq!(input.next_u32(), None);
+    }
+
+    #[test]
+    fn string_output_accumulates_characters() {
+        let mut output = String::new();
+        for ch in "hé€".chars() {
+            output.write_u32(ch as u32).unwrap();
+        }
+        assert_eq!(output.output_str(), "hé€");
+        assert_eq!(output.output_len(), 3);
+        output.truncate_output(1);
+        assert_eq!(output, "h");
+    }
+
+    #[test]
+    fn string_output_rejects_invalid_code_points() {
+        let mut output = String::from("ok");
+        let e = output.write_u32(0xd800).unwrap_err();
+        assert_eq!(e.to_string(), "invalid code point 55296");
+        assert!(output.write_u32(u32::MAX).is_err());
+        assert_eq!(output, "ok");
+    }
+}
@@ -1,4 +1,26 @@
 //! Virtual Machine Description
+//!
+//! Without the default `std` feature, only the [`vm`] and [`io`] modules are
+//! built, for targets providing `alloc` only.  Without the `alloc` feature
+//! either, nothing is allocated and [`vm::VmFixed`] is the only way to run
+//! programs.
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod analysis;
+#[cfg(feature = "std")]
 pub mod asm;
+#[cfg(feature = "std")]
+pub mod file;
+pub mod io;
+#[cfg(feature = "std")]
+pub mod programs;
+#[cfg(feature = "std")]
+pub mod validate;
 pub mod vm;
+#[cfg(feature = "wasm")]
+pub mod wasm;
@@ -3,8 +3,11 @@
 use anyhow::Context;
 use clap::{Parser, Subcommand};
 use std::fs;
+use std::io::{self, BufRead, Read, Write};
 
 use enaa::asm::*;
+use enaa::file::*;
+use enaa::programs::decrypter;
 use enaa::vm::*;
 
 #[derive(Parser)]
@@ -16,46 +19,196 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    Dis,
-    Decrypt { path: String },
+    Dis {
+        /// Print hexadecimal dump of assembled bytecode instead.
+        #[arg(long)]
+        hex: bool,
+    },
+    Decrypt {
+        path: String,
+        #[arg(long)]
+        escape: bool,
+        /// Print execution counters to stderr after output.
+        #[arg(long)]
+        stats: bool,
+    },
+    Filter {
+        #[arg(long)]
+        bytecode: Option<String>,
+    },
+    /// Run bytecode file on input file or stdin.
+    Run {
+        bytecode: String,
+        input: Option<String>,
+        /// Print output bytes as hexadecimal.
+        #[arg(long)]
+        hex: bool,
+        /// Print execution counters to stderr after output.
+        #[arg(long)]
+        stats: bool,
+    },
+    /// Assemble text source file into bytecode file.
+    Asm {
+        src: String,
+        out: String,
+        /// Also print listing of assembled program.
+        #[arg(long)]
+        print_listing: bool,
+    },
+    /// Disassemble bytecode file.
+    DisFile { path: String },
+    /// Interactively assemble and run instructions read from stdin.
+    ///
+    /// Instructions are entered one per line and a blank line runs the
+    /// program accumulated so far.  `:input TEXT` sets the program input and
+    /// `:reset` discards all instructions.
+    Repl,
 }
 
 fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
-    let bytecode = assemble(DECRYPTER)?;
+    let decrypter = decrypter();
+    let bytecode = assemble(&decrypter)?;
     match cli.command {
-        Commands::Dis => println!("{}", pretty_print(DECRYPTER)?),
-        Commands::Decrypt { path } => {
+        Commands::Dis { hex: false } => println!("{}", pretty_print(&decrypter)?),
+        Commands::Dis { hex: true } => print!("{}", hex_dump(&bytecode)),
+        Commands::Decrypt {
+            path,
+            escape,
+            stats,
+        } => {
             let cipher = fs::read_to_string(path).context("reading cipher")?;
-            println!("{}", run(&bytecode, &cipher)?);
+            let mut output = String::new();
+            let mut vm = Vm::new(&bytecode, cipher.as_str(), &mut output);
+            if escape {
+                vm = vm.with_escaped_output();
+            }
+            vm.run()?;
+            let counters = vm.stats().clone();
+            println!("{}", output);
+            if stats {
+                print_stats(&counters);
+            }
         }
+        Commands::Filter { bytecode: path } => {
+            let bytecode = match path {
+                Some(path) => read_bytecode_or_raw(&fs::read(path).context("reading bytecode")?)?,
+                None => bytecode,
+            };
+            run_filter(&bytecode)?;
+        }
+        Commands::Run {
+            bytecode: path,
+            input,
+            hex,
+            stats,
+        } => {
+            let bytecode = read_bytecode_or_raw(&fs::read(path).context("reading bytecode")?)?;
+            let input = match input {
+                Some(path) => fs::read_to_string(path).context("reading input")?,
+                None => {
+                    let mut input = String::new();
+                    io::stdin()
+                        .read_to_string(&mut input)
+                        .context("reading input")?;
+                    input
+                }
+            };
+            let (output, counters) = run_with_stats(&bytecode, &input)?;
+            if hex {
+                let bytes: Vec<_> = output.bytes().map(|b| format!("{:02x}", b)).collect();
+                println!("{}", bytes.join(" "));
+            } else {
+                println!("{}", output);
+            }
+            if stats {
+                print_stats(&counters);
+            }
+        }
+        Commands::Asm {
+            src,
+            out,
+            print_listing,
+        } => {
+            let source = fs::read_to_string(src).context("reading source")?;
+            let bytecode = parse(&source)?;
+            let mut file = fs::File::create(out).context("creating bytecode file")?;
+            write_bytecode(&bytecode, &mut file).context("writing bytecode file")?;
+            if print_listing {
+                print!("{}", bytecode_listing(&bytecode)?);
+            }
+        }
+        Commands::DisFile { path } => {
+            let bytecode = read_bytecode_or_raw(&fs::read(path).context("reading bytecode")?)?;
+            print!("{}", disassemble(&bytecode)?);
+        }
+        Commands::Repl => run_repl()?,
     }
     Ok(())
 }
-const DECRYPTER: &[Insn] = &[
-    Insn::new(Opcode::Push).set_value(4),
-    Insn::new(Opcode::Popa),
-    Insn::new(Opcode::In).set_label("loop"),
-    Insn::new(Opcode::Dup),
-    Insn::new(Opcode::Bne).set_target("decode"),
-    Insn::new(Opcode::Exit),
-    Insn::new(Opcode::Pusha).set_label("decode"),
-    Insn::new(Opcode::Add),
-    Insn::new(Opcode::Dup),
-    Insn::new(Opcode::Push).set_value('z' as u32),
-    Insn::new(Opcode::Ble).set_target("out"),
-    Insn::new(Opcode::Push).set_value(26),
-    Insn::new(Opcode::Sub),
-    Insn::new(Opcode::Out).set_label("out"),
-    Insn::new(Opcode::Pusha),
-    Insn::new(Opcode::Push).set_value(1),
-    Insn::new(Opcode::Add),
-    Insn::new(Opcode::Dup),
-    Insn::new(Opcode::Push).set_value(25),
-    Insn::new(Opcode::Bgt).set_target("wrap"),
-    Insn::new(Opcode::Popa),
-    Insn::new(Opcode::Jmp).set_target("loop"),
-    Insn::new(Opcode::Push).set_value(0).set_label("wrap"),
-    Insn::new(Opcode::Popa),
-    Insn::new(Opcode::Jmp).set_target("loop"),
-];
+
+/// Maximum number of instructions a REPL program may execute, so that
+/// infinite loops do not hang the session.
+const REPL_MAX_STEPS: u64 = 1_000_000;
+
+fn run_repl() -> anyhow::Result<()> {
+    let mut source = String::new();
+    let mut group = String::new();
+    let mut input = String::new();
+    prompt()?;
+    for line in io::stdin().lock().lines() {
+        let line = line.context("reading stdin")?;
+        let trimmed = line.trim();
+        if let Some(text) = trimmed.strip_prefix(":input") {
+            input = text.trim_start().to_string();
+        } else if trimmed == ":reset" {
+            source.clear();
+            group.clear();
+        } else if !trimmed.is_empty() {
+            group.push_str(&line);
+            group.push('\n');
+        } else {
+            let candidate = format!("{}{}", source, group);
+            group.clear();
+            // Programs stop after their last instruction.
+            match parse(&format!("{}exit\n", candidate)) {
+                Ok(bytecode) => {
+                    source = candidate;
+                    eval_repl(&bytecode, &input);
+                }
+                Err(e) => println!("error: {}", e),
+            }
+        }
+        prompt()?;
+    }
+    println!();
+    Ok(())
+}
+
+fn prompt() -> anyhow::Result<()> {
+    print!("> ");
+    io::stdout().flush().context("writing prompt")
+}
+
+/// Run REPL program and print the resulting stack, auxiliary register and
+/// output, whether it succeeded or not.
+fn eval_repl(bytecode: &[u8], input: &str) {
+    let mut vm = VmBuilder::new(bytecode)
+        .input(input)
+        .max_steps(REPL_MAX_STEPS)
+        .build();
+    if let Err(e) = vm.run() {
+        println!("error: {}", e);
+    }
+    let state = vm.state();
+    println!("stack: {:?}", state.stack);
+    println!("aux: {}", state.aux);
+    println!("output: {:?}", state.output_so_far);
+}
+
+fn print_stats(stats: &VmStats) {
+    eprintln!("instructions executed: {}", stats.instructions_executed);
+    eprintln!("max stack depth: {}", stats.max_stack_depth);
+    eprintln!("input chars read: {}", stats.input_chars_read);
+    eprintln!("output chars written: {}", stats.output_chars_written);
+}
@@ -0,0 +1,170 @@
+//! Sample programs
+
+use crate::asm::Insn;
+use crate::vm::Opcode;
+
+/// Text form of [`decrypter()`], as accepted by [`crate::asm::parse`].
+pub const DECRYPTER_SOURCE: &str = "\
+        push 4
+        popa
+loop:   in
+        dup
+        bne decode
+        exit
+decode: pusha
+        add
+        dup
+        push 'z'
+        ble out
+        push 26
+        sub
+out:    out
+        pusha
+        inc
+        push 26
+        rem
+        popa
+        jmp loop
+";
+
+/// Caesar decrypter run by the `enaa` CLI.
+///
+/// Each lowercase letter read is shifted forward in the alphabet by a key
+/// that starts at 4 and is incremented modulo 26 after each character.  The
+/// program exits on end of input.
+///
+/// Other characters are shifted by the key too and moved back 26 places
+/// when they end up past `z`, so that only the key wrap uses `Rem`: wrapping
+/// letters with `Rem` would also reduce every other character into the
+/// lowercase range and change the output.
+pub fn decrypter() -> Vec<Insn> {
+    vec![
+        Insn::new(Opcode::Push).set_value(4),
+        Insn::new(Opcode::Popa),
+        Insn::new(Opcode::In).set_label("loop"),
+        Insn::new(Opcode::Dup),
+        Insn::new(Opcode::Bne).set_target("decode"),
+        Insn::new(Opcode::Exit),
+        Insn::new(Opcode::Pusha).set_label("decode"),
+        Insn::new(Opcode::Add),
+        Insn::new(Opcode::Dup),
+        Insn::new(Opcode::Push).set_value('z' as u32),
+        Insn::new(Opcode::Ble).set_target("out"),
+        Insn::new(Opcode::Push).set_value(26),
+        Insn::new(Opcode::Sub),
+        Insn::new(Opcode::Out).set_label("out"),
+        Insn::new(Opcode::Pusha),
+        Insn::new(Opcode::Inc),
+        Insn::new(Opcode::Push).set_value(26),
+        Insn::new(Opcode::Rem),
+        Insn::new(Opcode::Popa),
+        Insn::new(Opcode::Jmp).set_target("loop"),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::vm::run;
+
+    /// Decrypter as written before `Rem` existed, wrapping the key with
+    /// comparisons and branches.
+    fn branching_decrypter() -> Vec<Insn> {
+        vec![
+            Insn::new(Opcode::Push).set_value(4),
+            Insn::new(Opcode::Popa),
+            Insn::new(Opcode::In).set_label("loop"),
+            Insn::new(Opcode::Dup),
+            Insn::new(Opcode::Bne).set_target("decode"),
+            Insn::new(Opcode::Exit),
+            Insn::new(Opcode::Pusha).set_label("decode"),
+            Insn::new(Opcode::Add),
+            Insn::new(Opcode::Dup),
+            Insn::new(Opcode::Push).set_value('z' as u32),
+            Insn::new(Opcode::Ble).set_target("out"),
+            Insn::new(Opcode::Push).set_value(26),
+            Insn::new(Opcode::Sub),
+            Insn::new(Opcode::Out).set_label("out"),
+            Insn::new(Opcode::Pusha),
+            Insn::new(Opcode::Inc),
+            Insn::new(Opcode::Dup),
+            Insn::new(Opcode::Push).set_value(25),
+            Insn::new(Opcode::Bgt).set_target("wrap"),
+            Insn::new(Opcode::Popa),
+            Insn::new(Opcode::Jmp).set_target("loop"),
+            Insn::new(Opcode::Push).set_value(0).set_label("wrap"),
+            Insn::new(Opcode::Popa),
+            Insn::new(Opcode::Jmp).set_target("loop"),
+        ]
+    }
+
+    #[test]
+    fn rem_decrypter_matches_branching_decrypter() {
+        let new = assemble(&decrypter()).unwrap();
+        let old = assemble(&branching_decrypter()).unwrap();
+        let run = |program: &[u8], input: &str| run(program, input).map_err(|e| e.to_string());
+        let all_bytes: String = (1..=255u8).map(char::from).collect();
+        let alphabet = "abcdefghijklmnopqrstuvwxyz";
+        for input in [
+            "",
+            alphabet,
+            &alphabet.repeat(3),
+            "thequickbrownfoxjumpsoverthelazydog",
+            "Hello, world\n",
+            "é€\u{10ffff}",
+            &all_bytes,
+            &all_bytes.repeat(2),
+        ] {
+            assert_eq!(run(&new, input), run(&old, input), "{:?}", input);
+        }
+        for byte in 0..=255u8 {
+            let input = char::from(byte).to_string();
+            assert_eq!(run(&new, &input), run(&old, &input), "{}", byte);
+        }
+        assert_eq!(run(&new, "Hello, world\n").unwrap(), "Ljrsw5*haezs\u{1a}");
+    }
+
+    #[test]
+    fn rem_decrypter_is_shorter() {
+        let new = assemble(&decrypter()).unwrap();
+        let old = assemble(&branching_decrypter()).unwrap();
+        assert!(new.len() < old.len(), "{} >= {}", new.len(), old.len());
+    }
+
+    /// `branching_decrypter()` with the key reset spelled as the two-byte
+    /// `pushi 0` rather than `push 0`.
+    fn branching_decrypter_without_push0() -> Vec<Insn> {
+        let mut source = branching_decrypter();
+        let wrap = source.len() - 3;
+        source[wrap] = Insn::new(Opcode::Pushi).set_value(0).set_label("wrap");
+        source
+    }
+
+    #[test]
+    fn push0_shortens_branching_decrypter() {
+        let with = assemble(&branching_decrypter()).unwrap();
+        let without = assemble(&branching_decrypter_without_push0()).unwrap();
+        assert!(with.contains(&(Opcode::Push0 as u8)));
+        assert_eq!(with.len() + 1, without.len());
+        for input in ["", "abc", "xyzabc", "thequickbrownfoxjumpsoverthelazydog"] {
+            assert_eq!(run(&with, input).unwrap(), run(&without, input).unwrap());
+        }
+    }
+
+    #[test]
+    fn two_byte_push_of_0_and_1_still_decodes() {
+        let program = [
+            Opcode::Push as u8,
+            0,
+            Opcode::Push as u8,
+            1,
+            Opcode::Push0 as u8,
+            Opcode::Push1 as u8,
+            Opcode::Exit as u8,
+        ];
+        let mut vm = crate::vm::VmBuilder::new(&program).build();
+        vm.run().unwrap();
+        assert_eq!(vm.state().stack, [0, 1, 0, 1]);
+    }
+}
@@ -0,0 +1,115 @@
+//! Static bytecode validation
+
+use std::fmt;
+
+use crate::analysis::{self, Decoded};
+use crate::vm::*;
+
+/// Problem found by [`validate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// Byte at `offset` does not encode any opcode.
+    ///
+    /// Extension opcodes are reported too as their handlers are only known
+    /// at run time.
+    InvalidOpcode { offset: usize, byte: u8 },
+
+    /// Opcode at `offset` needs an operand but the program ends before it.
+    MissingOperand { offset: usize, opcode: Opcode },
+
+    /// Branch at `offset` jumps to `target` which is not the start of an
+    /// instruction.
+    InvalidTarget { offset: usize, target: usize },
+
+    /// No `Exit` instruction can be reached from offset 0.
+    NoReachableExit,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::InvalidOpcode { offset, byte } => {
+                write!(f, "invalid opcode {} at offset {}", byte, offset)
+            }
+            ValidationError::MissingOperand { offset, opcode } => write!(
+                f,
+                "missing operand for {} at offset {}",
+                opcode.mnemonic().to_uppercase(),
+                offset
+            ),
+            ValidationError::InvalidTarget { offset, target } => {
+                write!(
+                    f,
+                    "branch at offset {} to invalid target {}",
+                    offset, target
+                )
+            }
+            ValidationError::NoReachableExit => write!(f, "no reachable exit"),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Statically check that bytecode is well-formed.
+///
+/// All problems found are returned rather than only the first one.
+pub fn validate(bytecode: &[u8]) -> Result<(), Vec<ValidationError>> {
+    let mut errors = Vec::new();
+
+    // Decode what can be, skipping invalid bytes.
+    let mut decoded = Vec::new();
+    let mut offset = 0;
+    while offset < bytecode.len() {
+        let byte = bytecode[offset];
+        let Ok(opcode) = Opcode::try_from(byte) else {
+            errors.push(ValidationError::InvalidOpcode { offset, byte });
+            offset += 1;
+            continue;
+        };
+        let operand_len = opcode.operand_len();
+        let operand = match operand_len {
+            0 => None,
+            _ => match bytecode.get(offset + 1..offset + 1 + operand_len) {
+                Some(bytes) => Some(bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u32)),
+                None => {
+                    errors.push(ValidationError::MissingOperand { offset, opcode });
+                    break;
+                }
+            },
+        };
+        let insn = Decoded {
+            offset,
+            opcode,
+            operand,
+        };
+        offset = insn.next();
+        decoded.push(insn);
+    }
+
+    for insn in &decoded {
+        if let Some(target) = insn.target() {
+            if decoded.binary_search_by_key(&target, |d| d.offset).is_err() {
+                errors.push(ValidationError::InvalidTarget {
+                    offset: insn.offset,
+                    target,
+                });
+            }
+        }
+    }
+
+    let live = analysis::reachable(&decoded);
+    let exits = decoded
+        .iter()
+        .zip(live)
+        .any(|(insn, live)| live && insn.opcode == Opcode::Exit);
+    if !exits {
+        errors.push(ValidationError::NoReachableExit);
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
@@ -1,14 +1,39 @@
 //! Virtual machine
 
-use anyhow::{anyhow, Context};
+#[cfg(feature = "alloc")]
+use alloc::{
+    borrow::Cow,
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::fmt::{self, Write as _};
+use core::ops::{BitOr, RangeInclusive};
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, Write};
+
+use crate::io::{OutputError, VmInput, VmOutput};
 
 /// All supported bytecodes.
 ///
 /// Some bytecodes have an operand which is the unsigned byte following the
 /// opcode in the code segment.  An operand is either a (conditional) jump
-/// absolute address (offset in bytecode sequence) or an immediate integer.
+/// absolute address (offset in bytecode sequence), an immediate integer or a
+/// register index.
+/// Wide variants of these bytecodes take a 16-bit little-endian operand
+/// stored in the two following bytes instead, and `Push32` a 32-bit one.
+/// Relative variants of branches take a signed byte offset from the next
+/// instruction instead of an absolute address.
+///
+/// Debugging opcodes are only available with the `debug-opcodes` feature.
+/// Without it, their bytes are invalid opcodes and referring to them does not
+/// compile.
 #[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Opcode {
     /// Push on stack ASCII code of next character in input buffer or push 0 on
     /// end of input.
@@ -29,12 +54,14 @@ pub enum Opcode {
     /// [... X] --> [... X X]
     Dup = 2,
 
-    /// Pop two topmost stack elements and push back their sum.
+    /// Pop two topmost stack elements and push back their sum, wrapping
+    /// around on overflow.
     ///
     /// [... X Y] --> [... X+Y]
     Add = 3,
 
-    /// Pop two topmost stack elements and push back their difference.
+    /// Pop two topmost stack elements and push back their difference,
+    /// wrapping around on underflow.
     ///
     /// [... X Y] --> [... X-Y]
     Sub = 4,
@@ -91,10 +118,361 @@ pub enum Opcode {
     ///
     /// [... X Y] --> [...]
     Ble = 14,
+
+    /// Print registers and stack to stderr for debugging purposes.
+    ///
+    /// [...] --> [...]
+    #[cfg(feature = "debug-opcodes")]
+    Regs = 15,
+
+    /// Pop topmost stack element and fail if it is zero.
+    ///
+    /// This is meant for checking invariants while debugging a program and
+    /// must be removed before using the program in production.
+    ///
+    /// [... X] --> [...]
+    #[cfg(feature = "debug-opcodes")]
+    Assert = 16,
+
+    /// Record marker holding byte following this opcode in the execution
+    /// trace, if tracing is enabled.  Do nothing otherwise.
+    ///
+    /// [...] --> [...]
+    Trace = 17,
+
+    /// Pop two topmost stack elements, consider they are ASCII codes and copy
+    /// them into the output buffer, second topmost first.
+    ///
+    /// [... X Y] --> [...]
+    /// X Y --> OUT
+    Out2 = 18,
+
+    /// Pop three topmost stack elements and push back the second one if the
+    /// third one is non-zero, the first one otherwise.
+    ///
+    /// [... C X Y] --> [... X] if C != 0
+    /// [... C X Y] --> [... Y] if C == 0
+    Select = 19,
+
+    /// Same as `Push` with a 16-bit operand.
+    Pushw = 20,
+
+    /// Same as `Jmp` with a 16-bit operand.
+    Jmpw = 21,
+
+    /// Same as `Bne` with a 16-bit operand.
+    Bnew = 22,
+
+    /// Same as `Beq` with a 16-bit operand.
+    Beqw = 23,
+
+    /// Same as `Blt` with a 16-bit operand.
+    Bltw = 24,
+
+    /// Same as `Bgt` with a 16-bit operand.
+    Bgtw = 25,
+
+    /// Same as `Ble` with a 16-bit operand.
+    Blew = 26,
+
+    /// Pop two topmost stack elements and push back their product, wrapping
+    /// around on overflow.
+    ///
+    /// [... X Y] --> [... X*Y]
+    Mul = 27,
+
+    /// Pop two topmost stack elements and push back the quotient of their
+    /// unsigned division.  Fail if the divisor is zero.
+    ///
+    /// [... X Y] --> [... X/Y]
+    Div = 28,
+
+    /// Pop two topmost stack elements and push back the remainder of their
+    /// unsigned division.  Fail if the divisor is zero.
+    ///
+    /// [... X Y] --> [... X%Y]
+    Rem = 29,
+
+    /// Pop two topmost stack elements and push back their bitwise and.
+    ///
+    /// [... X Y] --> [... X&Y]
+    And = 30,
+
+    /// Pop two topmost stack elements and push back their bitwise or.
+    ///
+    /// [... X Y] --> [... X|Y]
+    Or = 31,
+
+    /// Pop two topmost stack elements and push back their bitwise exclusive
+    /// or.
+    ///
+    /// [... X Y] --> [... X^Y]
+    Xor = 32,
+
+    /// Pop topmost stack element and push back its bitwise complement.
+    ///
+    /// [... X] --> [... !X]
+    Not = 33,
+
+    /// Pop topmost stack element and push back its two's-complement negation.
+    ///
+    /// [... X] --> [... -X]
+    Neg = 34,
+
+    /// Pop shift amount and value and push back value shifted left, or zero
+    /// if the amount is 32 or more.
+    ///
+    /// [... X N] --> [... X<<N]
+    Shl = 35,
+
+    /// Pop shift amount and value and push back value logically shifted
+    /// right, or zero if the amount is 32 or more.
+    ///
+    /// [... X N] --> [... X>>N]
+    Shr = 36,
+
+    /// Pop shift amount and value and push back value arithmetically shifted
+    /// right, filling vacated bits with the sign bit.  Amounts of 32 or more
+    /// behave like 31.
+    ///
+    /// [... X N] --> [... X>>N]
+    Sar = 37,
+
+    /// Exchange two topmost stack elements.
+    ///
+    /// [... X Y] --> [... Y X]
+    Swap = 38,
+
+    /// Pop topmost stack element and discard it.
+    ///
+    /// [... X] --> [...]
+    Drop = 39,
+
+    /// Push copy of second topmost stack element.
+    ///
+    /// [... X Y] --> [... X Y X]
+    Over = 40,
+
+    /// Do nothing.
+    ///
+    /// [...] --> [...]
+    Nop = 41,
+
+    /// Push address of next instruction onto return stack and jump to
+    /// absolute address stored in byte following this opcode.
+    ///
+    /// [...] --> [...]
+    Call = 42,
+
+    /// Pop address from return stack and jump to it.
+    ///
+    /// [...] --> [...]
+    Ret = 43,
+
+    /// Same as `Call` with a 16-bit operand.
+    Callw = 44,
+
+    /// Push content of register whose index is stored in byte following this
+    /// opcode onto stack.  `Pusha` is the same as `Pushr 0`.
+    ///
+    /// [...] --> [... REG[N]]
+    Pushr = 45,
+
+    /// Pop stack topmost element into register whose index is stored in byte
+    /// following this opcode.  `Popa` is the same as `Popr 0`.
+    ///
+    /// [... X] --> [...]
+    /// X --> REG[N]
+    Popr = 46,
+
+    /// Pop address and push back content of heap cell at this address.
+    ///
+    /// [... A] --> [... HEAP[A]]
+    Load = 47,
+
+    /// Pop value and address and write value into heap cell at this address.
+    ///
+    /// [... A X] --> [...]
+    /// X --> HEAP[A]
+    Store = 48,
+
+    /// Push next byte of binary input or push 0 on end of binary input.
+    ///
+    /// BIN -> X
+    /// [...] --> [... X]
+    InByte = 49,
+
+    /// Pop topmost stack element, consider it is a byte and copy it into the
+    /// binary output buffer.
+    ///
+    /// [... X] --> [...]
+    /// X --> BOUT
+    OutByte = 50,
+
+    /// Same as `Push` with a 32-bit operand.
+    Push32 = 51,
+
+    /// Same as `Blt` but compare elements as two's-complement signed
+    /// integers.
+    ///
+    /// [... X Y] --> [...]
+    Blts = 52,
+
+    /// Same as `Bgt` but compare elements as two's-complement signed
+    /// integers.
+    ///
+    /// [... X Y] --> [...]
+    Bgts = 53,
+
+    /// Same as `Ble` but compare elements as two's-complement signed
+    /// integers.
+    ///
+    /// [... X Y] --> [...]
+    Bles = 54,
+
+    /// Pop two topmost stack elements and jump if second topmost is greater
+    /// than or equal to first one, comparing them as two's-complement signed
+    /// integers.
+    ///
+    /// [... X Y] --> [...]
+    Bges = 55,
+
+    /// Same as `Jmp` with a signed 8-bit offset relative to the next
+    /// instruction instead of an absolute address.
+    JmpRel = 56,
+
+    /// Same as `Bne` with a signed 8-bit offset relative to the next
+    /// instruction instead of an absolute address.
+    BneRel = 57,
+
+    /// Same as `Beq` with a signed 8-bit offset relative to the next
+    /// instruction instead of an absolute address.
+    BeqRel = 58,
+
+    /// Same as `Blt` with a signed 8-bit offset relative to the next
+    /// instruction instead of an absolute address.
+    BltRel = 59,
+
+    /// Same as `Bgt` with a signed 8-bit offset relative to the next
+    /// instruction instead of an absolute address.
+    BgtRel = 60,
+
+    /// Same as `Ble` with a signed 8-bit offset relative to the next
+    /// instruction instead of an absolute address.
+    BleRel = 61,
+
+    /// Same as `Blts` with a signed 8-bit offset relative to the next
+    /// instruction instead of an absolute address.
+    BltsRel = 62,
+
+    /// Same as `Bgts` with a signed 8-bit offset relative to the next
+    /// instruction instead of an absolute address.
+    BgtsRel = 63,
+
+    /// Same as `Bles` with a signed 8-bit offset relative to the next
+    /// instruction instead of an absolute address.
+    BlesRel = 64,
+
+    /// Same as `Bges` with a signed 8-bit offset relative to the next
+    /// instruction instead of an absolute address.
+    BgesRel = 65,
+
+    /// Duplicate two topmost stack elements.
+    ///
+    /// [... X Y] --> [... X Y X Y]
+    Dup2 = 66,
+
+    /// Same as `In` but read from input channel whose index is stored in
+    /// byte following this opcode.  Channel 0 is the input of `In`.
+    ///
+    /// IN[N] -> X
+    /// [...] --> [... X]
+    InN = 67,
+
+    /// Same as `Out` but write to output channel whose index is stored in
+    /// byte following this opcode.  Channel 0 is the output of `Out`.
+    ///
+    /// [... X] --> [...]
+    /// X --> OUT[N]
+    OutN = 68,
+
+    /// Same as `Push 0` in a single byte.
+    ///
+    /// [...] --> [... 0]
+    Push0 = 69,
+
+    /// Same as `Push 1` in a single byte.
+    ///
+    /// [...] --> [... 1]
+    Push1 = 70,
+
+    /// Assembler pseudo-opcode replaced by the body of the macro named by its
+    /// target operand.  It never appears in bytecode.
+    MacroCall = 71,
+
+    /// Pop topmost stack element and jump if zero.
+    ///
+    /// [... X] --> [...]
+    Beqz = 72,
+
+    /// Same as `Beqz` with a 16-bit operand.
+    Beqzw = 73,
+
+    /// Same as `Beqz` with a signed 8-bit offset relative to the next
+    /// instruction instead of an absolute address.
+    BeqzRel = 74,
+
+    /// Pop byte and address and write byte into code segment at this address.
+    /// Fail if the address is outside the program or the value does not fit
+    /// in a byte.
+    ///
+    /// The program is copied on first patch and the original left intact.
+    ///
+    /// [... A X] --> [...]
+    /// X --> CODE[A]
+    Patch = 75,
+
+    /// Add 1 to topmost stack element, wrapping around on overflow.
+    ///
+    /// [... X] --> [... X+1]
+    Inc = 76,
+
+    /// Subtract 1 from topmost stack element, wrapping around on underflow.
+    ///
+    /// [... X] --> [... X-1]
+    Dec = 77,
+
+    /// Push operand sign-extended from 8 to 32 bits.
+    ///
+    /// [...] --> [... sext(N)]
+    Pushi = 78,
+
+    /// Write topmost stack element to output as a character without popping
+    /// it.
+    ///
+    /// [... X] --> [... X]
+    OutPeek = 79,
+
+    /// Same as `In` but push operand instead of 0 on end of input.
+    ///
+    /// [...] --> [... X]
+    InOr = 80,
+
+    /// Rotate three topmost stack elements, moving the third one to the top.
+    ///
+    /// [... X Y Z] --> [... Y Z X]
+    Rot = 81,
+
+    /// Linker placeholder for a call to a subroutine in another segment.
+    /// Its operand holds the index of this segment and the offset of the
+    /// subroutine in it.  It never appears in linked bytecode.
+    ///
+    /// See [`crate::asm::link`].
+    CallExtern = 255,
 }
 
 impl TryFrom<u8> for Opcode {
-    type Error = anyhow::Error;
+    type Error = VmError;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
@@ -113,137 +491,4539 @@ impl TryFrom<u8> for Opcode {
             12 => Ok(Opcode::Popa),
             13 => Ok(Opcode::Bgt),
             14 => Ok(Opcode::Ble),
-            _ => Err(anyhow!("invalid opcode {}", value)),
+            #[cfg(feature = "debug-opcodes")]
+            15 => Ok(Opcode::Regs),
+            #[cfg(feature = "debug-opcodes")]
+            16 => Ok(Opcode::Assert),
+            17 => Ok(Opcode::Trace),
+            18 => Ok(Opcode::Out2),
+            19 => Ok(Opcode::Select),
+            20 => Ok(Opcode::Pushw),
+            21 => Ok(Opcode::Jmpw),
+            22 => Ok(Opcode::Bnew),
+            23 => Ok(Opcode::Beqw),
+            24 => Ok(Opcode::Bltw),
+            25 => Ok(Opcode::Bgtw),
+            26 => Ok(Opcode::Blew),
+            27 => Ok(Opcode::Mul),
+            28 => Ok(Opcode::Div),
+            29 => Ok(Opcode::Rem),
+            30 => Ok(Opcode::And),
+            31 => Ok(Opcode::Or),
+            32 => Ok(Opcode::Xor),
+            33 => Ok(Opcode::Not),
+            34 => Ok(Opcode::Neg),
+            35 => Ok(Opcode::Shl),
+            36 => Ok(Opcode::Shr),
+            37 => Ok(Opcode::Sar),
+            38 => Ok(Opcode::Swap),
+            39 => Ok(Opcode::Drop),
+            40 => Ok(Opcode::Over),
+            41 => Ok(Opcode::Nop),
+            42 => Ok(Opcode::Call),
+            43 => Ok(Opcode::Ret),
+            44 => Ok(Opcode::Callw),
+            45 => Ok(Opcode::Pushr),
+            46 => Ok(Opcode::Popr),
+            47 => Ok(Opcode::Load),
+            48 => Ok(Opcode::Store),
+            49 => Ok(Opcode::InByte),
+            50 => Ok(Opcode::OutByte),
+            51 => Ok(Opcode::Push32),
+            52 => Ok(Opcode::Blts),
+            53 => Ok(Opcode::Bgts),
+            54 => Ok(Opcode::Bles),
+            55 => Ok(Opcode::Bges),
+            56 => Ok(Opcode::JmpRel),
+            57 => Ok(Opcode::BneRel),
+            58 => Ok(Opcode::BeqRel),
+            59 => Ok(Opcode::BltRel),
+            60 => Ok(Opcode::BgtRel),
+            61 => Ok(Opcode::BleRel),
+            62 => Ok(Opcode::BltsRel),
+            63 => Ok(Opcode::BgtsRel),
+            64 => Ok(Opcode::BlesRel),
+            65 => Ok(Opcode::BgesRel),
+            66 => Ok(Opcode::Dup2),
+            67 => Ok(Opcode::InN),
+            68 => Ok(Opcode::OutN),
+            69 => Ok(Opcode::Push0),
+            70 => Ok(Opcode::Push1),
+            72 => Ok(Opcode::Beqz),
+            73 => Ok(Opcode::Beqzw),
+            74 => Ok(Opcode::BeqzRel),
+            75 => Ok(Opcode::Patch),
+            76 => Ok(Opcode::Inc),
+            77 => Ok(Opcode::Dec),
+            78 => Ok(Opcode::Pushi),
+            79 => Ok(Opcode::OutPeek),
+            80 => Ok(Opcode::InOr),
+            81 => Ok(Opcode::Rot),
+            255 => Ok(Opcode::CallExtern),
+            _ => Err(VmError::InvalidOpcode(value)),
         }
     }
 }
 
-/// Virtual machine state.
-///
-/// The VM is a stack machine that manipulates 32-bit unsigned integers.
-///
-/// The VM has:
-/// - a code segment storing bytecodes to execute;
-/// - a data stack used for computation and temporary storage;
-/// - an auxiliary register;
-/// - an input buffer containing a sequence of ASCII characters;
-/// - an output buffer containing a sequence of ASCII characters;
-/// - a program counter register indexing into the code segment.
-struct Vm<'a> {
-    program: &'a [u8],
-    input_chars: std::str::Chars<'a>,
-    output: String,
-    pc: usize,
-    stack: Vec<u32>,
-    aux: u32,
-}
+impl Opcode {
+    /// Alias of `Bne` named after its comparison with zero, like `Beqz`.
+    #[allow(non_upper_case_globals)]
+    pub const Bnez: Opcode = Opcode::Bne;
 
-impl<'a> Vm<'a> {
-    /// Initialize VM.
-    fn new(program: &'a [u8], input: &'a str) -> Vm<'a> {
-        Vm {
-            program,
-            input_chars: input.chars(),
-            output: String::new(),
-            pc: 0,
-            stack: Vec::with_capacity(16),
-            aux: 0,
+    /// Iterate over all opcodes in bytecode order.
+    pub fn all() -> impl Iterator<Item = Opcode> {
+        (0..=u8::MAX).filter_map(|b| Opcode::try_from(b).ok())
+    }
+
+    /// Canonical lowercase assembly mnemonic.
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Opcode::In => "in",
+            Opcode::Out => "out",
+            Opcode::Dup => "dup",
+            Opcode::Add => "add",
+            Opcode::Sub => "sub",
+            Opcode::Bne => "bne",
+            Opcode::Blt => "blt",
+            Opcode::Exit => "exit",
+            Opcode::Push => "push",
+            Opcode::Jmp => "jmp",
+            Opcode::Beq => "beq",
+            Opcode::Pusha => "pusha",
+            Opcode::Popa => "popa",
+            Opcode::Bgt => "bgt",
+            Opcode::Ble => "ble",
+            #[cfg(feature = "debug-opcodes")]
+            Opcode::Regs => "regs",
+            #[cfg(feature = "debug-opcodes")]
+            Opcode::Assert => "assert",
+            Opcode::Trace => "trace",
+            Opcode::Out2 => "out2",
+            Opcode::Select => "select",
+            Opcode::Pushw => "pushw",
+            Opcode::Jmpw => "jmpw",
+            Opcode::Bnew => "bnew",
+            Opcode::Beqw => "beqw",
+            Opcode::Bltw => "bltw",
+            Opcode::Bgtw => "bgtw",
+            Opcode::Blew => "blew",
+            Opcode::Mul => "mul",
+            Opcode::Div => "div",
+            Opcode::Rem => "rem",
+            Opcode::And => "and",
+            Opcode::Or => "or",
+            Opcode::Xor => "xor",
+            Opcode::Not => "not",
+            Opcode::Neg => "neg",
+            Opcode::Shl => "shl",
+            Opcode::Shr => "shr",
+            Opcode::Sar => "sar",
+            Opcode::Swap => "swap",
+            Opcode::Drop => "drop",
+            Opcode::Over => "over",
+            Opcode::Nop => "nop",
+            Opcode::Call => "call",
+            Opcode::Ret => "ret",
+            Opcode::Callw => "callw",
+            Opcode::Pushr => "pushr",
+            Opcode::Popr => "popr",
+            Opcode::Load => "load",
+            Opcode::Store => "store",
+            Opcode::InByte => "inbyte",
+            Opcode::OutByte => "outbyte",
+            Opcode::Push32 => "push32",
+            Opcode::Blts => "blts",
+            Opcode::Bgts => "bgts",
+            Opcode::Bles => "bles",
+            Opcode::Bges => "bges",
+            Opcode::JmpRel => "jmprel",
+            Opcode::BneRel => "bnerel",
+            Opcode::BeqRel => "beqrel",
+            Opcode::BltRel => "bltrel",
+            Opcode::BgtRel => "bgtrel",
+            Opcode::BleRel => "blerel",
+            Opcode::BltsRel => "bltsrel",
+            Opcode::BgtsRel => "bgtsrel",
+            Opcode::BlesRel => "blesrel",
+            Opcode::BgesRel => "bgesrel",
+            Opcode::Dup2 => "dup2",
+            Opcode::InN => "inn",
+            Opcode::OutN => "outn",
+            Opcode::Push0 => "push0",
+            Opcode::Push1 => "push1",
+            Opcode::MacroCall => "macrocall",
+            Opcode::Beqz => "beqz",
+            Opcode::Beqzw => "beqzw",
+            Opcode::BeqzRel => "beqzrel",
+            Opcode::Patch => "patch",
+            Opcode::Inc => "inc",
+            Opcode::Dec => "dec",
+            Opcode::Pushi => "pushi",
+            Opcode::OutPeek => "outpeek",
+            Opcode::InOr => "inor",
+            Opcode::Rot => "rot",
+            Opcode::CallExtern => "callextern",
         }
     }
 
-    /// Interpret VM.
-    fn run(&mut self) -> anyhow::Result<String> {
-        loop {
-            let opcode = self.program[self.pc];
-            match Opcode::try_from(opcode)? {
-                Opcode::Exit => break,
-                Opcode::In => {
-                    let i = self.input_chars.next().map_or(0, |ch| ch as u32);
-                    self.push(i);
-                    self.pc += 1;
-                }
-                Opcode::Out => {
-                    let ch = char::from_u32(self.pop()?).context("converting code point")?;
-                    self.output.push(ch);
-                    self.pc += 1;
-                }
-                Opcode::Jmp => {
-                    self.pc = self.program[self.pc + 1] as usize;
-                }
-                Opcode::Dup => {
-                    self.push(*self.stack.last().context("duplicating stack")?);
-                    self.pc += 1;
-                }
-                Opcode::Bne => {
-                    let top = self.pop()?;
-                    if top != 0 {
-                        self.pc = self.program[self.pc + 1] as usize;
-                    } else {
-                        self.pc += 2;
-                    }
-                }
-                Opcode::Bgt => {
-                    self.branch_if(|l, r| l > r)?;
-                }
-                Opcode::Blt => {
-                    self.branch_if(|l, r| l < r)?;
-                }
-                Opcode::Ble => {
-                    self.branch_if(|l, r| l <= r)?;
-                }
-                Opcode::Pusha => {
-                    self.push(self.aux);
-                    self.pc += 1;
-                }
-                Opcode::Push => {
-                    self.push(self.program[self.pc + 1] as u32);
-                    self.pc += 2;
-                }
-                Opcode::Popa => {
-                    self.aux = self.pop()?;
-                    self.pc += 1;
-                }
-                Opcode::Add => {
-                    let rhs = self.pop()?;
-                    let lhs = self.pop()?;
-                    self.push(lhs + rhs);
-                    self.pc += 1;
-                }
-                Opcode::Sub => {
-                    let rhs = self.pop()?;
-                    let lhs = self.pop()?;
-                    self.push(lhs - rhs);
-                    self.pc += 1;
-                }
-                _ => todo!(),
-            }
+    /// Variant of this opcode taking a 16-bit operand, if any.
+    pub fn wide(self) -> Option<Opcode> {
+        match self {
+            Opcode::Push => Some(Opcode::Pushw),
+            Opcode::Jmp => Some(Opcode::Jmpw),
+            Opcode::Bne => Some(Opcode::Bnew),
+            Opcode::Beq => Some(Opcode::Beqw),
+            Opcode::Blt => Some(Opcode::Bltw),
+            Opcode::Bgt => Some(Opcode::Bgtw),
+            Opcode::Ble => Some(Opcode::Blew),
+            Opcode::Call => Some(Opcode::Callw),
+            Opcode::Beqz => Some(Opcode::Beqzw),
+            _ => None,
         }
-        Ok(self.output.clone())
     }
 
-    fn push(&mut self, x: u32) {
-        self.stack.push(x)
+    /// Variant of this opcode with `value` as implicit operand, if any.
+    pub fn implicit(self, value: u32) -> Option<Opcode> {
+        match (self, value) {
+            (Opcode::Push, 0) => Some(Opcode::Push0),
+            (Opcode::Push, 1) => Some(Opcode::Push1),
+            _ => None,
+        }
     }
 
-    fn pop(&mut self) -> anyhow::Result<u32> {
-        self.stack.pop().context("pop")
+    /// Operand implied by this opcode, if any.
+    pub fn implicit_value(self) -> Option<u32> {
+        match self {
+            Opcode::Push0 => Some(0),
+            Opcode::Push1 => Some(1),
+            _ => None,
+        }
     }
 
-    fn branch_if<Cmp: FnOnce(u32, u32) -> bool>(&mut self, cmp: Cmp) -> anyhow::Result<()> {
-        let rhs = self.pop()?;
-        let lhs = self.pop()?;
-        if cmp(lhs, rhs) {
-            self.pc = self.program[self.pc + 1] as usize;
+    /// Variant of this opcode taking a 32-bit operand, if any.
+    pub fn wide32(self) -> Option<Opcode> {
+        match self {
+            Opcode::Push | Opcode::Pushw => Some(Opcode::Push32),
+            _ => None,
+        }
+    }
+
+    /// Variant of this branch taking an offset relative to the next
+    /// instruction, if any.
+    pub fn relative(self) -> Option<Opcode> {
+        match self {
+            Opcode::Jmp => Some(Opcode::JmpRel),
+            Opcode::Bne => Some(Opcode::BneRel),
+            Opcode::Beq => Some(Opcode::BeqRel),
+            Opcode::Blt => Some(Opcode::BltRel),
+            Opcode::Bgt => Some(Opcode::BgtRel),
+            Opcode::Ble => Some(Opcode::BleRel),
+            Opcode::Blts => Some(Opcode::BltsRel),
+            Opcode::Bgts => Some(Opcode::BgtsRel),
+            Opcode::Bles => Some(Opcode::BlesRel),
+            Opcode::Bges => Some(Opcode::BgesRel),
+            Opcode::Beqz => Some(Opcode::BeqzRel),
+            _ => None,
+        }
+    }
+
+    /// Is the operand of this branch an offset relative to the next
+    /// instruction rather than an absolute address?
+    pub fn is_relative(self) -> bool {
+        matches!(
+            self,
+            Opcode::JmpRel
+                | Opcode::BneRel
+                | Opcode::BeqRel
+                | Opcode::BltRel
+                | Opcode::BgtRel
+                | Opcode::BleRel
+                | Opcode::BltsRel
+                | Opcode::BgtsRel
+                | Opcode::BlesRel
+                | Opcode::BgesRel
+                | Opcode::BeqzRel
+        )
+    }
+
+    /// Address this branch located at `pc` jumps to given its `operand`.
+    ///
+    /// Relative offsets reaching below address 0 wrap around to addresses
+    /// past any program.
+    pub fn branch_target(self, pc: usize, operand: u32) -> usize {
+        if self.is_relative() {
+            (pc + 1 + self.operand_len()).wrapping_add_signed(operand as u8 as i8 as isize)
         } else {
-            self.pc += 2;
+            operand as usize
+        }
+    }
+
+    /// Variant of this opcode taking a register index operand, if any.
+    pub fn indexed(self) -> Option<Opcode> {
+        match self {
+            Opcode::Pusha => Some(Opcode::Pushr),
+            Opcode::Popa => Some(Opcode::Popr),
+            _ => None,
+        }
+    }
+
+    /// Number of operand bytes following this opcode in the code segment.
+    pub fn operand_len(self) -> usize {
+        match self {
+            Opcode::Push
+            | Opcode::Pushi
+            | Opcode::Jmp
+            | Opcode::Bne
+            | Opcode::Beq
+            | Opcode::Blt
+            | Opcode::Bgt
+            | Opcode::Ble
+            | Opcode::Blts
+            | Opcode::Bgts
+            | Opcode::Bles
+            | Opcode::Bges
+            | Opcode::JmpRel
+            | Opcode::BneRel
+            | Opcode::BeqRel
+            | Opcode::BltRel
+            | Opcode::BgtRel
+            | Opcode::BleRel
+            | Opcode::BltsRel
+            | Opcode::BgtsRel
+            | Opcode::BlesRel
+            | Opcode::BgesRel
+            | Opcode::Call
+            | Opcode::Pushr
+            | Opcode::Popr
+            | Opcode::InN
+            | Opcode::InOr
+            | Opcode::OutN
+            | Opcode::Beqz
+            | Opcode::BeqzRel
+            | Opcode::Trace => 1,
+            Opcode::Pushw
+            | Opcode::Jmpw
+            | Opcode::Bnew
+            | Opcode::Beqw
+            | Opcode::Bltw
+            | Opcode::Bgtw
+            | Opcode::Blew
+            | Opcode::Beqzw
+            | Opcode::Callw
+            | Opcode::CallExtern => 2,
+            Opcode::Push32 => 4,
+            _ => 0,
+        }
+    }
+
+    /// Semantic properties of this opcode.
+    pub fn attributes(self) -> OpcodeAttributes {
+        use OpcodeAttributes as A;
+        match self {
+            Opcode::In | Opcode::InByte | Opcode::InN | Opcode::InOr => A::READS_INPUT,
+            Opcode::Out | Opcode::Out2 | Opcode::OutByte | Opcode::OutN | Opcode::OutPeek => {
+                A::WRITES_OUTPUT
+            }
+            Opcode::Add | Opcode::Mul | Opcode::And | Opcode::Or | Opcode::Xor => A::IS_COMMUTATIVE,
+            Opcode::Bne
+            | Opcode::Blt
+            | Opcode::Bgt
+            | Opcode::Ble
+            | Opcode::Bnew
+            | Opcode::Bltw
+            | Opcode::Bgtw
+            | Opcode::Blew
+            | Opcode::Blts
+            | Opcode::Bgts
+            | Opcode::Bles
+            | Opcode::Bges
+            | Opcode::BneRel
+            | Opcode::BltRel
+            | Opcode::BgtRel
+            | Opcode::BleRel
+            | Opcode::BltsRel
+            | Opcode::BgtsRel
+            | Opcode::BlesRel
+            | Opcode::BgesRel
+            | Opcode::Beqz
+            | Opcode::Beqzw
+            | Opcode::BeqzRel
+            | Opcode::Call
+            | Opcode::Callw => A::IS_BRANCH,
+            Opcode::Beq | Opcode::Beqw | Opcode::BeqRel => A::IS_BRANCH | A::IS_COMMUTATIVE,
+            Opcode::Jmp | Opcode::Jmpw | Opcode::JmpRel => A::IS_BRANCH | A::IS_TERMINATOR,
+            Opcode::Exit | Opcode::Ret => A::IS_TERMINATOR,
+            Opcode::Popa | Opcode::Popr => A::MODIFIES_AUX,
+            Opcode::Dup
+            | Opcode::Dup2
+            | Opcode::Sub
+            | Opcode::Push
+            | Opcode::Pushw
+            | Opcode::Push32
+            | Opcode::Pushi
+            | Opcode::Push0
+            | Opcode::Push1
+            | Opcode::Pusha
+            | Opcode::Pushr
+            | Opcode::Trace
+            | Opcode::Select
+            | Opcode::Div
+            | Opcode::Rem
+            | Opcode::Not
+            | Opcode::Neg
+            | Opcode::Inc
+            | Opcode::Dec
+            | Opcode::Shl
+            | Opcode::Shr
+            | Opcode::Sar
+            | Opcode::Swap
+            | Opcode::Rot
+            | Opcode::Drop
+            | Opcode::Over
+            | Opcode::Nop
+            | Opcode::Load
+            | Opcode::Store
+            | Opcode::Patch
+            | Opcode::MacroCall
+            | Opcode::CallExtern => A::NONE,
+            #[cfg(feature = "debug-opcodes")]
+            Opcode::Regs | Opcode::Assert => A::NONE,
+        }
+    }
+
+    /// Does this opcode (conditionally) jump to the address in its operand?
+    pub fn is_branch(self) -> bool {
+        self.attributes().contains(OpcodeAttributes::IS_BRANCH)
+    }
+
+    /// Does execution never continue with the next instruction?
+    pub fn is_terminator(self) -> bool {
+        self.attributes().contains(OpcodeAttributes::IS_TERMINATOR)
+    }
+
+    /// Does this opcode always jump to the address in its operand?
+    pub fn is_unconditional_branch(self) -> bool {
+        self.attributes()
+            .contains(OpcodeAttributes::IS_BRANCH | OpcodeAttributes::IS_TERMINATOR)
+    }
+
+    /// Does this opcode read input or write output?
+    pub fn is_io(self) -> bool {
+        let attributes = self.attributes();
+        attributes.contains(OpcodeAttributes::READS_INPUT)
+            || attributes.contains(OpcodeAttributes::WRITES_OUTPUT)
+    }
+
+    /// Does this opcode compute a new value from stack elements?
+    ///
+    /// Arithmetic opcodes added later must be listed here too.
+    pub fn is_arithmetic(self) -> bool {
+        matches!(
+            self,
+            Opcode::Add
+                | Opcode::Sub
+                | Opcode::Mul
+                | Opcode::Div
+                | Opcode::Rem
+                | Opcode::And
+                | Opcode::Or
+                | Opcode::Xor
+                | Opcode::Not
+                | Opcode::Neg
+                | Opcode::Inc
+                | Opcode::Dec
+                | Opcode::Shl
+                | Opcode::Shr
+                | Opcode::Sar
+        )
+    }
+
+    /// Number of stack elements popped and pushed when executing this opcode.
+    pub fn stack_effect(self) -> (usize, usize) {
+        match self {
+            Opcode::In
+            | Opcode::InByte
+            | Opcode::InN
+            | Opcode::InOr
+            | Opcode::Push
+            | Opcode::Pushw
+            | Opcode::Push32
+            | Opcode::Pushi
+            | Opcode::Push0
+            | Opcode::Push1
+            | Opcode::Pusha
+            | Opcode::Pushr => (0, 1),
+            Opcode::Out
+            | Opcode::OutByte
+            | Opcode::OutN
+            | Opcode::Bne
+            | Opcode::Bnew
+            | Opcode::BneRel
+            | Opcode::Beqz
+            | Opcode::Beqzw
+            | Opcode::BeqzRel
+            | Opcode::Popa
+            | Opcode::Popr
+            | Opcode::Drop => (1, 0),
+            Opcode::Dup => (1, 2),
+            Opcode::Not
+            | Opcode::Neg
+            | Opcode::Inc
+            | Opcode::Dec
+            | Opcode::Load
+            | Opcode::OutPeek => (1, 1),
+            Opcode::Swap => (2, 2),
+            Opcode::Rot => (3, 3),
+            Opcode::Over => (2, 3),
+            Opcode::Dup2 => (2, 4),
+            Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Div
+            | Opcode::Rem
+            | Opcode::And
+            | Opcode::Or
+            | Opcode::Xor
+            | Opcode::Shl
+            | Opcode::Shr
+            | Opcode::Sar => (2, 1),
+            Opcode::Select => (3, 1),
+            Opcode::Blt
+            | Opcode::Beq
+            | Opcode::Bgt
+            | Opcode::Ble
+            | Opcode::Bltw
+            | Opcode::Beqw
+            | Opcode::Bgtw
+            | Opcode::Blew
+            | Opcode::Blts
+            | Opcode::Bgts
+            | Opcode::Bles
+            | Opcode::Bges
+            | Opcode::BeqRel
+            | Opcode::BltRel
+            | Opcode::BgtRel
+            | Opcode::BleRel
+            | Opcode::BltsRel
+            | Opcode::BgtsRel
+            | Opcode::BlesRel
+            | Opcode::BgesRel
+            | Opcode::Out2
+            | Opcode::Store
+            | Opcode::Patch => (2, 0),
+            Opcode::Exit
+            | Opcode::Jmp
+            | Opcode::Jmpw
+            | Opcode::JmpRel
+            | Opcode::Trace
+            | Opcode::Nop
+            | Opcode::Call
+            | Opcode::Callw
+            | Opcode::Ret
+            | Opcode::MacroCall
+            | Opcode::CallExtern => (0, 0),
+            #[cfg(feature = "debug-opcodes")]
+            Opcode::Regs => (0, 0),
+            #[cfg(feature = "debug-opcodes")]
+            Opcode::Assert => (1, 0),
         }
-        Ok(())
     }
 }
 
-/// Execute specified program on specified input and return generated output.
-pub fn run(program: &[u8], input: &str) -> anyhow::Result<String> {
-    debug_assert!(!program.is_empty());
-    let mut vm = Vm::new(program, input);
-    vm.run()
+/// Formats as the canonical lowercase mnemonic, e.g. `push`.
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.mnemonic())
+    }
+}
+
+/// Set of semantic properties of an opcode, meant for optimizers and code
+/// generators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OpcodeAttributes(u8);
+
+impl OpcodeAttributes {
+    pub const NONE: OpcodeAttributes = OpcodeAttributes(0);
+
+    /// May jump to the address in its operand.
+    pub const IS_BRANCH: OpcodeAttributes = OpcodeAttributes(1 << 0);
+
+    /// Never falls through to the next instruction.
+    pub const IS_TERMINATOR: OpcodeAttributes = OpcodeAttributes(1 << 1);
+
+    /// Gives the same result when its two operands are swapped.
+    pub const IS_COMMUTATIVE: OpcodeAttributes = OpcodeAttributes(1 << 2);
+
+    /// Consumes a character from the input.
+    pub const READS_INPUT: OpcodeAttributes = OpcodeAttributes(1 << 3);
+
+    /// Produces a character on the output.
+    pub const WRITES_OUTPUT: OpcodeAttributes = OpcodeAttributes(1 << 4);
+
+    /// Writes into a register.
+    pub const MODIFIES_AUX: OpcodeAttributes = OpcodeAttributes(1 << 5);
+
+    /// Are all attributes in `other` also in `self`?
+    pub fn contains(self, other: OpcodeAttributes) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for OpcodeAttributes {
+    type Output = OpcodeAttributes;
+
+    fn bitor(self, rhs: OpcodeAttributes) -> OpcodeAttributes {
+        OpcodeAttributes(self.0 | rhs.0)
+    }
+}
+
+/// Error raised while executing a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    /// Byte does not encode any opcode.
+    InvalidOpcode(u8),
+
+    /// Instruction at `at_pc` needs more stack elements than available.
+    StackUnderflow { at_pc: usize },
+
+    /// Instruction at `at_pc` pushes onto a full stack.
+    StackOverflow { at_pc: usize },
+
+    /// Value written to output is not a Unicode scalar value.
+    InvalidCodePoint(u32),
+
+    /// Value written to binary output does not fit in a byte.
+    InvalidByte(u32),
+
+    /// Opcode is valid but not supported by the interpreter yet.
+    Unimplemented(Opcode),
+
+    /// `Assert` instruction at `pc` popped `value`, which is zero.
+    #[cfg(feature = "debug-opcodes")]
+    AssertionFailed { pc: usize, value: u32 },
+
+    /// Opcode at `pc` is the last byte of the program but needs an operand.
+    UnexpectedEndOfProgram { pc: usize, opcode: Opcode },
+
+    /// Program counter `pc` points past the end of a program of
+    /// `program_len` bytes.
+    PcOutOfBounds { pc: usize, program_len: usize },
+
+    /// Jump or call at `at_pc` transfers control to `target` outside the
+    /// program.
+    InvalidJumpTarget { at_pc: usize, target: usize },
+
+    /// Heartbeat callback requested to stop execution.
+    Cancelled,
+
+    /// `Call` instruction at `at_pc` pushes onto a full return stack.
+    ReturnStackOverflow { at_pc: usize },
+
+    /// `Ret` instruction at `at_pc` pops from an empty return stack.
+    ReturnStackUnderflow { at_pc: usize },
+
+    /// Instruction at `at_pc` refers to register `index` which does not exist.
+    InvalidRegister { at_pc: usize, index: u32 },
+
+    /// `Load` or `Store` instruction at `at_pc` accesses `address` outside
+    /// the heap.
+    InvalidAddress { at_pc: usize, address: u32 },
+
+    /// `Patch` instruction at `at_pc` writes at `address` outside the
+    /// program.
+    InvalidCodeAddress { at_pc: usize, address: u32 },
+
+    /// `Div` or `Rem` instruction at `at_pc` popped a zero divisor.
+    DivisionByZero { at_pc: usize },
+
+    /// Program executed `limit` instructions without exiting.
+    StepLimitExceeded { limit: u64 },
+
+    /// Handler of extension opcode failed.
+    #[cfg(feature = "alloc")]
+    Extension { opcode: u8, message: String },
+
+    /// Output channel failed to accept a value written by the instruction
+    /// at `at_pc`.
+    Output { at_pc: usize, error: OutputError },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::InvalidOpcode(value) => write!(f, "invalid opcode {}", value),
+            VmError::StackUnderflow { at_pc } => write!(f, "stack underflow at pc={}", at_pc),
+            VmError::StackOverflow { at_pc } => write!(f, "stack overflow at pc={}", at_pc),
+            VmError::InvalidCodePoint(value) => write!(f, "invalid code point {}", value),
+            VmError::InvalidByte(value) => write!(f, "invalid byte {}", value),
+            VmError::Unimplemented(opcode) => write!(f, "unimplemented opcode {:?}", opcode),
+            #[cfg(feature = "debug-opcodes")]
+            VmError::AssertionFailed { pc, value } => {
+                write!(f, "assertion failed at pc={}: popped {}", pc, value)
+            }
+            VmError::UnexpectedEndOfProgram { pc, opcode } => write!(
+                f,
+                "opcode {} at pc={} requires an operand byte but the program ends at byte {}",
+                Uppercase(opcode.mnemonic()),
+                pc,
+                pc
+            ),
+            VmError::PcOutOfBounds { pc, program_len } => write!(
+                f,
+                "pc={} is out of bounds of program of {} bytes",
+                pc, program_len
+            ),
+            VmError::InvalidJumpTarget { at_pc, target } => {
+                write!(f, "invalid jump target {} at pc={}", target, at_pc)
+            }
+            VmError::Cancelled => write!(f, "execution cancelled"),
+            VmError::ReturnStackOverflow { at_pc } => {
+                write!(f, "return stack overflow at pc={}", at_pc)
+            }
+            VmError::ReturnStackUnderflow { at_pc } => {
+                write!(f, "return stack underflow at pc={}", at_pc)
+            }
+            VmError::InvalidRegister { at_pc, index } => {
+                write!(f, "invalid register {} at pc={}", index, at_pc)
+            }
+            VmError::InvalidAddress { at_pc, address } => {
+                write!(f, "invalid heap address {} at pc={}", address, at_pc)
+            }
+            VmError::InvalidCodeAddress { at_pc, address } => {
+                write!(f, "invalid code address {} at pc={}", address, at_pc)
+            }
+            VmError::DivisionByZero { at_pc } => write!(f, "division by zero at pc={}", at_pc),
+            VmError::StepLimitExceeded { limit } => {
+                write!(f, "step limit of {} instructions exceeded", limit)
+            }
+            #[cfg(feature = "alloc")]
+            VmError::Extension { opcode, message } => {
+                write!(f, "extension opcode {} failed: {}", opcode, message)
+            }
+            VmError::Output { at_pc, error } => {
+                write!(f, "output failed at pc={}: {}", at_pc, error)
+            }
+        }
+    }
+}
+
+impl core::error::Error for VmError {}
+
+/// Display adapter writing ASCII text in upper case.
+struct Uppercase<'s>(&'s str);
+
+impl fmt::Display for Uppercase<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0
+            .chars()
+            .try_for_each(|ch| f.write_char(ch.to_ascii_uppercase()))
+    }
+}
+
+/// Map errors to HTTP status codes for web services running client programs.
+///
+/// Errors caused by malformed programs are client errors.  Errors the program
+/// cannot be blamed for are server errors.
+#[cfg(feature = "web")]
+impl From<VmError> for http::StatusCode {
+    fn from(e: VmError) -> http::StatusCode {
+        match e {
+            VmError::InvalidOpcode(_)
+            | VmError::InvalidCodePoint(_)
+            | VmError::InvalidByte(_)
+            | VmError::UnexpectedEndOfProgram { .. }
+            | VmError::InvalidJumpTarget { .. }
+            | VmError::DivisionByZero { .. }
+            | VmError::InvalidRegister { .. }
+            | VmError::InvalidAddress { .. }
+            | VmError::InvalidCodeAddress { .. }
+            | VmError::ReturnStackOverflow { .. }
+            | VmError::ReturnStackUnderflow { .. }
+            | VmError::StackOverflow { .. } => http::StatusCode::BAD_REQUEST,
+            #[cfg(feature = "debug-opcodes")]
+            VmError::AssertionFailed { .. } => http::StatusCode::BAD_REQUEST,
+            VmError::StackUnderflow { .. }
+            | VmError::PcOutOfBounds { .. }
+            | VmError::Extension { .. }
+            | VmError::Output { .. } => http::StatusCode::INTERNAL_SERVER_ERROR,
+            VmError::Cancelled => http::StatusCode::SERVICE_UNAVAILABLE,
+            VmError::StepLimitExceeded { .. } => http::StatusCode::REQUEST_TIMEOUT,
+            VmError::Unimplemented(_) => http::StatusCode::NOT_IMPLEMENTED,
+        }
+    }
+}
+
+/// Event recorded while tracing program execution.
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// Instruction at address `pc` was executed.  `stack` and `aux` are the
+    /// content of the stack and auxiliary register before execution.
+    Exec {
+        pc: usize,
+        opcode: Opcode,
+        operand: Option<u32>,
+        stack: Vec<u32>,
+        aux: u32,
+    },
+
+    /// `Trace` instruction was executed with this operand.
+    Marker(u8),
+}
+
+/// Effect of executing a single instruction with [`Vm::step`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// Instruction executed without visible effect.
+    Continue,
+
+    /// `Exit` was reached.  Stepping again reports `Halted` again.
+    Halted,
+
+    /// `Out` wrote this character to the output channel.
+    WroteOutput(char),
+
+    /// `Out2` wrote these two characters to the output channel.
+    WroteOutput2(char, char),
+
+    /// `OutByte` wrote this byte to the binary output buffer.
+    WroteByte(u8),
+
+    /// `In` was reached but the input channel has no character ready.  The
+    /// instruction is not executed and stepping again retries it.
+    NeedsInput,
+
+    /// Execution reached a breakpoint set with [`Vm::set_breakpoint`] at this
+    /// address.  The instruction there is not executed yet and stepping again
+    /// executes it.
+    Breakpoint(usize),
+}
+
+/// Execution counters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VmStats {
+    /// Number of instructions executed, including the final `Exit`.
+    pub instructions_executed: u64,
+
+    /// Number of instructions that read from the input, including at end of
+    /// input.
+    pub input_reads: u64,
+
+    /// Number of instructions that wrote to the output.
+    pub output_writes: u64,
+
+    /// Number of characters read from the input, excluding end of input.
+    pub input_chars_read: u64,
+
+    /// Number of characters written to the output, before any escaping.
+    pub output_chars_written: u64,
+
+    /// Largest number of elements the stack held.
+    pub max_stack_depth: usize,
+
+    /// Number of branch instructions executed, whether taken or not.
+    pub branches_executed: u64,
+}
+
+/// Resource limits applied by [`Vm::with_options`].
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmOptions {
+    /// Largest number of elements the stack may hold.
+    pub max_stack_depth: usize,
+
+    /// Largest number of return addresses the return stack may hold.
+    pub max_call_depth: usize,
+
+    /// Number of heap cells, all initially zero.
+    pub heap_size: usize,
+
+    /// Record every executed instruction, retrievable with [`Vm::trace`].
+    pub trace: bool,
+
+    /// Content of input channels 1 and above read by `InN`.  Channel 0 is
+    /// the input channel the VM was created with.
+    pub input_channels: Vec<String>,
+
+    /// Largest number of instructions to execute, if any.
+    ///
+    /// Built in release mode, the interpreter executes in the order of 100
+    /// million instructions per second on a typical desktop CPU, so a limit of
+    /// 100 million stops a runaway program after roughly a second.
+    pub max_steps: Option<u64>,
+
+    /// How characters read by `In` and written by `Out` map to bytes.
+    pub encoding: Encoding,
+}
+
+#[cfg(feature = "alloc")]
+impl Default for VmOptions {
+    fn default() -> Self {
+        VmOptions {
+            max_stack_depth: 256,
+            max_call_depth: 256,
+            heap_size: 256,
+            trace: false,
+            input_channels: Vec::new(),
+            max_steps: None,
+            encoding: Encoding::default(),
+        }
+    }
+}
+
+/// Mapping between the values handled by character I/O instructions and the
+/// bytes of input and output, selected with [`VmOptions::encoding`].
+///
+/// The encoding is applied by [`run_encoded`] when converting input bytes to
+/// characters and output characters back to bytes, and by the VM when
+/// checking values written by `Out` and its variants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// Values are Unicode scalar values, exchanged as UTF-8.
+    #[default]
+    Utf8,
+
+    /// Values are bytes, each one a Latin-1 character.  Writing a value above
+    /// 255 fails with [`VmError::InvalidByte`].
+    Latin1,
+
+    /// Values are bytes of binary data.  Only the low 8 bits of values
+    /// written are kept.
+    RawByte,
+}
+
+/// Number of registers.  Register 0 is the auxiliary register.
+pub const REGISTER_COUNT: usize = 8;
+
+/// Bytes reserved for opcodes defined by embedders with
+/// [`Vm::register_extension`].
+///
+/// 255 is not part of it as it encodes the linker placeholder
+/// [`Opcode::CallExtern`].
+pub const EXTENSION_OPCODES: RangeInclusive<u8> = 240..=254;
+
+/// Handler executing an extension opcode.
+#[cfg(feature = "alloc")]
+pub type ExtensionHandler<'a> = Box<dyn FnMut(&mut VmExtContext) -> anyhow::Result<()> + 'a>;
+
+/// VM state accessible from extension handlers.
+#[cfg(feature = "alloc")]
+pub struct VmExtContext<'v> {
+    stack: Stack<'v>,
+    regs: [u32; REGISTER_COUNT],
+    pc: usize,
+}
+
+#[cfg(feature = "alloc")]
+impl VmExtContext<'_> {
+    pub fn push(&mut self, x: u32) -> Result<(), VmError> {
+        if self.stack.push(x) {
+            Ok(())
+        } else {
+            Err(VmError::StackOverflow { at_pc: self.pc })
+        }
+    }
+
+    pub fn pop(&mut self) -> Result<u32, VmError> {
+        self.stack
+            .pop()
+            .ok_or(VmError::StackUnderflow { at_pc: self.pc })
+    }
+
+    pub fn aux(&self) -> u32 {
+        self.regs[0]
+    }
+
+    pub fn set_aux(&mut self, value: u32) {
+        self.regs[0] = value
+    }
+
+    /// Content of register `index`.
+    ///
+    /// Panics if `index` is not less than [`REGISTER_COUNT`].
+    pub fn reg(&self, index: usize) -> u32 {
+        self.regs[index]
+    }
+
+    /// Panics if `index` is not less than [`REGISTER_COUNT`].
+    pub fn set_reg(&mut self, index: usize, value: u32) {
+        self.regs[index] = value
+    }
+
+    /// Address of the extension opcode being executed.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+}
+
+/// Storage of the data stack.
+#[cfg(feature = "alloc")]
+enum Stack<'a> {
+    /// Growable vector.
+    Heap(Vec<u32>),
+
+    /// Caller-provided buffer whose first `len` elements are in use.
+    Buffer { buf: &'a mut [u32], len: usize },
+}
+
+#[cfg(feature = "alloc")]
+impl Default for Stack<'_> {
+    fn default() -> Self {
+        Stack::Heap(Vec::new())
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl Stack<'_> {
+    /// Push `x` and return whether there was room for it.
+    fn push(&mut self, x: u32) -> bool {
+        match self {
+            Stack::Heap(v) => v.push(x),
+            Stack::Buffer { buf, len } => match buf.get_mut(*len) {
+                Some(slot) => {
+                    *slot = x;
+                    *len += 1;
+                }
+                None => return false,
+            },
+        }
+        true
+    }
+
+    fn pop(&mut self) -> Option<u32> {
+        match self {
+            Stack::Heap(v) => v.pop(),
+            Stack::Buffer { buf, len } => {
+                *len = len.checked_sub(1)?;
+                Some(buf[*len])
+            }
+        }
+    }
+
+    fn as_slice(&self) -> &[u32] {
+        match self {
+            Stack::Heap(v) => v,
+            Stack::Buffer { buf, len } => &buf[..*len],
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    /// Replace content with `values`.
+    ///
+    /// Panics if `values` does not fit in a caller-provided buffer.
+    fn set(&mut self, values: &[u32]) {
+        match self {
+            Stack::Heap(v) => {
+                v.clear();
+                v.extend_from_slice(values);
+            }
+            Stack::Buffer { buf, len } => {
+                buf[..values.len()].copy_from_slice(values);
+                *len = values.len();
+            }
+        }
+    }
+}
+
+/// Execution state saved by [`Vm::snapshot`].
+///
+/// Input is not captured as channels cannot be rewound.  Callers must reset
+/// input separately before restoring.
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmSnapshot {
+    pub pc: usize,
+    pub stack: Vec<u32>,
+    pub return_stack: Vec<usize>,
+    pub regs: [u32; REGISTER_COUNT],
+    pub heap: Vec<u32>,
+
+    /// Number of characters written to the output channel.
+    pub output_len: usize,
+
+    /// Number of bytes written to the binary output buffer.
+    pub byte_output_len: usize,
+}
+
+/// Logical execution state returned by [`Vm::state`] for inspection.
+///
+/// Unlike [`VmSnapshot`], this cannot be restored.
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmState {
+    pub pc: usize,
+    pub stack: Vec<u32>,
+    pub aux: u32,
+
+    /// Output buffered by the output channel, empty if it does not buffer.
+    pub output_so_far: String,
+}
+
+/// Virtual machine state.
+///
+/// The VM is a stack machine that manipulates 32-bit unsigned integers.
+///
+/// The VM has:
+/// - a code segment storing bytecodes to execute;
+/// - a data stack used for computation and temporary storage;
+/// - a return stack holding addresses of instructions following calls;
+/// - eight registers, the first of which is the auxiliary register;
+/// - a heap of cells addressed by index;
+/// - input and output channels carrying sequences of ASCII characters, and
+///   numbered extra channels;
+/// - binary input and output buffers carrying sequences of bytes;
+/// - a program counter register indexing into the code segment.
+///
+/// Bytes in [`EXTENSION_OPCODES`] can be bound to custom handlers.
+///
+/// The VM borrows the program, unless it patches itself, and the stack buffer
+/// if any, for its whole lifetime `'a` and owns its input and output
+/// channels.  Pass `&mut output` to keep access to output after the VM is
+/// dropped.  A VM can be driven to completion
+/// with [`Vm::run`] or one instruction at a time with [`Vm::step`], and both
+/// can be mixed freely.
+#[cfg(feature = "alloc")]
+pub struct Vm<'a, I: VmInput, O: VmOutput> {
+    program: Cow<'a, [u8]>,
+    original_program: &'a [u8],
+    input: I,
+    output: O,
+    pc: usize,
+    stack: Stack<'a>,
+    return_stack: Vec<usize>,
+    regs: [u32; REGISTER_COUNT],
+    heap: Vec<u32>,
+    byte_input: core::slice::Iter<'a, u8>,
+    byte_output: Vec<u8>,
+    channel_inputs: Vec<VecDeque<char>>,
+    channel_outputs: Vec<String>,
+    breakpoints: BTreeSet<usize>,
+    paused_at: Option<usize>,
+    trace: Option<Vec<TraceEvent>>,
+    extensions: BTreeMap<u8, ExtensionHandler<'a>>,
+    escape_output: bool,
+    stats: VmStats,
+    heartbeat: Option<Heartbeat<'a>>,
+    max_stack_depth: Option<usize>,
+    max_call_depth: usize,
+    max_steps: Option<u64>,
+    encoding: Encoding,
+}
+
+#[cfg(feature = "alloc")]
+impl<'a> Vm<'a, &'a str, String> {
+    /// Prepare VM for executing its program again on `new_input`.
+    ///
+    /// Execution state, output, counters and trace are cleared, reusing
+    /// their buffers.  The program is the one the VM was created with, with
+    /// any patch undone.  Configuration is kept, but binary input and extra
+    /// input channels are not rewound.
+    pub fn reset(&mut self, new_input: &'a str) {
+        self.program = Cow::Borrowed(self.original_program);
+        self.input = new_input;
+        self.output.clear_output();
+        self.pc = 0;
+        self.stack.set(&[]);
+        self.return_stack.clear();
+        self.regs = [0; REGISTER_COUNT];
+        self.heap.fill(0);
+        self.byte_output.clear();
+        self.channel_outputs.clear();
+        self.paused_at = None;
+        if let Some(trace) = &mut self.trace {
+            trace.clear();
+        }
+        self.stats = VmStats::default();
+    }
+}
+
+/// Interval in instructions between calls of callback returning whether to
+/// continue execution.
+#[cfg(feature = "alloc")]
+type Heartbeat<'a> = (u64, Box<dyn FnMut() -> bool + 'a>);
+
+#[cfg(feature = "alloc")]
+impl<'a, I: VmInput, O: VmOutput> Vm<'a, I, O> {
+    /// Initialize VM.
+    ///
+    /// The return stack is bounded by the default [`VmOptions::max_call_depth`]
+    /// so that runaway recursion fails with [`VmError::ReturnStackOverflow`].
+    pub fn new(program: &'a [u8], input: I, output: O) -> Vm<'a, I, O> {
+        Vm {
+            program: Cow::Borrowed(program),
+            original_program: program,
+            input,
+            output,
+            pc: 0,
+            stack: Stack::Heap(Vec::with_capacity(16)),
+            return_stack: Vec::new(),
+            regs: [0; REGISTER_COUNT],
+            heap: vec![0; VmOptions::default().heap_size],
+            byte_input: [].iter(),
+            byte_output: Vec::new(),
+            channel_inputs: Vec::new(),
+            channel_outputs: Vec::new(),
+            breakpoints: BTreeSet::new(),
+            paused_at: None,
+            trace: None,
+            extensions: BTreeMap::new(),
+            escape_output: false,
+            stats: VmStats::default(),
+            heartbeat: None,
+            max_stack_depth: None,
+            max_call_depth: VmOptions::default().max_call_depth,
+            max_steps: None,
+            encoding: Encoding::Utf8,
+        }
+    }
+
+    /// Store the stack in `buf` rather than on the heap.
+    ///
+    /// Pushing onto a full buffer then fails with [`VmError::StackOverflow`].
+    pub fn with_stack_buffer(mut self, buf: &'a mut [u32]) -> Vm<'a, I, O> {
+        self.stack = Stack::Buffer { buf, len: 0 };
+        self
+    }
+
+    /// Enforce resource limits in `opts`.
+    ///
+    /// Exceeding the stack depth limit fails with [`VmError::StackOverflow`],
+    /// exceeding the call depth limit with [`VmError::ReturnStackOverflow`]
+    /// and exceeding the step limit with [`VmError::StepLimitExceeded`].
+    pub fn with_options(mut self, opts: &VmOptions) -> Vm<'a, I, O> {
+        self.max_stack_depth = Some(opts.max_stack_depth);
+        self.max_call_depth = opts.max_call_depth;
+        self.heap = vec![0; opts.heap_size];
+        if opts.trace {
+            self = self.with_trace();
+        }
+        self.channel_inputs = opts
+            .input_channels
+            .iter()
+            .map(|input| input.chars().collect())
+            .collect();
+        self.max_steps = opts.max_steps;
+        self.encoding = opts.encoding;
+        self
+    }
+
+    /// Read binary input from `bytes`.
+    pub fn with_byte_input(mut self, bytes: &'a [u8]) -> Vm<'a, I, O> {
+        self.byte_input = bytes.iter();
+        self
+    }
+
+    /// Write non-printable ASCII characters as `\xNN` escape sequences.
+    pub fn with_escaped_output(mut self) -> Vm<'a, I, O> {
+        self.escape_output = true;
+        self
+    }
+
+    /// Call `callback` every `interval` executed instructions and cancel
+    /// execution with [`VmError::Cancelled`] if it returns `false`.
+    ///
+    /// The callback is called right after an instruction executes, so steps
+    /// stopping at a breakpoint or waiting for input do not call it.
+    ///
+    /// Panics if `interval` is zero.
+    pub fn with_heartbeat(
+        mut self,
+        interval: u64,
+        callback: impl FnMut() -> bool + 'a,
+    ) -> Vm<'a, I, O> {
+        assert!(interval > 0, "heartbeat interval must be positive");
+        self.heartbeat = Some((interval, Box::new(callback)));
+        self
+    }
+
+    /// Execute `handler` whenever `opcode` is encountered.
+    ///
+    /// The handler is responsible for updating the stack and registers.  The
+    /// VM then resumes from the next byte.
+    ///
+    /// Panics if `opcode` is not in [`EXTENSION_OPCODES`].
+    pub fn register_extension(&mut self, opcode: u8, handler: ExtensionHandler<'a>) {
+        assert!(
+            EXTENSION_OPCODES.contains(&opcode),
+            "opcode {} outside extension range",
+            opcode
+        );
+        self.extensions.insert(opcode, handler);
+    }
+
+    pub fn input(&self) -> &I {
+        &self.input
+    }
+
+    pub fn output(&self) -> &O {
+        &self.output
+    }
+
+    pub fn into_output(self) -> O {
+        self.output
+    }
+
+    /// Discard output generated so far, leaving all other state intact.
+    pub fn clear_output(&mut self) {
+        self.output.clear_output()
+    }
+
+    /// Bytes written to the binary output buffer so far.
+    pub fn byte_output(&self) -> &[u8] {
+        &self.byte_output
+    }
+
+    pub fn into_byte_output(self) -> Vec<u8> {
+        self.byte_output
+    }
+
+    /// Characters written so far to output `channel` by `OutN`.
+    ///
+    /// Channel 0 is the output channel the VM was created with, which is
+    /// empty if it does not buffer output.
+    pub fn channel_output(&self, channel: usize) -> &str {
+        match channel {
+            0 => self.output.output_str(),
+            _ => self
+                .channel_outputs
+                .get(channel - 1)
+                .map_or("", String::as_str),
+        }
+    }
+
+    /// Copy of the state visible to programs, for display or logging.
+    pub fn state(&self) -> VmState {
+        VmState {
+            pc: self.pc,
+            stack: self.stack.as_slice().to_vec(),
+            aux: self.regs[0],
+            output_so_far: self.output.output_str().to_string(),
+        }
+    }
+
+    /// Save execution state.
+    pub fn snapshot(&self) -> VmSnapshot {
+        VmSnapshot {
+            pc: self.pc,
+            stack: self.stack.as_slice().to_vec(),
+            return_stack: self.return_stack.clone(),
+            regs: self.regs,
+            heap: self.heap.clone(),
+            output_len: self.output.output_len(),
+            byte_output_len: self.byte_output.len(),
+        }
+    }
+
+    /// Rewind execution state to `snap`, discarding output written since.
+    ///
+    /// Panics if the stack is stored in a buffer too small for the snapshot.
+    pub fn restore(&mut self, snap: VmSnapshot) {
+        self.pc = snap.pc;
+        self.stack.set(&snap.stack);
+        self.return_stack = snap.return_stack;
+        self.regs = snap.regs;
+        self.heap = snap.heap;
+        self.output.truncate_output(snap.output_len);
+        self.byte_output.truncate(snap.byte_output_len);
+    }
+
+    /// Counters accumulated since the VM was created.
+    pub fn stats(&self) -> &VmStats {
+        &self.stats
+    }
+
+    /// Record every executed instruction.
+    fn with_trace(mut self) -> Vm<'a, I, O> {
+        self.trace = Some(Vec::new());
+        self
+    }
+
+    /// Events recorded so far if tracing was enabled with [`VmOptions::trace`].
+    pub fn trace(&self) -> Option<&[TraceEvent]> {
+        self.trace.as_deref()
+    }
+
+    /// Make [`Vm::step`] pause before executing the instruction at `pc`.
+    pub fn set_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+    }
+
+    pub fn clear_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.remove(&pc);
+    }
+
+    /// Interpret VM until it exits.
+    ///
+    /// An input channel with no character ready is polled until it has one.
+    /// Breakpoints are ignored.
+    pub fn run(&mut self) -> Result<(), VmError> {
+        while self.step()? != StepOutcome::Halted {}
+        Ok(())
+    }
+
+    /// Execute a single instruction.
+    ///
+    /// Output is written to the output channel before returning.
+    pub fn step(&mut self) -> Result<StepOutcome, VmError> {
+        if self.breakpoints.contains(&self.pc) && self.paused_at != Some(self.pc) {
+            self.paused_at = Some(self.pc);
+            return Ok(StepOutcome::Breakpoint(self.pc));
+        }
+        if let Some(limit) = self.max_steps {
+            if self.stats.instructions_executed >= limit {
+                return Err(VmError::StepLimitExceeded { limit });
+            }
+        }
+        let byte = *self.program.get(self.pc).ok_or(VmError::PcOutOfBounds {
+            pc: self.pc,
+            program_len: self.program.len(),
+        })?;
+        if EXTENSION_OPCODES.contains(&byte) {
+            self.paused_at = None;
+            let outcome = self.run_extension(byte)?;
+            self.beat()?;
+            return Ok(outcome);
+        }
+        let opcode = Opcode::try_from(byte)?;
+        let reads_input = match opcode {
+            Opcode::In | Opcode::InOr => true,
+            Opcode::InN => self.fetch_operand(opcode)? == 0,
+            _ => false,
+        };
+        if reads_input && !self.input.input_ready() {
+            return Ok(StepOutcome::NeedsInput);
+        }
+        self.paused_at = None;
+        self.count(opcode);
+        if let Some(trace) = &mut self.trace {
+            let operand = match opcode.operand_len() {
+                0 => None,
+                _ => read_operand(&self.program, self.pc, opcode).ok(),
+            };
+            trace.push(TraceEvent::Exec {
+                pc: self.pc,
+                opcode,
+                operand,
+                stack: self.stack.as_slice().to_vec(),
+                aux: self.regs[0],
+            });
+        }
+        let outcome = self.execute(opcode)?;
+        if outcome != StepOutcome::Halted {
+            self.beat()?;
+        }
+        Ok(outcome)
+    }
+
+    /// Call heartbeat callback if due after executing an instruction.
+    fn beat(&mut self) -> Result<(), VmError> {
+        if let Some((interval, callback)) = &mut self.heartbeat {
+            let executed = self.stats.instructions_executed;
+            if executed.is_multiple_of(*interval) && !callback() {
+                return Err(VmError::Cancelled);
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute instruction at `pc` whose opcode is `opcode`.
+    fn execute(&mut self, opcode: Opcode) -> Result<StepOutcome, VmError> {
+        match opcode {
+            Opcode::InN => {
+                let channel = self.fetch_operand(opcode)? as usize;
+                let i = self.read_channel(channel).unwrap_or(0);
+                self.push(i)?;
+                self.pc += 2;
+            }
+            Opcode::OutN => {
+                let channel = self.fetch_operand(opcode)? as usize;
+                let ch = self.pop_char()?;
+                if channel == 0 {
+                    self.write_output(ch)?;
+                    self.pc += 2;
+                    return Ok(StepOutcome::WroteOutput(ch));
+                }
+                self.pc += 2;
+                if self.channel_outputs.len() < channel {
+                    self.channel_outputs.resize(channel, String::new());
+                }
+                self.channel_outputs[channel - 1].push(ch);
+                self.stats.output_chars_written += 1;
+            }
+            Opcode::InByte => {
+                let byte = self.byte_input.next().copied().unwrap_or(0);
+                self.push(byte as u32)?;
+                self.pc += 1;
+            }
+            Opcode::OutByte => {
+                let value = self.pop()?;
+                let byte = u8::try_from(value).map_err(|_| VmError::InvalidByte(value))?;
+                self.byte_output.push(byte);
+                self.pc += 1;
+                return Ok(StepOutcome::WroteByte(byte));
+            }
+            Opcode::Load => {
+                let address = self.pop()?;
+                let value = *self.heap_cell(address)?;
+                self.push(value)?;
+                self.pc += 1;
+            }
+            Opcode::Store => {
+                let value = self.pop()?;
+                let address = self.pop()?;
+                *self.heap_cell(address)? = value;
+                self.pc += 1;
+            }
+            Opcode::Patch => {
+                let value = self.pop()?;
+                let address = self.pop()?;
+                let byte = u8::try_from(value).map_err(|_| VmError::InvalidByte(value))?;
+                if address as usize >= self.program.len() {
+                    return Err(VmError::InvalidCodeAddress {
+                        at_pc: self.pc,
+                        address,
+                    });
+                }
+                self.program.to_mut()[address as usize] = byte;
+                self.pc += 1;
+            }
+            #[cfg(feature = "debug-opcodes")]
+            Opcode::Regs => {
+                eprintln!(
+                    "PC={} STACK={:?} REGS={:?}",
+                    self.pc,
+                    self.stack.as_slice(),
+                    self.regs
+                );
+                self.pc += 1;
+            }
+            _ => return self.execute_core(opcode),
+        }
+        Ok(StepOutcome::Continue)
+    }
+
+    fn count(&mut self, opcode: Opcode) {
+        let attributes = opcode.attributes();
+        self.stats.instructions_executed += 1;
+        if attributes.contains(OpcodeAttributes::READS_INPUT) {
+            self.stats.input_reads += 1;
+        }
+        if attributes.contains(OpcodeAttributes::WRITES_OUTPUT) {
+            self.stats.output_writes += 1;
+        }
+        if attributes.contains(OpcodeAttributes::IS_BRANCH) {
+            self.stats.branches_executed += 1;
+        }
+    }
+
+    /// Read next character from input `channel`, if any.
+    fn read_channel(&mut self, channel: usize) -> Option<u32> {
+        let value = match channel {
+            0 => self.input.next_u32(),
+            _ => self
+                .channel_inputs
+                .get_mut(channel - 1)
+                .and_then(VecDeque::pop_front)
+                .map(u32::from),
+        };
+        if value.is_some() {
+            self.stats.input_chars_read += 1;
+        }
+        value
+    }
+
+    fn run_extension(&mut self, opcode: u8) -> Result<StepOutcome, VmError> {
+        let handler = self
+            .extensions
+            .get_mut(&opcode)
+            .ok_or(VmError::InvalidOpcode(opcode))?;
+        let mut context = VmExtContext {
+            stack: core::mem::take(&mut self.stack),
+            regs: self.regs,
+            pc: self.pc,
+        };
+        let result = handler(&mut context);
+        self.stack = context.stack;
+        self.regs = context.regs;
+        result.map_err(|e| VmError::Extension {
+            opcode,
+            message: e.to_string(),
+        })?;
+        if self
+            .max_stack_depth
+            .is_some_and(|max| self.stack.len() > max)
+        {
+            return Err(VmError::StackOverflow { at_pc: self.pc });
+        }
+        self.stats.instructions_executed += 1;
+        self.stats.max_stack_depth = self.stats.max_stack_depth.max(self.stack.len());
+        self.pc += 1;
+        Ok(StepOutcome::Continue)
+    }
+
+    fn heap_cell(&mut self, address: u32) -> Result<&mut u32, VmError> {
+        self.heap
+            .get_mut(address as usize)
+            .ok_or(VmError::InvalidAddress {
+                at_pc: self.pc,
+                address,
+            })
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<I: VmInput, O: VmOutput> Machine for Vm<'_, I, O> {
+    fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    fn pc(&self) -> usize {
+        self.pc
+    }
+
+    fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    fn regs(&mut self) -> &mut [u32; REGISTER_COUNT] {
+        &mut self.regs
+    }
+
+    fn stack(&self) -> &[u32] {
+        self.stack.as_slice()
+    }
+
+    fn push(&mut self, x: u32) -> Result<(), VmError> {
+        let full = self
+            .max_stack_depth
+            .is_some_and(|max| self.stack.len() >= max);
+        if full || !self.stack.push(x) {
+            return Err(VmError::StackOverflow { at_pc: self.pc });
+        }
+        self.stats.max_stack_depth = self.stats.max_stack_depth.max(self.stack.len());
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u32, VmError> {
+        self.stack
+            .pop()
+            .ok_or(VmError::StackUnderflow { at_pc: self.pc })
+    }
+
+    fn return_stack_full(&self) -> bool {
+        self.return_stack.len() >= self.max_call_depth
+    }
+
+    fn push_return(&mut self, address: usize) {
+        self.return_stack.push(address);
+    }
+
+    fn pop_return(&mut self) -> Option<usize> {
+        self.return_stack.pop()
+    }
+
+    fn read_input(&mut self) -> Option<u32> {
+        self.read_channel(0)
+    }
+
+    fn write_output(&mut self, ch: char) -> Result<(), VmError> {
+        self.stats.output_chars_written += 1;
+        let result = if self.escape_output && ch.is_ascii_control() {
+            format!("\\x{:02x}", ch as u32)
+                .chars()
+                .try_for_each(|escaped| self.output.write_u32(escaped as u32))
+        } else {
+            self.output.write_u32(ch as u32)
+        };
+        result.map_err(|error| VmError::Output {
+            at_pc: self.pc,
+            error,
+        })
+    }
+
+    fn output_char(&self, value: u32) -> Result<char, VmError> {
+        match self.encoding {
+            Encoding::Utf8 => char::from_u32(value).ok_or(VmError::InvalidCodePoint(value)),
+            Encoding::Latin1 => u8::try_from(value)
+                .map(char::from)
+                .map_err(|_| VmError::InvalidByte(value)),
+            Encoding::RawByte => Ok(char::from(value as u8)),
+        }
+    }
+
+    fn mark(&mut self, marker: u8) {
+        if let Some(trace) = &mut self.trace {
+            trace.push(TraceEvent::Marker(marker));
+        }
+    }
+}
+
+/// Execution state shared by [`Vm`] and [`VmFixed`].
+///
+/// Opcodes needing only the stacks, the registers and input and output
+/// channel 0 are executed by [`Machine::execute_core`] so that both VMs give
+/// them the same semantics.
+trait Machine {
+    fn program(&self) -> &[u8];
+
+    fn pc(&self) -> usize;
+
+    fn set_pc(&mut self, pc: usize);
+
+    fn regs(&mut self) -> &mut [u32; REGISTER_COUNT];
+
+    /// Data stack from bottom to top.
+    fn stack(&self) -> &[u32];
+
+    fn push(&mut self, x: u32) -> Result<(), VmError>;
+
+    fn pop(&mut self) -> Result<u32, VmError>;
+
+    /// Would pushing a return address overflow the return stack?
+    fn return_stack_full(&self) -> bool;
+
+    fn push_return(&mut self, address: usize);
+
+    fn pop_return(&mut self) -> Option<usize>;
+
+    /// Read next character from input channel 0, if any.
+    fn read_input(&mut self) -> Option<u32>;
+
+    /// Write `ch` to output channel 0.
+    fn write_output(&mut self, ch: char) -> Result<(), VmError>;
+
+    /// Convert `value` to the character to write.
+    fn output_char(&self, value: u32) -> Result<char, VmError>;
+
+    /// Record the operand of an executed `Trace` instruction.
+    fn mark(&mut self, _marker: u8) {}
+
+    /// Execute instruction at `pc` whose opcode is `opcode`.
+    ///
+    /// Opcodes needing more state fail with [`VmError::Unimplemented`].
+    fn execute_core(&mut self, opcode: Opcode) -> Result<StepOutcome, VmError> {
+        match opcode {
+            Opcode::Exit => return Ok(StepOutcome::Halted),
+            Opcode::Nop => self.advance(opcode),
+            Opcode::In => {
+                let i = self.read_input().unwrap_or(0);
+                self.push(i)?;
+                self.advance(opcode);
+            }
+            Opcode::InOr => {
+                let fallback = self.fetch_operand(opcode)?;
+                let i = self.read_input().unwrap_or(fallback);
+                self.push(i)?;
+                self.advance(opcode);
+            }
+            Opcode::Out => {
+                let ch = self.pop_char()?;
+                self.write_output(ch)?;
+                self.advance(opcode);
+                return Ok(StepOutcome::WroteOutput(ch));
+            }
+            Opcode::OutPeek => {
+                let top = self.top()?;
+                let ch = self.output_char(top)?;
+                self.write_output(ch)?;
+                self.advance(opcode);
+                return Ok(StepOutcome::WroteOutput(ch));
+            }
+            Opcode::Out2 => {
+                let second = self.pop_char()?;
+                let first = self.pop_char()?;
+                self.write_output(first)?;
+                self.write_output(second)?;
+                self.advance(opcode);
+                return Ok(StepOutcome::WroteOutput2(first, second));
+            }
+            Opcode::Jmp | Opcode::Jmpw | Opcode::JmpRel => {
+                let target = self.fetch_target(opcode)?;
+                self.jump(target)?;
+            }
+            Opcode::Call | Opcode::Callw => {
+                if self.return_stack_full() {
+                    return Err(VmError::ReturnStackOverflow { at_pc: self.pc() });
+                }
+                let target = self.fetch_operand(opcode)? as usize;
+                let return_address = self.pc() + 1 + opcode.operand_len();
+                self.jump(target)?;
+                self.push_return(return_address);
+            }
+            Opcode::Ret => {
+                let address = self
+                    .pop_return()
+                    .ok_or(VmError::ReturnStackUnderflow { at_pc: self.pc() })?;
+                self.set_pc(address);
+            }
+            Opcode::Dup => {
+                let top = self.top()?;
+                self.push(top)?;
+                self.advance(opcode);
+            }
+            Opcode::Dup2 => {
+                let [second, top] = *self
+                    .stack()
+                    .last_chunk()
+                    .ok_or(VmError::StackUnderflow { at_pc: self.pc() })?;
+                self.push(second)?;
+                self.push(top)?;
+                self.advance(opcode);
+            }
+            Opcode::Over => {
+                let stack = self.stack();
+                let second = *stack
+                    .len()
+                    .checked_sub(2)
+                    .and_then(|i| stack.get(i))
+                    .ok_or(VmError::StackUnderflow { at_pc: self.pc() })?;
+                self.push(second)?;
+                self.advance(opcode);
+            }
+            Opcode::Bne | Opcode::Bnew | Opcode::BneRel => {
+                let top = self.pop()?;
+                let target = self.fetch_target(opcode)?;
+                self.branch(opcode, top != 0, target)?;
+            }
+            Opcode::Beqz | Opcode::Beqzw | Opcode::BeqzRel => {
+                let top = self.pop()?;
+                let target = self.fetch_target(opcode)?;
+                self.branch(opcode, top == 0, target)?;
+            }
+            Opcode::Beq | Opcode::Beqw | Opcode::BeqRel => {
+                self.branch_if(opcode, |l, r| l == r)?;
+            }
+            Opcode::Bgt | Opcode::Bgtw | Opcode::BgtRel => {
+                self.branch_if(opcode, |l, r| l > r)?;
+            }
+            Opcode::Blt | Opcode::Bltw | Opcode::BltRel => {
+                self.branch_if(opcode, |l, r| l < r)?;
+            }
+            Opcode::Ble | Opcode::Blew | Opcode::BleRel => {
+                self.branch_if(opcode, |l, r| l <= r)?;
+            }
+            Opcode::Blts | Opcode::BltsRel => {
+                self.branch_if(opcode, |l, r| (l as i32) < (r as i32))?;
+            }
+            Opcode::Bgts | Opcode::BgtsRel => {
+                self.branch_if(opcode, |l, r| (l as i32) > (r as i32))?;
+            }
+            Opcode::Bles | Opcode::BlesRel => {
+                self.branch_if(opcode, |l, r| (l as i32) <= (r as i32))?;
+            }
+            Opcode::Bges | Opcode::BgesRel => {
+                self.branch_if(opcode, |l, r| (l as i32) >= (r as i32))?;
+            }
+            Opcode::Pusha => {
+                let aux = self.regs()[0];
+                self.push(aux)?;
+                self.advance(opcode);
+            }
+            Opcode::Pushr => {
+                let index = self.fetch_register(opcode)?;
+                let value = self.regs()[index];
+                self.push(value)?;
+                self.advance(opcode);
+            }
+            Opcode::Push | Opcode::Pushw | Opcode::Push32 => {
+                let value = self.fetch_operand(opcode)?;
+                self.push(value)?;
+                self.advance(opcode);
+            }
+            Opcode::Pushi => {
+                let value = self.fetch_operand(opcode)? as u8 as i8 as u32;
+                self.push(value)?;
+                self.advance(opcode);
+            }
+            Opcode::Push0 => {
+                self.push(0)?;
+                self.advance(opcode);
+            }
+            Opcode::Push1 => {
+                self.push(1)?;
+                self.advance(opcode);
+            }
+            Opcode::Popa => {
+                self.regs()[0] = self.pop()?;
+                self.advance(opcode);
+            }
+            Opcode::Popr => {
+                let index = self.fetch_register(opcode)?;
+                self.regs()[index] = self.pop()?;
+                self.advance(opcode);
+            }
+            Opcode::Drop => {
+                self.pop()?;
+                self.advance(opcode);
+            }
+            Opcode::Add => self.binary_op(u32::wrapping_add)?,
+            Opcode::Sub => self.binary_op(u32::wrapping_sub)?,
+            Opcode::Mul => self.binary_op(u32::wrapping_mul)?,
+            Opcode::Div => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+                let quotient = lhs
+                    .checked_div(rhs)
+                    .ok_or(VmError::DivisionByZero { at_pc: self.pc() })?;
+                self.push(quotient)?;
+                self.advance(opcode);
+            }
+            Opcode::Rem => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+                let remainder = lhs
+                    .checked_rem(rhs)
+                    .ok_or(VmError::DivisionByZero { at_pc: self.pc() })?;
+                self.push(remainder)?;
+                self.advance(opcode);
+            }
+            Opcode::And => self.binary_op(|l, r| l & r)?,
+            Opcode::Or => self.binary_op(|l, r| l | r)?,
+            Opcode::Xor => self.binary_op(|l, r| l ^ r)?,
+            Opcode::Not => self.unary_op(|x| !x)?,
+            Opcode::Neg => self.unary_op(u32::wrapping_neg)?,
+            Opcode::Inc => self.unary_op(|x| x.wrapping_add(1))?,
+            Opcode::Dec => self.unary_op(|x| x.wrapping_sub(1))?,
+            Opcode::Shl => self.binary_op(|l, r| l.checked_shl(r).unwrap_or(0))?,
+            Opcode::Shr => self.binary_op(|l, r| l.checked_shr(r).unwrap_or(0))?,
+            Opcode::Sar => self.binary_op(|l, r| ((l as i32) >> r.min(31)) as u32)?,
+            Opcode::Swap => {
+                let top = self.pop()?;
+                let below = self.pop()?;
+                self.push(top)?;
+                self.push(below)?;
+                self.advance(opcode);
+            }
+            Opcode::Rot => {
+                let top = self.pop()?;
+                let middle = self.pop()?;
+                let bottom = self.pop()?;
+                self.push(middle)?;
+                self.push(top)?;
+                self.push(bottom)?;
+                self.advance(opcode);
+            }
+            Opcode::Select => {
+                let if_false = self.pop()?;
+                let if_true = self.pop()?;
+                let cond = self.pop()?;
+                self.push(if cond != 0 { if_true } else { if_false })?;
+                self.advance(opcode);
+            }
+            #[cfg(feature = "debug-opcodes")]
+            Opcode::Assert => {
+                let value = self.pop()?;
+                if value == 0 {
+                    return Err(VmError::AssertionFailed {
+                        pc: self.pc(),
+                        value,
+                    });
+                }
+                self.advance(opcode);
+            }
+            Opcode::Trace => {
+                let marker = self.fetch_operand(opcode)? as u8;
+                self.mark(marker);
+                self.advance(opcode);
+            }
+            _ => return Err(VmError::Unimplemented(opcode)),
+        }
+        Ok(StepOutcome::Continue)
+    }
+
+    /// Move `pc` past the instruction whose opcode is `opcode`.
+    fn advance(&mut self, opcode: Opcode) {
+        self.set_pc(self.pc() + 1 + opcode.operand_len());
+    }
+
+    fn top(&self) -> Result<u32, VmError> {
+        self.stack()
+            .last()
+            .copied()
+            .ok_or(VmError::StackUnderflow { at_pc: self.pc() })
+    }
+
+    fn pop_char(&mut self) -> Result<char, VmError> {
+        let value = self.pop()?;
+        self.output_char(value)
+    }
+
+    /// Read the operand of `opcode` located at `pc`.
+    fn fetch_operand(&self, opcode: Opcode) -> Result<u32, VmError> {
+        read_operand(self.program(), self.pc(), opcode)
+    }
+
+    /// Read the operand of branch `opcode` located at `pc` and return the
+    /// address it jumps to.
+    fn fetch_target(&self, opcode: Opcode) -> Result<usize, VmError> {
+        let operand = self.fetch_operand(opcode)?;
+        Ok(opcode.branch_target(self.pc(), operand))
+    }
+
+    /// Read the register index operand of `opcode` located at `pc`.
+    fn fetch_register(&self, opcode: Opcode) -> Result<usize, VmError> {
+        let index = self.fetch_operand(opcode)?;
+        if index as usize >= REGISTER_COUNT {
+            return Err(VmError::InvalidRegister {
+                at_pc: self.pc(),
+                index,
+            });
+        }
+        Ok(index as usize)
+    }
+
+    /// Replace the topmost stack element with `op` applied to it.
+    fn unary_op<Op: FnOnce(u32) -> u32>(&mut self, op: Op) -> Result<(), VmError> {
+        let x = self.pop()?;
+        self.push(op(x))?;
+        self.set_pc(self.pc() + 1);
+        Ok(())
+    }
+
+    /// Replace the two topmost stack elements with `op` applied to them.
+    fn binary_op<Op: FnOnce(u32, u32) -> u32>(&mut self, op: Op) -> Result<(), VmError> {
+        let rhs = self.pop()?;
+        let lhs = self.pop()?;
+        self.push(op(lhs, rhs))?;
+        self.set_pc(self.pc() + 1);
+        Ok(())
+    }
+
+    /// Jump to `target` if `taken`, else move past branch `opcode`.
+    fn branch(&mut self, opcode: Opcode, taken: bool, target: usize) -> Result<(), VmError> {
+        if taken {
+            self.jump(target)
+        } else {
+            self.advance(opcode);
+            Ok(())
+        }
+    }
+
+    /// Transfer control to `target`, which must be inside the program.
+    fn jump(&mut self, target: usize) -> Result<(), VmError> {
+        if target >= self.program().len() {
+            return Err(VmError::InvalidJumpTarget {
+                at_pc: self.pc(),
+                target,
+            });
+        }
+        self.set_pc(target);
+        Ok(())
+    }
+
+    fn branch_if<Cmp: FnOnce(u32, u32) -> bool>(
+        &mut self,
+        opcode: Opcode,
+        cmp: Cmp,
+    ) -> Result<(), VmError> {
+        let rhs = self.pop()?;
+        let lhs = self.pop()?;
+        let target = self.fetch_target(opcode)?;
+        self.branch(opcode, cmp(lhs, rhs), target)
+    }
+}
+
+/// Decode the little-endian operand of `opcode` located at `pc`.
+fn read_operand(program: &[u8], pc: usize, opcode: Opcode) -> Result<u32, VmError> {
+    let bytes = program
+        .get(pc + 1..pc + 1 + opcode.operand_len())
+        .ok_or(VmError::UnexpectedEndOfProgram { pc, opcode })?;
+    Ok(bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u32))
+}
+
+/// Configuration of a VM reading its input from a string, with optional
+/// initial register and stack content and resource limits.
+///
+/// Unlike [`Vm::with_options`], only stack and step limits explicitly set
+/// are enforced.
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone)]
+pub struct VmBuilder<'a> {
+    program: &'a [u8],
+    input: &'a str,
+    aux: u32,
+    stack: Vec<u32>,
+    max_stack_depth: Option<usize>,
+    max_steps: Option<u64>,
+}
+
+#[cfg(feature = "alloc")]
+impl<'a> VmBuilder<'a> {
+    pub fn new(program: &'a [u8]) -> VmBuilder<'a> {
+        VmBuilder {
+            program,
+            input: "",
+            aux: 0,
+            stack: Vec::new(),
+            max_stack_depth: None,
+            max_steps: None,
+        }
+    }
+
+    /// Read input characters from `input`.
+    pub fn input(mut self, input: &'a str) -> VmBuilder<'a> {
+        self.input = input;
+        self
+    }
+
+    /// Start with `value` in the auxiliary register.
+    pub fn initial_aux(mut self, value: u32) -> VmBuilder<'a> {
+        self.aux = value;
+        self
+    }
+
+    /// Start with `values` on the stack, the last one topmost.
+    pub fn initial_stack(mut self, values: Vec<u32>) -> VmBuilder<'a> {
+        self.stack = values;
+        self
+    }
+
+    /// Fail with [`VmError::StackOverflow`] when pushing more than `depth`
+    /// elements.
+    pub fn max_stack_depth(mut self, depth: usize) -> VmBuilder<'a> {
+        self.max_stack_depth = Some(depth);
+        self
+    }
+
+    /// Fail with [`VmError::StepLimitExceeded`] after executing `steps`
+    /// instructions.
+    pub fn max_steps(mut self, steps: u64) -> VmBuilder<'a> {
+        self.max_steps = Some(steps);
+        self
+    }
+
+    pub fn build(self) -> Vm<'a, &'a str, String> {
+        let mut vm = Vm::new(self.program, self.input, String::new());
+        vm.regs[0] = self.aux;
+        vm.stack.set(&self.stack);
+        vm.max_stack_depth = self.max_stack_depth;
+        vm.max_steps = self.max_steps;
+        vm
+    }
+
+    /// Build VM, run it until it exits and return generated output.
+    pub fn run(self) -> anyhow::Result<String> {
+        let mut vm = self.build();
+        vm.run()?;
+        Ok(vm.into_output())
+    }
+}
+
+/// VM runner whose data stack is an array of `N` elements and return stack
+/// an array of `R` addresses.
+///
+/// Nothing is allocated on the heap, so `VmFixed` is available without the
+/// `alloc` feature.  In exchange, there are no `Load` and `Store` cells, no
+/// extra input and output channels and no binary input and output: `Load`,
+/// `Store`, `Patch`, `InN`, `OutN`, `InByte` and `OutByte` fail with
+/// [`VmError::Unimplemented`].  Input and output values are Unicode scalar
+/// values.
+pub struct VmFixed<const N: usize, const R: usize = 16> {
+    stack: [u32; N],
+    stack_top: usize,
+}
+
+/// Fixed-size stack VM runner with a 256-element stack.
+pub type VmDefault = VmFixed<256>;
+
+impl<const N: usize, const R: usize> VmFixed<N, R> {
+    pub const fn new() -> VmFixed<N, R> {
+        VmFixed {
+            stack: [0; N],
+            stack_top: 0,
+        }
+    }
+
+    /// Execute `program` until it exits or fails.  Pass `&mut output` to
+    /// keep access to output.
+    ///
+    /// Pushing an element onto a full stack fails with
+    /// [`VmError::StackOverflow`] and calling with a full return stack with
+    /// [`VmError::ReturnStackOverflow`].
+    pub fn run(
+        &mut self,
+        program: &[u8],
+        input: impl VmInput,
+        output: impl VmOutput,
+    ) -> Result<(), VmError> {
+        let mut machine = FixedMachine {
+            program,
+            pc: 0,
+            stack: &mut self.stack,
+            stack_top: 0,
+            return_stack: [0; R],
+            return_top: 0,
+            regs: [0; REGISTER_COUNT],
+            input,
+            output,
+        };
+        let result = machine.run();
+        self.stack_top = machine.stack_top;
+        result
+    }
+
+    /// Stack left by the last run, from bottom to top.
+    pub fn stack(&self) -> &[u32] {
+        &self.stack[..self.stack_top]
+    }
+}
+
+impl<const N: usize, const R: usize> Default for VmFixed<N, R> {
+    fn default() -> Self {
+        VmFixed::new()
+    }
+}
+
+/// State of a program run by [`VmFixed`].
+struct FixedMachine<'a, I, O, const R: usize> {
+    program: &'a [u8],
+    pc: usize,
+    stack: &'a mut [u32],
+    stack_top: usize,
+    return_stack: [usize; R],
+    return_top: usize,
+    regs: [u32; REGISTER_COUNT],
+    input: I,
+    output: O,
+}
+
+impl<I: VmInput, O: VmOutput, const R: usize> FixedMachine<'_, I, O, R> {
+    /// Interpret program until it exits.
+    ///
+    /// An input channel with no character ready is polled until it has one.
+    fn run(&mut self) -> Result<(), VmError> {
+        loop {
+            let byte = *self.program.get(self.pc).ok_or(VmError::PcOutOfBounds {
+                pc: self.pc,
+                program_len: self.program.len(),
+            })?;
+            let opcode = Opcode::try_from(byte)?;
+            if matches!(opcode, Opcode::In | Opcode::InOr) {
+                while !self.input.input_ready() {}
+            }
+            if self.execute_core(opcode)? == StepOutcome::Halted {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<I: VmInput, O: VmOutput, const R: usize> Machine for FixedMachine<'_, I, O, R> {
+    fn program(&self) -> &[u8] {
+        self.program
+    }
+
+    fn pc(&self) -> usize {
+        self.pc
+    }
+
+    fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    fn regs(&mut self) -> &mut [u32; REGISTER_COUNT] {
+        &mut self.regs
+    }
+
+    fn stack(&self) -> &[u32] {
+        &self.stack[..self.stack_top]
+    }
+
+    fn push(&mut self, x: u32) -> Result<(), VmError> {
+        let slot = self
+            .stack
+            .get_mut(self.stack_top)
+            .ok_or(VmError::StackOverflow { at_pc: self.pc })?;
+        *slot = x;
+        self.stack_top += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u32, VmError> {
+        self.stack_top = self
+            .stack_top
+            .checked_sub(1)
+            .ok_or(VmError::StackUnderflow { at_pc: self.pc })?;
+        Ok(self.stack[self.stack_top])
+    }
+
+    fn return_stack_full(&self) -> bool {
+        self.return_top == R
+    }
+
+    fn push_return(&mut self, address: usize) {
+        self.return_stack[self.return_top] = address;
+        self.return_top += 1;
+    }
+
+    fn pop_return(&mut self) -> Option<usize> {
+        self.return_top = self.return_top.checked_sub(1)?;
+        Some(self.return_stack[self.return_top])
+    }
+
+    fn read_input(&mut self) -> Option<u32> {
+        self.input.next_u32()
+    }
+
+    fn write_output(&mut self, ch: char) -> Result<(), VmError> {
+        self.output
+            .write_u32(ch as u32)
+            .map_err(|error| VmError::Output {
+                at_pc: self.pc,
+                error,
+            })
+    }
+
+    fn output_char(&self, value: u32) -> Result<char, VmError> {
+        char::from_u32(value).ok_or(VmError::InvalidCodePoint(value))
+    }
+}
+
+/// Execute specified program on specified input and return generated output.
+#[cfg(feature = "alloc")]
+pub fn run(program: &[u8], input: &str) -> anyhow::Result<String> {
+    let (output, _) = run_with_stats(program, input)?;
+    Ok(output)
+}
+
+/// Execute specified program on specified input and return generated output
+/// along with execution counters.
+#[cfg(feature = "alloc")]
+pub fn run_with_stats(program: &[u8], input: &str) -> anyhow::Result<(String, VmStats)> {
+    debug_assert!(!program.is_empty());
+    let mut vm = Vm::new(program, input, String::new());
+    vm.run()?;
+    let stats = vm.stats.clone();
+    Ok((vm.into_output(), stats))
+}
+
+/// Execute specified program on specified input within the resource limits in
+/// `opts` and return generated output.
+#[cfg(feature = "alloc")]
+pub fn run_with_options(program: &[u8], input: &str, opts: &VmOptions) -> anyhow::Result<String> {
+    debug_assert!(!program.is_empty());
+    let mut vm = Vm::new(program, input, String::new()).with_options(opts);
+    vm.run()?;
+    Ok(vm.into_output())
+}
+
+/// Execute specified program on specified text and binary input within the
+/// resource limits in `opts` and return generated text and binary output.
+#[cfg(feature = "alloc")]
+pub fn run_with_bytes(
+    program: &[u8],
+    input: &str,
+    input_bytes: &[u8],
+    opts: &VmOptions,
+) -> anyhow::Result<(String, Vec<u8>)> {
+    debug_assert!(!program.is_empty());
+    let mut vm = Vm::new(program, input, String::new())
+        .with_options(opts)
+        .with_byte_input(input_bytes);
+    vm.run()?;
+    let output = core::mem::take(&mut vm.output);
+    Ok((output, vm.into_byte_output()))
+}
+
+/// Execute specified program on input bytes decoded according to
+/// `opts.encoding` within the resource limits in `opts` and return generated
+/// output encoded the same way.
+///
+/// With [`Encoding::Utf8`], input that is not valid UTF-8 is an error.
+#[cfg(feature = "alloc")]
+pub fn run_encoded(program: &[u8], input: &[u8], opts: &VmOptions) -> anyhow::Result<Vec<u8>> {
+    debug_assert!(!program.is_empty());
+    let input: String = match opts.encoding {
+        Encoding::Utf8 => core::str::from_utf8(input)
+            .map_err(|e| anyhow::anyhow!("input is not valid UTF-8: {}", e))?
+            .into(),
+        Encoding::Latin1 | Encoding::RawByte => input.iter().map(|&b| char::from(b)).collect(),
+    };
+    let output = run_with_options(program, &input, opts)?;
+    Ok(match opts.encoding {
+        Encoding::Utf8 => output.into_bytes(),
+        // Characters written were checked or truncated to fit in a byte.
+        Encoding::Latin1 | Encoding::RawByte => output.chars().map(|ch| ch as u8).collect(),
+    })
+}
+
+/// Execute specified program reading from and writing to specified channels.
+#[cfg(feature = "alloc")]
+pub fn run_with_io(
+    program: &[u8],
+    input: impl VmInput,
+    output: impl VmOutput,
+) -> anyhow::Result<()> {
+    debug_assert!(!program.is_empty());
+    Vm::new(program, input, output).run()?;
+    Ok(())
+}
+
+/// Execute specified program on each line read from stdin and write each
+/// output on its own line to stdout.
+///
+/// Stdout being closed early, as when piping into `head`, ends the filter
+/// without error.
+#[cfg(feature = "std")]
+pub fn run_filter(program: &[u8]) -> anyhow::Result<()> {
+    let mut stdout = io::stdout().lock();
+    for line in io::stdin().lock().lines() {
+        let output = run(program, &line?)?;
+        if !ignore_broken_pipe(writeln!(stdout, "{}", output))? {
+            return Ok(());
+        }
+    }
+    ignore_broken_pipe(stdout.flush())?;
+    Ok(())
+}
+
+/// Tell whether output can continue, treating a closed pipe as a normal end.
+#[cfg(feature = "std")]
+fn ignore_broken_pipe(result: io::Result<()>) -> io::Result<bool> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Execute specified program on UTF-8 input read asynchronously and return
+/// generated output.
+///
+/// Instructions run synchronously: the VM only yields to the runtime when it
+/// needs an input character that has not been read yet.
+#[cfg(feature = "tokio")]
+pub async fn run_async<R: tokio::io::AsyncRead + Unpin>(
+    program: &[u8],
+    mut input: R,
+) -> anyhow::Result<String> {
+    debug_assert!(!program.is_empty());
+    let mut vm = Vm::new(program, FedInput::default(), String::new());
+    loop {
+        match vm.step()? {
+            StepOutcome::Halted => break,
+            StepOutcome::NeedsInput => {
+                let ch = read_char_async(&mut input).await?;
+                vm.input.feed(ch);
+            }
+            _ => (),
+        }
+    }
+    Ok(vm.into_output())
+}
+
+/// Read one UTF-8 encoded character, or `None` on end of input.
+#[cfg(feature = "tokio")]
+async fn read_char_async<R: tokio::io::AsyncRead + Unpin>(
+    input: &mut R,
+) -> anyhow::Result<Option<char>> {
+    use tokio::io::AsyncReadExt;
+
+    let mut bytes = [0; 4];
+    bytes[0] = match input.read_u8().await {
+        Ok(byte) => byte,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let len = match bytes[0] {
+        0x00..=0x7f => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => anyhow::bail!("invalid UTF-8 input"),
+    };
+    input.read_exact(&mut bytes[1..len]).await?;
+    let s = core::str::from_utf8(&bytes[..len])?;
+    Ok(s.chars().next())
+}
+
+/// Input fed one character at a time by [`run_async`].
+#[cfg(feature = "tokio")]
+#[derive(Debug, Default)]
+struct FedInput {
+    pending: Option<char>,
+    closed: bool,
+}
+
+#[cfg(feature = "tokio")]
+impl FedInput {
+    /// Provide next input character, or `None` to signal end of input.
+    fn feed(&mut self, ch: Option<char>) {
+        self.pending = ch;
+        self.closed = ch.is_none();
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl VmInput for FedInput {
+    fn next_u32(&mut self) -> Option<u32> {
+        self.pending.take().map(u32::from)
+    }
+
+    fn input_ready(&mut self) -> bool {
+        self.pending.is_some() || self.closed
+    }
+}
+
+/// Lazily execute specified program on specified input and yield each output
+/// character as soon as it is generated.
+///
+/// The iterator ends when the program exits or after yielding an error.
+/// Collecting it into an `anyhow::Result<String>` gives the same result as
+/// [`run`].
+#[cfg(feature = "alloc")]
+pub fn run_iter<'a>(program: &'a [u8], input: &'a str) -> OutputIter<'a> {
+    debug_assert!(!program.is_empty());
+    OutputIter {
+        vm: Vm::new(program, input, String::new()),
+        pending: None,
+        done: false,
+    }
+}
+
+/// Iterator returned by [`run_iter`].
+#[cfg(feature = "alloc")]
+pub struct OutputIter<'a> {
+    vm: Vm<'a, &'a str, String>,
+    pending: Option<char>,
+    done: bool,
+}
+
+#[cfg(feature = "alloc")]
+impl Iterator for OutputIter<'_> {
+    type Item = anyhow::Result<char>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(ch) = self.pending.take() {
+            return Some(Ok(ch));
+        }
+        while !self.done {
+            let outcome = self.vm.step();
+            // Characters are yielded rather than accumulated.
+            self.vm.clear_output();
+            match outcome {
+                Ok(
+                    StepOutcome::Continue | StepOutcome::NeedsInput | StepOutcome::Breakpoint(_),
+                ) => (),
+                Ok(StepOutcome::WroteByte(_)) => (),
+                Ok(StepOutcome::WroteOutput(ch)) => return Some(Ok(ch)),
+                Ok(StepOutcome::WroteOutput2(first, second)) => {
+                    self.pending = Some(second);
+                    return Some(Ok(first));
+                }
+                Ok(StepOutcome::Halted) => self.done = true,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Execute specified program on specified input and return generated output
+/// along with the sequence of executed instructions.
+#[cfg(feature = "alloc")]
+pub fn run_traced(program: &[u8], input: &str) -> anyhow::Result<(String, Vec<TraceEvent>)> {
+    debug_assert!(!program.is_empty());
+    let mut vm = Vm::new(program, input, String::new()).with_trace();
+    vm.run()?;
+    Ok((vm.output, vm.trace.unwrap_or_default()))
+}
+
+/// Render trace in the folded stack format consumed by flamegraph tools such
+/// as `inferno-flamegraph`.
+///
+/// Each executed instruction is one sample whose stack is `main` followed by
+/// one `fn@xxxx` frame per active call, named after the callee's address.
+/// Identical stacks are merged and emitted once per line as
+/// `frame;frame;... count`, sorted by stack.
+#[cfg(feature = "alloc")]
+pub fn trace_to_flamegraph(events: &[TraceEvent]) -> String {
+    let mut samples = BTreeMap::new();
+    let mut frames = String::from("main");
+    let mut frame_starts = Vec::new();
+    let mut entering_call = false;
+    for event in events {
+        match event {
+            TraceEvent::Exec { pc, opcode, .. } => {
+                if entering_call {
+                    frame_starts.push(frames.len());
+                    frames.push_str(&format!(";fn@{:04x}", pc));
+                    entering_call = false;
+                }
+                *samples
+                    .entry(format!("{};{}@{:04x}", frames, opcode, pc))
+                    .or_insert(0) += 1;
+                match opcode {
+                    Opcode::Call | Opcode::Callw => entering_call = true,
+                    Opcode::Ret => {
+                        if let Some(start) = frame_starts.pop() {
+                            frames.truncate(start);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            TraceEvent::Marker(_) => (),
+        }
+    }
+    let mut output = String::new();
+    for (stack, count) in samples {
+        output.push_str(&format!("{} {}\n", stack, count));
+    }
+    output
+}
+
+/// Render trace as human-readable text with one line per event.
+///
+/// Instructions are printed as `pc: opcode [operand] stack=[...] aux=N` where
+/// the stack and auxiliary register are shown as they were before executing
+/// the instruction.
+#[cfg(feature = "alloc")]
+pub fn trace_to_string(events: &[TraceEvent]) -> String {
+    let mut output = String::new();
+    for event in events {
+        match event {
+            TraceEvent::Exec {
+                pc,
+                opcode,
+                operand,
+                stack,
+                aux,
+            } => {
+                output.push_str(&format!("{:04x}: {}", pc, opcode));
+                if let Some(operand) = operand {
+                    output.push_str(&format!(" {}", operand));
+                }
+                output.push_str(&format!(" stack={:?} aux={}\n", stack, aux));
+            }
+            TraceEvent::Marker(marker) => output.push_str(&format!("marker {}\n", marker)),
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{parse, Insn};
+
+    /// Apply binary operation `mnemonic` to `lhs` and `rhs`.
+    fn binary(mnemonic: &str, lhs: u32, rhs: u32) -> Result<u32, VmError> {
+        let program = parse(&format!("push {}\npush {}\n{}\nexit", lhs, rhs, mnemonic)).unwrap();
+        Ok(exec(&program)?[0])
+    }
+
+    /// Apply unary operation `mnemonic` to `x`.
+    fn unary(mnemonic: &str, x: u32) -> Result<u32, VmError> {
+        let program = parse(&format!("push {}\n{}\nexit", x, mnemonic)).unwrap();
+        Ok(exec(&program)?[0])
+    }
+
+    /// Execute `mnemonic` on a stack holding `depth` elements.
+    fn underflow(mnemonic: &str, depth: usize) -> Result<Vec<u32>, VmError> {
+        let program = parse(&format!("{}{}\nexit", "push 7\n".repeat(depth), mnemonic)).unwrap();
+        exec(&program)
+    }
+
+    /// Assemble program writing `text` to output.
+    fn printer(text: &str) -> Vec<u8> {
+        text.chars()
+            .fold(crate::asm::ProgramBuilder::new(), |b, ch| {
+                b.push(ch as u32).out()
+            })
+            .exit()
+            .assemble()
+            .unwrap()
+    }
+
+    /// Run `program` without input and return the final stack.
+    fn exec(program: &[u8]) -> Result<Vec<u32>, VmError> {
+        let mut vm = VmBuilder::new(program).build();
+        vm.run()?;
+        Ok(vm.state().stack)
+    }
+
+    #[test]
+    fn flamegraph_is_accepted_by_inferno() {
+        let program = parse("push 3\nloop: dec\ndup\nbnez loop\nexit").unwrap();
+        let (_, events) = run_traced(&program, "").unwrap();
+        let folded = trace_to_flamegraph(&events);
+
+        let mut samples = 0;
+        for line in folded.lines() {
+            let (stack, count) = line.rsplit_once(' ').unwrap();
+            assert!(stack.starts_with("main;"), "{}", line);
+            samples += count.parse::<usize>().unwrap();
+        }
+        assert_eq!(samples, events.len());
+
+        let mut svg = Vec::new();
+        let mut opts = inferno::flamegraph::Options::default();
+        inferno::flamegraph::from_lines(&mut opts, folded.lines(), &mut svg).unwrap();
+        assert!(String::from_utf8(svg).unwrap().contains("<svg"));
+    }
+
+    #[cfg(feature = "debug-opcodes")]
+    #[test]
+    fn assert_passes_on_non_zero() {
+        let program = parse("push 100\npush 50\nadd\ndup\nassert\nout\nexit").unwrap();
+        assert_eq!(run(&program, "").unwrap(), "\u{96}");
+    }
+
+    #[cfg(feature = "debug-opcodes")]
+    #[test]
+    fn assert_fails_on_zero() {
+        let program = [
+            Opcode::Push as u8,
+            0,
+            Opcode::Assert as u8,
+            Opcode::Exit as u8,
+        ];
+        let e = run(&program, "")
+            .unwrap_err()
+            .downcast::<VmError>()
+            .unwrap();
+        assert_eq!(e, VmError::AssertionFailed { pc: 2, value: 0 });
+        assert_eq!(e.to_string(), "assertion failed at pc=2: popped 0");
+
+        // Check that a sum stays below 200.
+        let check = "add\ndup\npush 200\nblt ok\npush 0\nassert\nok: exit";
+        let program = parse(&format!("push 100\npush 50\n{}", check)).unwrap();
+        assert!(run(&program, "").is_ok());
+        let program = parse(&format!("push 150\npush 50\n{}", check)).unwrap();
+        let e = run(&program, "")
+            .unwrap_err()
+            .downcast::<VmError>()
+            .unwrap();
+        assert_eq!(e, VmError::AssertionFailed { pc: 11, value: 0 });
+    }
+
+    fn square_vm(program: &[u8]) -> Vm<'_, &str, String> {
+        let mut vm = VmBuilder::new(program).build();
+        vm.register_extension(
+            240,
+            Box::new(|ctx: &mut VmExtContext| {
+                let x = ctx.pop()?;
+                ctx.push(x.wrapping_mul(x))?;
+                Ok(())
+            }),
+        );
+        vm
+    }
+
+    #[test]
+    fn extension_opcode_squares_top_of_stack() {
+        let program = [Opcode::Push as u8, 7, 240, 240, Opcode::Exit as u8];
+        let mut vm = square_vm(&program);
+        vm.run().unwrap();
+        assert_eq!(vm.state().stack, [2401]);
+    }
+
+    #[test]
+    fn extension_handler_error_is_reported() {
+        let program = [240, Opcode::Exit as u8];
+        let mut vm = square_vm(&program);
+        assert!(matches!(
+            vm.run(),
+            Err(VmError::Extension { opcode: 240, .. })
+        ));
+    }
+
+    #[test]
+    fn unregistered_extension_opcode_is_invalid() {
+        assert_eq!(exec(&[241]), Err(VmError::InvalidOpcode(241)));
+    }
+
+    #[test]
+    #[should_panic(expected = "outside extension range")]
+    fn extension_outside_range_panics() {
+        let program = [Opcode::Exit as u8];
+        let mut vm = VmBuilder::new(&program).build();
+        vm.register_extension(Opcode::Push as u8, Box::new(|_| Ok(())));
+    }
+
+    #[test]
+    fn escaped_output_shows_control_characters() {
+        let program = printer("\x01\x02hello\x03\x7f");
+        let mut output = String::new();
+        Vm::new(&program, "", &mut output)
+            .with_escaped_output()
+            .run()
+            .unwrap();
+        assert_eq!(output, r"\x01\x02hello\x03\x7f");
+    }
+
+    #[test]
+    fn output_is_not_escaped_by_default() {
+        let program = printer("\x01hi");
+        assert_eq!(run(&program, "").unwrap(), "\x01hi");
+    }
+
+    #[test]
+    fn decrypter_stats() {
+        let program = crate::asm::assemble(&crate::programs::decrypter()).unwrap();
+        let (output, stats) = run_with_stats(&program, "abc").unwrap();
+        assert_eq!(output, "egi");
+        // Prologue of 2 instructions, 15 per letter not wrapped and 4 to exit.
+        assert_eq!(stats.instructions_executed, 2 + 3 * 15 + 4);
+        assert_eq!(stats.input_reads, 4);
+        assert_eq!(stats.input_chars_read, 3);
+        assert_eq!(stats.output_writes, 3);
+        assert_eq!(stats.output_chars_written, 3);
+        assert_eq!(stats.max_stack_depth, 3);
+        // `Bne`, `Ble` and `Jmp` per letter and the final `Bne`.
+        assert_eq!(stats.branches_executed, 3 * 3 + 1);
+    }
+
+    #[test]
+    fn out2_writes_lower_element_first() {
+        let program = parse("push 'a'\npush 'b'\nout2\nexit").unwrap();
+        assert_eq!(run(&program, "").unwrap(), "ab");
+        assert_eq!(exec(&program), Ok(vec![]));
+    }
+
+    #[test]
+    fn out2_needs_two_elements() {
+        let program = [Opcode::Push as u8, b'a', Opcode::Out2 as u8];
+        assert_eq!(exec(&program), Err(VmError::StackUnderflow { at_pc: 2 }));
+    }
+
+    #[test]
+    fn trace_to_string_golden() {
+        let program = [
+            Opcode::Push as u8,
+            2,
+            Opcode::Popa as u8,
+            Opcode::Push as u8,
+            3,
+            Opcode::Trace as u8,
+            7,
+            Opcode::Pusha as u8,
+            Opcode::Add as u8,
+            Opcode::Exit as u8,
+        ];
+        let (_, events) = run_traced(&program, "").unwrap();
+        assert_eq!(
+            trace_to_string(&events),
+            "0000: push 2 stack=[] aux=0\n\
+             0002: popa stack=[2] aux=0\n\
+             0003: push 3 stack=[] aux=2\n\
+             0005: trace 7 stack=[3] aux=2\n\
+             marker 7\n\
+             0007: pusha stack=[3] aux=2\n\
+             0008: add stack=[3, 2] aux=2\n\
+             0009: exit stack=[5] aux=2\n"
+        );
+        assert_eq!(
+            trace_to_flamegraph(&events),
+            "main;add@0008 1\n\
+             main;exit@0009 1\n\
+             main;popa@0002 1\n\
+             main;push@0000 1\n\
+             main;push@0003 1\n\
+             main;pusha@0007 1\n\
+             main;trace@0005 1\n"
+        );
+    }
+
+    #[test]
+    fn heartbeat_returning_false_stops_vm() {
+        let program = parse("loop: nop\njmp loop").unwrap();
+        let mut calls = 0;
+        let mut vm = Vm::new(&program, "", String::new()).with_heartbeat(3, || {
+            calls += 1;
+            calls < 100
+        });
+        assert_eq!(vm.run(), Err(VmError::Cancelled));
+        assert_eq!(vm.stats().instructions_executed, 300);
+        drop(vm);
+        assert_eq!(calls, 100);
+    }
+
+    /// Input that never has a character available.
+    struct StarvedInput;
+
+    impl VmInput for StarvedInput {
+        fn next_u32(&mut self) -> Option<u32> {
+            None
+        }
+
+        fn input_ready(&mut self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn heartbeat_is_not_called_while_waiting() {
+        let program = parse("nop\nnop\nin\nexit").unwrap();
+        let mut calls = 0;
+        let mut vm = Vm::new(&program, StarvedInput, String::new()).with_heartbeat(1, || {
+            calls += 1;
+            true
+        });
+        vm.set_breakpoint(1);
+        assert_eq!(vm.step(), Ok(StepOutcome::Continue));
+        assert_eq!(vm.step(), Ok(StepOutcome::Breakpoint(1)));
+        assert_eq!(vm.step(), Ok(StepOutcome::Continue));
+        for _ in 0..10 {
+            assert_eq!(vm.step(), Ok(StepOutcome::NeedsInput));
+        }
+        drop(vm);
+        assert_eq!(calls, 2);
+    }
+
+    fn select(cond: u32, a: u32, b: u32) -> Result<Vec<u32>, VmError> {
+        let program = crate::asm::ProgramBuilder::new()
+            .push(cond)
+            .push(a)
+            .push(b)
+            .select()
+            .exit()
+            .assemble()
+            .unwrap();
+        exec(&program)
+    }
+
+    #[test]
+    fn select_picks_first_value_if_condition_is_true() {
+        assert_eq!(select(1, 10, 20), Ok(vec![10]));
+        assert_eq!(select(255, 10, 20), Ok(vec![10]));
+    }
+
+    #[test]
+    fn select_picks_second_value_if_condition_is_false() {
+        assert_eq!(select(0, 10, 20), Ok(vec![20]));
+    }
+
+    #[test]
+    fn select_with_equal_values() {
+        assert_eq!(select(0, 7, 7), Ok(vec![7]));
+        assert_eq!(select(1, 7, 7), Ok(vec![7]));
+    }
+
+    #[test]
+    fn select_needs_three_elements() {
+        let program = parse("push 1\npush 2\nselect\nexit").unwrap();
+        assert!(matches!(
+            exec(&program),
+            Err(VmError::StackUnderflow { .. })
+        ));
+    }
+
+    #[test]
+    fn opcode_classes_are_disjoint() {
+        for opcode in Opcode::all() {
+            let classes = [opcode.is_io(), opcode.is_arithmetic(), opcode.is_branch()];
+            let count = classes.iter().filter(|&&c| c).count();
+            assert!(count <= 1, "{} is in {} classes", opcode, count);
+        }
+    }
+
+    #[test]
+    fn opcode_classes() {
+        for opcode in [Opcode::In, Opcode::Out, Opcode::InByte, Opcode::Out2] {
+            assert!(opcode.is_io(), "{}", opcode);
+        }
+        for opcode in [
+            Opcode::Add,
+            Opcode::Sub,
+            Opcode::Mul,
+            Opcode::Neg,
+            Opcode::Xor,
+        ] {
+            assert!(opcode.is_arithmetic(), "{}", opcode);
+        }
+        for opcode in [Opcode::Dup, Opcode::Push, Opcode::Jmp, Opcode::Exit] {
+            assert!(!opcode.is_io() && !opcode.is_arithmetic(), "{}", opcode);
+        }
+    }
+
+    /// Assemble program pushing `lhs` and `rhs`, branching with `opcode` and
+    /// writing `T` if taken, `F` otherwise.
+    fn branch_program(opcode: Opcode, lhs: u32, rhs: u32) -> Vec<u8> {
+        crate::asm::assemble(&[
+            Insn::new(Opcode::Push32).set_value(lhs),
+            Insn::new(Opcode::Push32).set_value(rhs),
+            Insn::new(opcode).set_target("taken"),
+            Insn::new(Opcode::Push).set_value('F' as u32),
+            Insn::new(Opcode::Out),
+            Insn::new(Opcode::Exit),
+            Insn::new(Opcode::Push)
+                .set_value('T' as u32)
+                .set_label("taken"),
+            Insn::new(Opcode::Out),
+            Insn::new(Opcode::Exit),
+        ])
+        .unwrap()
+    }
+
+    fn branch_taken(opcode: Opcode, lhs: u32, rhs: u32) -> bool {
+        run(&branch_program(opcode, lhs, rhs), "").unwrap() == "T"
+    }
+
+    #[test]
+    fn beq_branches_on_equal_values() {
+        assert!(branch_taken(Opcode::Beq, 3, 3));
+        assert!(branch_taken(Opcode::Beq, u32::MAX, u32::MAX));
+        assert!(!branch_taken(Opcode::Beq, 3, 4));
+        assert!(!branch_taken(Opcode::Beq, 4, 3));
+    }
+
+    #[test]
+    fn beq_needs_two_elements() {
+        let program = [Opcode::Push as u8, 1, Opcode::Beq as u8, 0];
+        assert_eq!(exec(&program), Err(VmError::StackUnderflow { at_pc: 2 }));
+    }
+
+    #[test]
+    fn decrypter_output() {
+        let program = crate::asm::assemble(&crate::programs::decrypter()).unwrap();
+        assert_eq!(run(&program, "abcxyz").unwrap(), "egiegi");
+    }
+
+    #[test]
+    fn dup_loop_overflows_stack_limit() {
+        let program = parse("push 1\nloop: dup\njmp loop").unwrap();
+        for max_stack_depth in [1, 16, 256] {
+            let opts = VmOptions {
+                max_stack_depth,
+                ..VmOptions::default()
+            };
+            let e = run_with_options(&program, "", &opts)
+                .unwrap_err()
+                .downcast::<VmError>()
+                .unwrap();
+            assert!(matches!(e, VmError::StackOverflow { .. }), "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn stack_may_fill_up_to_limit() {
+        let opts = VmOptions {
+            max_stack_depth: 3,
+            ..VmOptions::default()
+        };
+        let program = parse("push 1\ndup\ndup\nexit").unwrap();
+        assert!(run_with_options(&program, "", &opts).is_ok());
+        let program = parse("push 1\ndup\ndup\ndup\nexit").unwrap();
+        let e = run_with_options(&program, "", &opts)
+            .unwrap_err()
+            .downcast::<VmError>()
+            .unwrap();
+        assert_eq!(e, VmError::StackOverflow { at_pc: 3 });
+    }
+
+    #[test]
+    fn mul_multiplies() {
+        assert_eq!(binary("mul", 6, 7), Ok(42));
+        assert_eq!(binary("mul", 0, 12345), Ok(0));
+        assert_eq!(binary("mul", 1, 12345), Ok(12345));
+        assert_eq!(binary("mul", 1000, 1000), Ok(1_000_000));
+    }
+
+    #[test]
+    fn mul_wraps_around() {
+        assert_eq!(binary("mul", u32::MAX, 2), Ok(u32::MAX - 1));
+        assert_eq!(binary("mul", 1 << 16, 1 << 16), Ok(0));
+    }
+
+    #[test]
+    fn mul_underflows() {
+        assert_eq!(
+            underflow("mul", 1),
+            Err(VmError::StackUnderflow { at_pc: 2 })
+        );
+        assert_eq!(
+            underflow("mul", 0),
+            Err(VmError::StackUnderflow { at_pc: 0 })
+        );
+    }
+
+    #[test]
+    fn div_and_rem_reject_zero_divisor() {
+        // `push 7` takes 2 bytes and `push 0` only 1.
+        assert_eq!(
+            binary("div", 7, 0),
+            Err(VmError::DivisionByZero { at_pc: 3 })
+        );
+        assert_eq!(
+            binary("rem", 7, 0),
+            Err(VmError::DivisionByZero { at_pc: 3 })
+        );
+        assert_eq!(
+            binary("div", 0, 0),
+            Err(VmError::DivisionByZero { at_pc: 2 })
+        );
+    }
+
+    #[test]
+    fn div_and_rem_are_unsigned() {
+        assert_eq!(binary("div", u32::MAX, 2), Ok(u32::MAX / 2));
+        assert_eq!(binary("rem", u32::MAX, 2), Ok(1));
+        assert_eq!(binary("div", u32::MAX, u32::MAX), Ok(1));
+        assert_eq!(binary("div", 1 << 31, 7), Ok((1 << 31) / 7));
+        assert_eq!(binary("rem", 5, u32::MAX), Ok(5));
+    }
+
+    #[test]
+    fn div_and_rem_satisfy_euclidean_identity() {
+        for a in [0, 1, 7, 26, 255, 256, 65_535, 1_000_003, u32::MAX] {
+            for b in [1, 2, 3, 26, 255, 65_536, u32::MAX] {
+                let quotient = binary("div", a, b).unwrap();
+                let remainder = binary("rem", a, b).unwrap();
+                assert!(remainder < b);
+                assert_eq!(quotient * b + remainder, a, "{} {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn div_and_rem_underflow() {
+        assert_eq!(
+            underflow("div", 1),
+            Err(VmError::StackUnderflow { at_pc: 2 })
+        );
+        assert_eq!(
+            underflow("rem", 1),
+            Err(VmError::StackUnderflow { at_pc: 2 })
+        );
+    }
+
+    #[test]
+    fn bitwise_ops_have_identity_elements() {
+        for x in [0, 1, 0x5a5a_5a5a, 1 << 31, u32::MAX] {
+            assert_eq!(binary("and", x, u32::MAX), Ok(x));
+            assert_eq!(binary("or", x, 0), Ok(x));
+            assert_eq!(binary("xor", x, 0), Ok(x));
+            assert_eq!(binary("xor", x, x), Ok(0));
+        }
+    }
+
+    #[test]
+    fn bitwise_ops_combine_masks() {
+        assert_eq!(binary("and", 0xff00_ff00, 0x0ff0_0ff0), Ok(0x0f00_0f00));
+        assert_eq!(binary("or", 0xff00_ff00, 0x0ff0_0ff0), Ok(0xfff0_fff0));
+        assert_eq!(binary("xor", 0xff00_ff00, 0x0ff0_0ff0), Ok(0xf0f0_f0f0));
+        assert_eq!(binary("and", 0x1234_5678, 0), Ok(0));
+        assert_eq!(binary("or", 0x1234_5678, u32::MAX), Ok(u32::MAX));
+        assert_eq!(binary("xor", 0x1234_5678, u32::MAX), Ok(!0x1234_5678));
+    }
+
+    #[test]
+    fn bitwise_ops_handle_sign_bit() {
+        let sign = 1 << 31;
+        assert_eq!(binary("and", sign, u32::MAX), Ok(sign));
+        assert_eq!(binary("and", sign, i32::MAX as u32), Ok(0));
+        assert_eq!(binary("or", sign, i32::MAX as u32), Ok(u32::MAX));
+        assert_eq!(binary("xor", sign, sign), Ok(0));
+        assert_eq!(binary("xor", sign, 1), Ok(sign | 1));
+    }
+
+    #[test]
+    fn bitwise_ops_underflow() {
+        for mnemonic in ["and", "or", "xor"] {
+            assert_eq!(
+                underflow(mnemonic, 1),
+                Err(VmError::StackUnderflow { at_pc: 2 })
+            );
+        }
+    }
+
+    #[test]
+    fn not_complements_bits() {
+        assert_eq!(unary("not", 0), Ok(u32::MAX));
+        assert_eq!(unary("not", u32::MAX), Ok(0));
+        for x in [1, 0x1234_5678, 1 << 31] {
+            assert_eq!(unary("not", unary("not", x).unwrap()), Ok(x));
+        }
+    }
+
+    #[test]
+    fn neg_negates_twos_complement() {
+        assert_eq!(unary("neg", 0), Ok(0));
+        assert_eq!(unary("neg", 1), Ok(u32::MAX));
+        assert_eq!(unary("neg", 1 << 31), Ok(1 << 31));
+        for x in [1, 42, 0x1234_5678, u32::MAX] {
+            assert_eq!(unary("neg", unary("neg", x).unwrap()), Ok(x));
+        }
+        let program = parse("push 5\nneg\nneg\nexit").unwrap();
+        assert_eq!(exec(&program), Ok(vec![5]));
+    }
+
+    #[test]
+    fn not_and_neg_underflow() {
+        assert_eq!(
+            underflow("not", 0),
+            Err(VmError::StackUnderflow { at_pc: 0 })
+        );
+        assert_eq!(
+            underflow("neg", 0),
+            Err(VmError::StackUnderflow { at_pc: 0 })
+        );
+    }
+
+    #[test]
+    fn shifts_by_zero_keep_value() {
+        for mnemonic in ["shl", "shr", "sar"] {
+            assert_eq!(binary(mnemonic, 0x8000_0001, 0), Ok(0x8000_0001));
+        }
+    }
+
+    #[test]
+    fn shifts_by_31_keep_one_bit() {
+        assert_eq!(binary("shl", 3, 31), Ok(1 << 31));
+        assert_eq!(binary("shr", u32::MAX, 31), Ok(1));
+        assert_eq!(binary("sar", 1 << 31, 31), Ok(u32::MAX));
+        assert_eq!(binary("sar", i32::MAX as u32, 31), Ok(0));
+    }
+
+    #[test]
+    fn shifts_by_32_or_more_saturate() {
+        for amount in [32, 64] {
+            assert_eq!(binary("shl", u32::MAX, amount), Ok(0));
+            assert_eq!(binary("shr", u32::MAX, amount), Ok(0));
+            assert_eq!(binary("sar", 1 << 31, amount), Ok(u32::MAX));
+            assert_eq!(binary("sar", i32::MAX as u32, amount), Ok(0));
+        }
+    }
+
+    #[test]
+    fn shifts_underflow() {
+        for mnemonic in ["shl", "shr", "sar"] {
+            assert_eq!(
+                underflow(mnemonic, 1),
+                Err(VmError::StackUnderflow { at_pc: 2 })
+            );
+        }
+    }
+
+    #[test]
+    fn swap_exchanges_top_elements() {
+        let program = parse("push 1\npush 2\npush 3\nswap\nexit").unwrap();
+        assert_eq!(exec(&program), Ok(vec![1, 3, 2]));
+    }
+
+    #[test]
+    fn swap_accepts_exactly_two_elements() {
+        let program = parse("push 'a'\npush 'b'\nswap\nout\nout\nexit").unwrap();
+        assert_eq!(run(&program, "").unwrap(), "ab");
+    }
+
+    #[test]
+    fn swap_underflows() {
+        assert_eq!(
+            underflow("swap", 1),
+            Err(VmError::StackUnderflow { at_pc: 2 })
+        );
+        assert_eq!(
+            underflow("swap", 0),
+            Err(VmError::StackUnderflow { at_pc: 0 })
+        );
+    }
+
+    #[test]
+    fn drop_discards_top_element() {
+        let program = parse("push 7\ndrop\nexit").unwrap();
+        assert_eq!(run(&program, "").unwrap(), "");
+        assert_eq!(exec(&program), Ok(vec![]));
+    }
+
+    #[test]
+    fn drop_keeps_aux() {
+        let program = parse("push 7\ndrop\nexit").unwrap();
+        let mut vm = VmBuilder::new(&program).initial_aux(9).build();
+        vm.run().unwrap();
+        assert_eq!(vm.state().aux, 9);
+    }
+
+    #[test]
+    fn drop_underflows() {
+        let e = underflow("drop", 0).unwrap_err();
+        assert_eq!(e, VmError::StackUnderflow { at_pc: 0 });
+        assert_eq!(e.to_string(), "stack underflow at pc=0");
+    }
+
+    #[test]
+    fn over_copies_second_element() {
+        let program = parse("push 1\npush 2\npush 3\nover\nexit").unwrap();
+        assert_eq!(exec(&program), Ok(vec![1, 2, 3, 2]));
+    }
+
+    #[test]
+    fn over_accepts_exactly_two_elements() {
+        let program = parse("push 4\npush 5\nover\nexit").unwrap();
+        assert_eq!(exec(&program), Ok(vec![4, 5, 4]));
+    }
+
+    #[test]
+    fn over_underflows() {
+        assert_eq!(
+            underflow("over", 1),
+            Err(VmError::StackUnderflow { at_pc: 2 })
+        );
+        assert_eq!(
+            underflow("over", 0),
+            Err(VmError::StackUnderflow { at_pc: 0 })
+        );
+    }
+
+    #[test]
+    fn nop_only_program_exits_cleanly() {
+        let program = parse(&format!("{}exit", "nop\n".repeat(10))).unwrap();
+        assert_eq!(run(&program, "ignored").unwrap(), "");
+        assert_eq!(exec(&program), Ok(vec![]));
+    }
+
+    #[test]
+    fn nop_does_not_change_decrypter_output() {
+        let decrypter = crate::programs::decrypter();
+        let expected = run(&crate::asm::assemble(&decrypter).unwrap(), "abcxyz").unwrap();
+        for i in 1..decrypter.len() {
+            let mut source = decrypter.clone();
+            source.insert(i, Insn::new(Opcode::Nop));
+            let program = crate::asm::assemble(&source).unwrap();
+            assert_eq!(run(&program, "abcxyz").unwrap(), expected, "{}", i);
+        }
+    }
+
+    #[test]
+    fn subroutine_returns_to_each_caller() {
+        let program =
+            parse("push 'a'\ncall print\npush 'b'\ncall print\nexit\nprint: out\nret").unwrap();
+        assert_eq!(run(&program, "").unwrap(), "ab");
+    }
+
+    #[test]
+    fn unbounded_recursion_overflows_return_stack() {
+        let program = parse("f: call f").unwrap();
+        let e = run(&program, "")
+            .unwrap_err()
+            .downcast::<VmError>()
+            .unwrap();
+        assert_eq!(e, VmError::ReturnStackOverflow { at_pc: 0 });
+
+        let opts = VmOptions {
+            max_call_depth: 2,
+            ..VmOptions::default()
+        };
+        let program = parse("call f\nexit\nf: call g\nret\ng: ret").unwrap();
+        assert!(run_with_options(&program, "", &opts).is_ok());
+        let program = parse("call f\nexit\nf: call g\nret\ng: call h\nret\nh: ret").unwrap();
+        let e = run_with_options(&program, "", &opts)
+            .unwrap_err()
+            .downcast::<VmError>()
+            .unwrap();
+        assert!(matches!(e, VmError::ReturnStackOverflow { .. }), "{:?}", e);
+    }
+
+    #[test]
+    fn ret_without_call_underflows_return_stack() {
+        let program = parse("push 1\nret").unwrap();
+        let e = run(&program, "")
+            .unwrap_err()
+            .downcast::<VmError>()
+            .unwrap();
+        assert_eq!(e, VmError::ReturnStackUnderflow { at_pc: 1 });
+    }
+
+    #[test]
+    fn registers_hold_values_independently() {
+        let mut source = String::new();
+        for r in 0..REGISTER_COUNT {
+            source.push_str(&format!("push {}\npopa {}\n", 10 * r + 1, r));
+        }
+        for r in (0..REGISTER_COUNT).rev() {
+            source.push_str(&format!("pusha {}\n", r));
+        }
+        source.push_str("exit");
+        let program = parse(&source).unwrap();
+        assert_eq!(exec(&program), Ok(vec![71, 61, 51, 41, 31, 21, 11, 1]));
+    }
+
+    #[test]
+    fn unindexed_pusha_and_popa_use_register_0() {
+        let program = parse("push 5\npopa\npush 6\npopa 1\npusha 0\npusha\nexit").unwrap();
+        let mut vm = VmBuilder::new(&program).build();
+        vm.run().unwrap();
+        assert_eq!(vm.state().stack, [5, 5]);
+        assert_eq!(vm.state().aux, 5);
+    }
+
+    #[test]
+    fn register_index_must_exist() {
+        assert!(parse("pusha 8").is_err());
+        let program = [Opcode::Pushr as u8, 8, Opcode::Exit as u8];
+        assert_eq!(
+            exec(&program),
+            Err(VmError::InvalidRegister { at_pc: 0, index: 8 })
+        );
+    }
+
+    #[test]
+    fn load_reads_what_store_wrote() {
+        let mut source = String::new();
+        for address in 0..5 {
+            source.push_str(&format!(
+                "push {}\npush {}\nstore\n",
+                address,
+                100 + address
+            ));
+        }
+        for address in 0..6 {
+            source.push_str(&format!("push {}\nload\n", address));
+        }
+        source.push_str("exit");
+        let program = parse(&source).unwrap();
+        assert_eq!(exec(&program), Ok(vec![100, 101, 102, 103, 104, 0]));
+    }
+
+    #[test]
+    fn heap_boundary_addresses_are_accessible() {
+        let last = VmOptions::default().heap_size - 1;
+        let program = parse(&format!(
+            "push 0\npush 1\nstore\npush {0}\npush 2\nstore\npush 0\nload\npush {0}\nload\nexit",
+            last
+        ))
+        .unwrap();
+        assert_eq!(exec(&program), Ok(vec![1, 2]));
+    }
+
+    #[test]
+    fn out_of_bounds_heap_access_fails() {
+        let size = VmOptions::default().heap_size as u32;
+        let program = parse(&format!("push {}\nload\nexit", size)).unwrap();
+        let e = exec(&program).unwrap_err();
+        assert_eq!(
+            e,
+            VmError::InvalidAddress {
+                at_pc: 3,
+                address: size
+            }
+        );
+        assert_eq!(e.to_string(), "invalid heap address 256 at pc=3");
+
+        let program = parse("push 4294967295\npush 1\nstore\nexit").unwrap();
+        assert!(matches!(
+            exec(&program),
+            Err(VmError::InvalidAddress {
+                address: u32::MAX,
+                ..
+            })
+        ));
+
+        let opts = VmOptions {
+            heap_size: 4,
+            ..VmOptions::default()
+        };
+        let program = parse("push 4\nload\nexit").unwrap();
+        assert!(run_with_options(&program, "", &opts).is_err());
+        let program = parse("push 3\nload\nexit").unwrap();
+        assert!(run_with_options(&program, "", &opts).is_ok());
+    }
+
+    #[test]
+    fn decrypter_trace_has_one_event_per_instruction() {
+        let program = crate::asm::assemble(&crate::programs::decrypter()).unwrap();
+        let (output, events) = run_traced(&program, "abc").unwrap();
+        let (_, stats) = run_with_stats(&program, "abc").unwrap();
+        assert_eq!(output, "egi");
+        assert_eq!(events.len() as u64, stats.instructions_executed);
+    }
+
+    #[test]
+    fn decrypter_trace_states_follow_each_other() {
+        let program = crate::asm::assemble(&crate::programs::decrypter()).unwrap();
+        let mut vm = VmBuilder::new(&program).input("abc").build().with_trace();
+        let mut states_after = Vec::new();
+        while vm.step().unwrap() != StepOutcome::Halted {
+            let state = vm.state();
+            states_after.push((state.stack, state.aux));
+        }
+        let events = vm.trace().unwrap();
+        assert_eq!(events.len(), states_after.len() + 1);
+        for (event, after_previous) in events[1..].iter().zip(&states_after) {
+            let TraceEvent::Exec { stack, aux, .. } = event else {
+                panic!("unexpected {:?}", event);
+            };
+            assert_eq!((stack, aux), (&after_previous.0, &after_previous.1));
+        }
+    }
+
+    #[test]
+    fn runs_from_same_snapshot_match() {
+        let program = crate::asm::assemble(&crate::programs::decrypter()).unwrap();
+        let mut vm = VmBuilder::new(&program).input("abc").build();
+        vm.step().unwrap();
+        vm.step().unwrap();
+        let snap = vm.snapshot();
+        vm.run().unwrap();
+        let first = vm.state().output_so_far;
+
+        // Input cannot be rewound, so replay the snapshot on a fresh VM fed
+        // the same input.
+        let mut vm = VmBuilder::new(&program).input("abc").build();
+        vm.restore(snap.clone());
+        assert_eq!(vm.snapshot(), snap);
+        vm.run().unwrap();
+        assert_eq!(vm.state().output_so_far, first);
+        assert_eq!(first, "egi");
+    }
+
+    #[test]
+    fn restore_discards_output_written_since_snapshot() {
+        let program = printer("hello");
+        let mut vm = VmBuilder::new(&program).build();
+        for _ in 0..4 {
+            vm.step().unwrap();
+        }
+        let snap = vm.snapshot();
+        vm.run().unwrap();
+        assert_eq!(vm.state().output_so_far, "hello");
+
+        vm.restore(snap.clone());
+        assert_eq!(vm.state().output_so_far, "he");
+        vm.run().unwrap();
+        assert_eq!(vm.state().output_so_far, "hello");
+        vm.restore(snap);
+        vm.run().unwrap();
+        assert_eq!(vm.state().output_so_far, "hello");
+    }
+
+    /// Input replaying scripted values, including non-characters.
+    struct ScriptedInput(Vec<u32>);
+
+    impl VmInput for ScriptedInput {
+        fn next_u32(&mut self) -> Option<u32> {
+            (!self.0.is_empty()).then(|| self.0.remove(0))
+        }
+    }
+
+    /// Output accepting `capacity` values then failing.
+    struct FullOutput {
+        written: Vec<u32>,
+        capacity: usize,
+    }
+
+    impl VmOutput for FullOutput {
+        fn write_u32(&mut self, v: u32) -> Result<(), OutputError> {
+            if self.written.len() == self.capacity {
+                return Err(OutputError::new("device full"));
+            }
+            self.written.push(v);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn vm_reads_from_custom_input() {
+        let program = parse("in\nin\nin\nexit").unwrap();
+        let mut vm = Vm::new(&program, ScriptedInput(vec![1, u32::MAX]), String::new());
+        vm.run().unwrap();
+        assert_eq!(vm.state().stack, [1, u32::MAX, 0]);
+        assert!(vm.input().0.is_empty());
+    }
+
+    #[test]
+    fn vm_writes_to_custom_output() {
+        let program = printer("hi");
+        let mut output = FullOutput {
+            written: Vec::new(),
+            capacity: 2,
+        };
+        Vm::new(&program, "", &mut output).run().unwrap();
+        assert_eq!(output.written, ['h' as u32, 'i' as u32]);
+    }
+
+    #[test]
+    fn output_errors_stop_vm() {
+        let program = printer("abc");
+        let output = FullOutput {
+            written: Vec::new(),
+            capacity: 2,
+        };
+        let mut vm = Vm::new(&program, "", output);
+        let e = vm.run().unwrap_err();
+        // `printer()` pushes then writes each character on 3 bytes.
+        assert_eq!(
+            e,
+            VmError::Output {
+                at_pc: 8,
+                error: OutputError::new("device full")
+            }
+        );
+        assert_eq!(e.to_string(), "output failed at pc=8: device full");
+        assert_eq!(vm.output().written, ['a' as u32, 'b' as u32]);
+    }
+
+    #[test]
+    fn run_wraps_string_channels() {
+        let program = crate::asm::assemble(&crate::programs::decrypter()).unwrap();
+        let mut output = String::new();
+        run_with_io(&program, "abc", &mut output).unwrap();
+        assert_eq!(output, run(&program, "abc").unwrap());
+    }
+
+    #[test]
+    fn all_bytes_round_trip_through_in_byte_and_out_byte() {
+        let bytes: Vec<u8> = (0..=u8::MAX).collect();
+        let program = parse(&format!("{}exit", "inbyte\noutbyte\n".repeat(256))).unwrap();
+        let (output, output_bytes) =
+            run_with_bytes(&program, "", &bytes, &VmOptions::default()).unwrap();
+        assert_eq!(output, "");
+        assert_eq!(output_bytes, bytes);
+    }
+
+    #[test]
+    fn in_byte_pushes_zero_at_end_of_input() {
+        let program = parse("inbyte\ninbyte\nexit").unwrap();
+        let mut vm = VmBuilder::new(&program).build().with_byte_input(&[0xff]);
+        vm.run().unwrap();
+        assert_eq!(vm.state().stack, [0xff, 0]);
+    }
+
+    #[test]
+    fn out_byte_rejects_values_above_255() {
+        let program = parse("push 256\noutbyte\nexit").unwrap();
+        assert_eq!(exec(&program), Err(VmError::InvalidByte(256)));
+    }
+
+    #[test]
+    fn signed_branches_order_negative_values_first() {
+        let minus_one = u32::MAX;
+        assert!(branch_taken(Opcode::Blts, minus_one, 1));
+        assert!(!branch_taken(Opcode::Blt, minus_one, 1));
+        assert!(branch_taken(Opcode::Bgts, 1, minus_one));
+        assert!(!branch_taken(Opcode::Bgt, 1, minus_one));
+        assert!(branch_taken(Opcode::Bles, minus_one, 0));
+        assert!(!branch_taken(Opcode::Ble, minus_one, 0));
+        assert!(branch_taken(Opcode::Bges, 0, minus_one));
+        assert!(!branch_taken(Opcode::Bgts, minus_one, 0));
+    }
+
+    #[test]
+    fn signed_branches_at_sign_bit_boundary() {
+        let min = i32::MIN as u32;
+        let max = i32::MAX as u32;
+        assert!(branch_taken(Opcode::Blts, min, max));
+        assert!(branch_taken(Opcode::Blt, max, min));
+        assert!(branch_taken(Opcode::Bgts, max, min));
+        assert!(!branch_taken(Opcode::Bgts, min, max));
+        assert!(branch_taken(Opcode::Bles, min, min));
+        assert!(branch_taken(Opcode::Bges, max, max));
+        assert!(!branch_taken(Opcode::Bges, min, max));
+        assert!(!branch_taken(Opcode::Bles, max, min));
+    }
+
+    #[test]
+    fn signed_branches_agree_with_unsigned_ones_below_sign_bit() {
+        for (lhs, rhs) in [(0, 0), (0, 1), (1, 0), (5, i32::MAX as u32)] {
+            assert_eq!(
+                branch_taken(Opcode::Blts, lhs, rhs),
+                branch_taken(Opcode::Blt, lhs, rhs)
+            );
+            assert_eq!(
+                branch_taken(Opcode::Bgts, lhs, rhs),
+                branch_taken(Opcode::Bgt, lhs, rhs)
+            );
+            assert_eq!(
+                branch_taken(Opcode::Bles, lhs, rhs),
+                branch_taken(Opcode::Ble, lhs, rhs)
+            );
+        }
+    }
+
+    #[test]
+    fn dup2_copies_top_pair() {
+        let program = parse("push 1\npush 2\npush 3\ndup2\nexit").unwrap();
+        assert_eq!(exec(&program), Ok(vec![1, 2, 3, 2, 3]));
+    }
+
+    #[test]
+    fn dup2_accepts_exactly_two_elements() {
+        let program = parse("push 6\npush 3\ndup2\nexit").unwrap();
+        assert_eq!(exec(&program), Ok(vec![6, 3, 6, 3]));
+    }
+
+    #[test]
+    fn dup2_underflows() {
+        assert_eq!(
+            underflow("dup2", 1),
+            Err(VmError::StackUnderflow { at_pc: 2 })
+        );
+        assert_eq!(
+            underflow("dup2", 0),
+            Err(VmError::StackUnderflow { at_pc: 0 })
+        );
+    }
+
+    #[test]
+    fn builder_seeds_aux_without_preamble() {
+        let program = parse("pusha\nout\nexit").unwrap();
+        let output = VmBuilder::new(&program).initial_aux('z' as u32).run();
+        assert_eq!(output.unwrap(), "z");
+    }
+
+    #[test]
+    fn builder_seeds_stack_and_input() {
+        let program = parse("in\nadd\nout\nexit").unwrap();
+        let builder = VmBuilder::new(&program)
+            .input("a")
+            .initial_stack(vec![7, 1]);
+        assert_eq!(builder.run().unwrap(), "b");
+    }
+
+    #[test]
+    fn builder_enforces_limits_only_when_set() {
+        let program = parse("push 1\npush 2\npush 3\nexit").unwrap();
+        assert!(VmBuilder::new(&program).run().is_ok());
+        let e = VmBuilder::new(&program)
+            .max_stack_depth(2)
+            .run()
+            .unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<VmError>(),
+            Some(&VmError::StackOverflow { at_pc: 3 })
+        );
+        let e = VmBuilder::new(&program).max_steps(2).run().unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<VmError>(),
+            Some(&VmError::StepLimitExceeded { limit: 2 })
+        );
+    }
+
+    /// Step VM until it halts and count how many times it stops at `pc`.
+    fn count_breakpoints(vm: &mut Vm<&str, String>, pc: usize) -> usize {
+        let mut hits = 0;
+        loop {
+            match vm.step().unwrap() {
+                StepOutcome::Halted => return hits,
+                StepOutcome::Breakpoint(at) => {
+                    assert_eq!(at, pc);
+                    hits += 1;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn breakpoint_fires_once_per_loop_iteration() {
+        let program = parse("push 3\nloop: dec\ndup\nbnez loop\nexit").unwrap();
+        let mut vm = VmBuilder::new(&program).build();
+        vm.set_breakpoint(2);
+        assert_eq!(count_breakpoints(&mut vm, 2), 3);
+        assert_eq!(vm.state().stack, [0]);
+    }
+
+    #[test]
+    fn cleared_breakpoint_does_not_fire() {
+        let program = parse("push 3\nloop: dec\ndup\nbnez loop\nexit").unwrap();
+        let mut vm = VmBuilder::new(&program).build();
+        vm.set_breakpoint(2);
+        assert_eq!(vm.step(), Ok(StepOutcome::Continue));
+        assert_eq!(vm.step(), Ok(StepOutcome::Breakpoint(2)));
+        vm.clear_breakpoint(2);
+        assert_eq!(count_breakpoints(&mut vm, 2), 0);
+    }
+
+    #[test]
+    fn patch_selects_jmp_target_from_input() {
+        let program = [
+            Opcode::In as u8,
+            Opcode::Push as u8,
+            b'0',
+            Opcode::Sub as u8,
+            Opcode::Push as u8,
+            4, // handler size
+            Opcode::Mul as u8,
+            Opcode::Push as u8,
+            16, // first handler
+            Opcode::Add as u8,
+            Opcode::Push as u8,
+            15, // `Jmp` operand
+            Opcode::Swap as u8,
+            Opcode::Patch as u8,
+            Opcode::Jmp as u8,
+            0,
+            Opcode::Push as u8,
+            b'A',
+            Opcode::Out as u8,
+            Opcode::Exit as u8,
+            Opcode::Push as u8,
+            b'B',
+            Opcode::Out as u8,
+            Opcode::Exit as u8,
+        ];
+        assert_eq!(run(&program, "0").unwrap(), "A");
+        assert_eq!(run(&program, "1").unwrap(), "B");
+        assert_eq!(program[15], 0);
+    }
+
+    #[test]
+    fn patch_outside_program_fails() {
+        let program = parse("push 200\npush 1\npatch\nexit").unwrap();
+        let e = exec(&program).unwrap_err();
+        assert_eq!(
+            e,
+            VmError::InvalidCodeAddress {
+                at_pc: 3,
+                address: 200
+            }
+        );
+        let program = parse("push 0\npush 256\npatch\nexit").unwrap();
+        assert_eq!(exec(&program), Err(VmError::InvalidByte(256)));
+    }
+
+    #[test]
+    fn inc_and_dec_wrap() {
+        assert_eq!(unary("inc", 41), Ok(42));
+        assert_eq!(unary("inc", u32::MAX), Ok(0));
+        assert_eq!(unary("dec", 42), Ok(41));
+        assert_eq!(unary("dec", 0), Ok(u32::MAX));
+    }
+
+    #[test]
+    fn inc_and_dec_underflow() {
+        for mnemonic in ["inc", "dec"] {
+            assert_eq!(
+                underflow(mnemonic, 0),
+                Err(VmError::StackUnderflow { at_pc: 0 })
+            );
+        }
+    }
+
+    #[test]
+    fn pushi_sign_extends() {
+        for (value, expected) in [(-1, 0xffff_ffff), (-128, 0xffff_ff80), (127, 0x0000_007f)] {
+            let source = [
+                crate::asm::Insn::new(Opcode::Pushi).set_value_i8(value),
+                crate::asm::Insn::new(Opcode::Exit),
+            ];
+            let program = crate::asm::assemble(&source).unwrap();
+            assert_eq!(
+                program,
+                [Opcode::Pushi as u8, value as u8, Opcode::Exit as u8]
+            );
+            assert_eq!(exec(&program), Ok(vec![expected]));
+            let program = parse(&format!("pushi {}\nexit", value)).unwrap();
+            assert_eq!(exec(&program), Ok(vec![expected]));
+        }
+    }
+
+    #[test]
+    fn outpeek_keeps_stack_depth() {
+        let program = parse("push 'x'\npush 'y'\noutpeek\nexit").unwrap();
+        let mut vm = VmBuilder::new(&program).build();
+        vm.run().unwrap();
+        assert_eq!(vm.state().stack, ['x' as u32, 'y' as u32]);
+        assert_eq!(vm.output(), "y");
+    }
+
+    #[test]
+    fn outpeek_underflows_like_out() {
+        assert_eq!(underflow("outpeek", 0), underflow("out", 0));
+        assert_eq!(
+            underflow("outpeek", 0),
+            Err(VmError::StackUnderflow { at_pc: 0 })
+        );
+    }
+
+    #[test]
+    fn inor_pushes_sentinel_only_at_end_of_input() {
+        let program = parse("inor 255\ninor 255\ninor 255\ninor 10\nin\nexit").unwrap();
+        let mut vm = VmBuilder::new(&program).input("ab").build();
+        vm.run().unwrap();
+        assert_eq!(vm.state().stack, ['a' as u32, 'b' as u32, 255, 10, 0]);
+
+        let mut vm = VmBuilder::new(&program).input("\u{ff}bcde").build();
+        vm.run().unwrap();
+        assert_eq!(
+            vm.state().stack,
+            [0xff, 'b' as u32, 'c' as u32, 'd' as u32, 'e' as u32]
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn run_async_matches_run() {
+        let program = crate::asm::assemble(&crate::programs::decrypter()).unwrap();
+        for input in ["", "abc", "xyzé€", "thequickbrownfoxjumpsoverthelazydog"] {
+            let reader = tokio::io::BufReader::new(input.as_bytes());
+            let output = run_async(&program, reader).await.unwrap();
+            assert_eq!(output, run(&program, input).unwrap());
+        }
+    }
+
+    #[test]
+    fn call_extern_is_not_an_extension_opcode() {
+        assert!(!EXTENSION_OPCODES.contains(&(Opcode::CallExtern as u8)));
+        assert_eq!(Opcode::try_from(255), Ok(Opcode::CallExtern));
+        let program = [Opcode::CallExtern as u8, 0, 0, Opcode::Exit as u8];
+        assert_eq!(
+            exec(&program),
+            Err(VmError::Unimplemented(Opcode::CallExtern))
+        );
+    }
+
+    #[test]
+    fn display_is_lowercase_mnemonic() {
+        let table = [
+            (Opcode::In, "in"),
+            (Opcode::Out, "out"),
+            (Opcode::Dup, "dup"),
+            (Opcode::Add, "add"),
+            (Opcode::Sub, "sub"),
+            (Opcode::Bne, "bne"),
+            (Opcode::Blt, "blt"),
+            (Opcode::Exit, "exit"),
+            (Opcode::Push, "push"),
+            (Opcode::Jmp, "jmp"),
+            (Opcode::Beq, "beq"),
+            (Opcode::Pusha, "pusha"),
+            (Opcode::Popa, "popa"),
+            (Opcode::Bgt, "bgt"),
+            (Opcode::Ble, "ble"),
+            #[cfg(feature = "debug-opcodes")]
+            (Opcode::Regs, "regs"),
+            #[cfg(feature = "debug-opcodes")]
+            (Opcode::Assert, "assert"),
+            (Opcode::Trace, "trace"),
+            (Opcode::Out2, "out2"),
+            (Opcode::Select, "select"),
+            (Opcode::Pushw, "pushw"),
+            (Opcode::Jmpw, "jmpw"),
+            (Opcode::Bnew, "bnew"),
+            (Opcode::Beqw, "beqw"),
+            (Opcode::Bltw, "bltw"),
+            (Opcode::Bgtw, "bgtw"),
+            (Opcode::Blew, "blew"),
+            (Opcode::Mul, "mul"),
+            (Opcode::Div, "div"),
+            (Opcode::Rem, "rem"),
+            (Opcode::And, "and"),
+            (Opcode::Or, "or"),
+            (Opcode::Xor, "xor"),
+            (Opcode::Not, "not"),
+            (Opcode::Neg, "neg"),
+            (Opcode::Shl, "shl"),
+            (Opcode::Shr, "shr"),
+            (Opcode::Sar, "sar"),
+            (Opcode::Swap, "swap"),
+            (Opcode::Drop, "drop"),
+            (Opcode::Over, "over"),
+            (Opcode::Nop, "nop"),
+            (Opcode::Call, "call"),
+            (Opcode::Ret, "ret"),
+            (Opcode::Callw, "callw"),
+            (Opcode::Pushr, "pushr"),
+            (Opcode::Popr, "popr"),
+            (Opcode::Load, "load"),
+            (Opcode::Store, "store"),
+            (Opcode::InByte, "inbyte"),
+            (Opcode::OutByte, "outbyte"),
+            (Opcode::Push32, "push32"),
+            (Opcode::Blts, "blts"),
+            (Opcode::Bgts, "bgts"),
+            (Opcode::Bles, "bles"),
+            (Opcode::Bges, "bges"),
+            (Opcode::JmpRel, "jmprel"),
+            (Opcode::BneRel, "bnerel"),
+            (Opcode::BeqRel, "beqrel"),
+            (Opcode::BltRel, "bltrel"),
+            (Opcode::BgtRel, "bgtrel"),
+            (Opcode::BleRel, "blerel"),
+            (Opcode::BltsRel, "bltsrel"),
+            (Opcode::BgtsRel, "bgtsrel"),
+            (Opcode::BlesRel, "blesrel"),
+            (Opcode::BgesRel, "bgesrel"),
+            (Opcode::Dup2, "dup2"),
+            (Opcode::InN, "inn"),
+            (Opcode::OutN, "outn"),
+            (Opcode::Push0, "push0"),
+            (Opcode::Push1, "push1"),
+            (Opcode::MacroCall, "macrocall"),
+            (Opcode::Beqz, "beqz"),
+            (Opcode::Beqzw, "beqzw"),
+            (Opcode::BeqzRel, "beqzrel"),
+            (Opcode::Patch, "patch"),
+            (Opcode::Inc, "inc"),
+            (Opcode::Dec, "dec"),
+            (Opcode::Pushi, "pushi"),
+            (Opcode::OutPeek, "outpeek"),
+            (Opcode::InOr, "inor"),
+            (Opcode::Rot, "rot"),
+            (Opcode::CallExtern, "callextern"),
+        ];
+        for (opcode, mnemonic) in table {
+            assert_eq!(opcode.to_string(), mnemonic);
+            assert_eq!(format!("{:<8}|", opcode), format!("{:<8}|", mnemonic));
+        }
+        // A decodable opcode missing from the table fails here.
+        for byte in 0..=u8::MAX {
+            if let Ok(opcode) = Opcode::try_from(byte) {
+                assert!(table.iter().any(|&(o, _)| o == opcode), "{:?}", opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_operands_fail_for_every_opcode() {
+        assert!(run(&[Opcode::Push as u8], "").is_err());
+        for byte in 0..=u8::MAX {
+            // `CallExtern` is a linker placeholder the VM does not execute.
+            let Ok(opcode) = Opcode::try_from(byte) else {
+                continue;
+            };
+            if opcode == Opcode::CallExtern {
+                continue;
+            }
+            for len in 0..opcode.operand_len() {
+                let mut program = vec![byte];
+                program.resize(1 + len, 0);
+                // Branches pop their operands before reading their target.
+                let mut vm = VmBuilder::new(&program).initial_stack(vec![1; 4]).build();
+                assert_eq!(
+                    vm.run(),
+                    Err(VmError::UnexpectedEndOfProgram { pc: 0, opcode }),
+                    "{} with {} operand bytes",
+                    opcode,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rot_moves_third_element_to_top() {
+        let program = parse("push 1\npush 2\npush 3\npush 4\nrot\nexit").unwrap();
+        assert_eq!(exec(&program), Ok(vec![1, 3, 4, 2]));
+        let program = parse("push 1\npush 2\npush 3\nrot\nexit").unwrap();
+        assert_eq!(exec(&program), Ok(vec![2, 3, 1]));
+    }
+
+    #[test]
+    fn rot_underflows() {
+        assert_eq!(
+            underflow("rot", 0),
+            Err(VmError::StackUnderflow { at_pc: 0 })
+        );
+        assert_eq!(
+            underflow("rot", 2),
+            Err(VmError::StackUnderflow { at_pc: 4 })
+        );
+    }
+
+    #[test]
+    fn rot_reorders_operands_without_aux() {
+        // (a - b) + (b - c) with a = 10, b = 4 and c = 1.
+        let program =
+            parse("push 10\npush 4\npush 1\nrot\nrot\ndup\nrot\nrot\nsub\nrot\nsub\nadd\nexit")
+                .unwrap();
+        assert!(!program.contains(&(Opcode::Pusha as u8)));
+        assert!(!program.contains(&(Opcode::Popa as u8)));
+        assert_eq!(exec(&program), Ok(vec![9]));
+    }
+
+    /// Does branch `mnemonic` taking one operand jump when `top` is pushed?
+    fn zero_branch_taken(mnemonic: &str, top: u32) -> bool {
+        let program = parse(&format!(
+            "push {}\n{} taken\npush 'F'\nout\nexit\ntaken: push 'T'\nout\nexit",
+            top, mnemonic
+        ))
+        .unwrap();
+        let mut vm = VmBuilder::new(&program).build();
+        vm.run().unwrap();
+        assert!(vm.state().stack.is_empty());
+        vm.output() == "T"
+    }
+
+    #[test]
+    fn beqz_and_bnez_compare_top_with_zero() {
+        for mnemonic in ["beqz", "BEQZ", "beqzw", "beqzrel"] {
+            assert!(zero_branch_taken(mnemonic, 0), "{}", mnemonic);
+            assert!(!zero_branch_taken(mnemonic, 1), "{}", mnemonic);
+            assert!(!zero_branch_taken(mnemonic, u32::MAX), "{}", mnemonic);
+        }
+        for mnemonic in ["bnez", "BNEZ", "bne", "BNE"] {
+            assert!(!zero_branch_taken(mnemonic, 0), "{}", mnemonic);
+            assert!(zero_branch_taken(mnemonic, 7), "{}", mnemonic);
+        }
+    }
+
+    #[test]
+    fn bnez_assembles_like_bne() {
+        assert_eq!(Opcode::Bnez as u8, Opcode::Bne as u8);
+        let source = "push 3\nloop: dec\ndup\n{} loop\nexit";
+        assert_eq!(
+            parse(&source.replace("{}", "bnez")).unwrap(),
+            parse(&source.replace("{}", "bne")).unwrap()
+        );
+    }
+
+    #[test]
+    fn reset_reruns_program_on_new_input() {
+        let program = crate::asm::assemble(&crate::programs::decrypter()).unwrap();
+        let mut vm = VmBuilder::new(&program).input("zzz").build();
+        vm.run().unwrap();
+        for input in ["", "abc", "thequickbrownfoxjumpsoverthelazydog"] {
+            vm.reset(input);
+            let state = vm.state();
+            assert_eq!(
+                (state.pc, state.aux, state.output_so_far.as_str()),
+                (0, 0, "")
+            );
+            assert!(state.stack.is_empty());
+            vm.run().unwrap();
+            assert_eq!(vm.output(), &run(&program, input).unwrap());
+        }
+    }
+
+    #[test]
+    fn reset_undoes_patches() {
+        let program = parse("push 'A'\nout\npush 1\npush 'B'\npatch\nexit").unwrap();
+        let mut vm = VmBuilder::new(&program).build();
+        vm.run().unwrap();
+        assert_eq!(vm.output(), "A");
+        vm.reset("");
+        vm.run().unwrap();
+        assert_eq!(vm.output(), "A");
+    }
+
+    #[test]
+    fn fixed_vm_matches_run() {
+        let program = crate::asm::assemble(&crate::programs::decrypter()).unwrap();
+        let mut vm = VmDefault::new();
+        for input in ["", "abc", "thequickbrownfoxjumpsoverthelazydog"] {
+            let mut output = String::new();
+            vm.run(&program, input, &mut output).unwrap();
+            assert_eq!(output, run(&program, input).unwrap());
+        }
+    }
+
+    #[test]
+    fn fixed_vm_overflows_its_array() {
+        let program = parse("push 1\npush 2\npush 3\nexit").unwrap();
+        let mut vm = VmFixed::<3>::new();
+        vm.run(&program, "", String::new()).unwrap();
+        assert_eq!(vm.stack(), [1, 2, 3]);
+
+        let mut vm = VmFixed::<2>::new();
+        assert_eq!(
+            vm.run(&program, "", String::new()),
+            Err(VmError::StackOverflow { at_pc: 3 })
+        );
+        assert_eq!(vm.stack(), [1, 2]);
+    }
+
+    /// Run program copying input to output with `encoding`.
+    fn echo(input: &[u8], encoding: Encoding) -> anyhow::Result<Vec<u8>> {
+        let program = parse("loop: in\ndup\nbeqz end\nout\njmp loop\nend: exit").unwrap();
+        let opts = VmOptions {
+            encoding,
+            ..VmOptions::default()
+        };
+        run_encoded(&program, input, &opts)
+    }
+
+    #[test]
+    fn utf8_is_default_encoding() {
+        assert_eq!(VmOptions::default().encoding, Encoding::Utf8);
+        assert_eq!(
+            echo("hé€".as_bytes(), Encoding::Utf8).unwrap(),
+            "hé€".as_bytes()
+        );
+        let e = echo(&[b'h', 0xe9], Encoding::Utf8).unwrap_err();
+        assert!(
+            e.to_string().starts_with("input is not valid UTF-8"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn latin1_and_raw_bytes_pass_every_byte() {
+        let bytes: Vec<u8> = (1..=u8::MAX).collect();
+        assert_eq!(echo(&bytes, Encoding::Latin1).unwrap(), bytes);
+        assert_eq!(echo(&bytes, Encoding::RawByte).unwrap(), bytes);
+    }
+
+    #[test]
+    fn encodings_differ_on_values_above_a_byte() {
+        let program = parse("push 321\nout\nexit").unwrap();
+        let encoded = |encoding| {
+            let opts = VmOptions {
+                encoding,
+                ..VmOptions::default()
+            };
+            run_encoded(&program, &[], &opts)
+        };
+        assert_eq!(encoded(Encoding::Utf8).unwrap(), "Ł".as_bytes());
+        let e = encoded(Encoding::Latin1).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<VmError>(),
+            Some(&VmError::InvalidByte(321))
+        );
+        assert_eq!(encoded(Encoding::RawByte).unwrap(), [321u32 as u8]);
+    }
+
+    #[test]
+    fn every_opcode_byte_runs_or_fails_cleanly() {
+        for byte in 0..=u8::MAX {
+            let program = [byte, 1, 1, 1, 1, Opcode::Exit as u8];
+            let mut vm = VmBuilder::new(&program)
+                .initial_stack(vec![1; 8])
+                .max_steps(100)
+                .build();
+            let result = vm.run();
+            match Opcode::try_from(byte) {
+                Err(_) => assert_eq!(result, Err(VmError::InvalidOpcode(byte))),
+                Ok(Opcode::CallExtern) => {
+                    assert_eq!(result, Err(VmError::Unimplemented(Opcode::CallExtern)))
+                }
+                Ok(_) => (),
+            }
+        }
+    }
+
+    #[test]
+    fn assembler_only_opcodes_are_not_executed() {
+        assert!(Opcode::try_from(Opcode::MacroCall as u8).is_err());
+        let e = exec(&[Opcode::CallExtern as u8, 0, 0]).unwrap_err();
+        assert_eq!(e.to_string(), "unimplemented opcode CallExtern");
+    }
+
+    #[test]
+    fn trace_markers_follow_their_instruction() {
+        let program = parse("push 5\ntrace 7\ndrop\ntrace 9\nexit").unwrap();
+        let (_, events) = run_traced(&program, "").unwrap();
+        let summary: Vec<_> = events
+            .iter()
+            .map(|event| match event {
+                TraceEvent::Exec { pc, .. } => format!("exec {}", pc),
+                TraceEvent::Marker(marker) => format!("marker {}", marker),
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            ["exec 0", "exec 2", "marker 7", "exec 4", "exec 5", "marker 9", "exec 7"]
+        );
+    }
+
+    #[test]
+    fn trace_without_tracing_is_a_nop() {
+        let program = parse("push 5\ntrace 7\nexit").unwrap();
+        assert_eq!(exec(&program), Ok(vec![5]));
+    }
+
+    #[test]
+    fn one_byte_push_reports_missing_operand() {
+        let program = [Opcode::Push as u8];
+        let e = exec(&program).unwrap_err();
+        assert_eq!(
+            e,
+            VmError::UnexpectedEndOfProgram {
+                pc: 0,
+                opcode: Opcode::Push
+            }
+        );
+        assert_eq!(
+            e.to_string(),
+            "opcode PUSH at pc=0 requires an operand byte but the program ends at byte 0"
+        );
+    }
+
+    /// Address branch operands of `step_once()` programs jump to.
+    const STEP_TARGET: usize = 12;
+
+    /// Observable effects of executing a single instruction.
+    #[derive(Debug, PartialEq)]
+    struct StepEffects {
+        result: Result<StepOutcome, VmError>,
+        snapshot: VmSnapshot,
+        output: String,
+        byte_output: Vec<u8>,
+        channel_output: String,
+        program: Vec<u8>,
+    }
+
+    /// Execute `opcode` once on `stack`, with "ab" as input and 7 as binary
+    /// input.  Branches target [`STEP_TARGET`] and other operands are 0.
+    fn step_once(opcode: Opcode, stack: &[u32]) -> StepEffects {
+        let len = opcode.operand_len();
+        let mut program = vec![opcode as u8];
+        let operand = match opcode.is_branch() {
+            true if opcode.is_relative() => (STEP_TARGET - 1 - len) as u32,
+            true => STEP_TARGET as u32,
+            false => 0,
+        };
+        program.extend(&operand.to_le_bytes()[..len]);
+        program.resize(STEP_TARGET, Opcode::Nop as u8);
+        program.push(Opcode::Exit as u8);
+
+        let opts = VmOptions {
+            heap_size: 16,
+            ..VmOptions::default()
+        };
+        let mut vm = VmBuilder::new(&program)
+            .input("ab")
+            .initial_stack(stack.to_vec())
+            .build()
+            .with_options(&opts)
+            .with_byte_input(&[7]);
+        let result = vm.step();
+        StepEffects {
+            result,
+            snapshot: vm.snapshot(),
+            output: vm.output().clone(),
+            byte_output: vm.byte_output().to_vec(),
+            channel_output: vm.channel_output(1).to_string(),
+            program: vm.program.to_vec(),
+        }
+    }
+
+    #[test]
+    fn attributes_match_observed_behavior() {
+        let stacks = [[0, 0, 0], [1, 2, 3], [3, 2, 1], [5, 5, 5]];
+        for opcode in Opcode::all() {
+            if opcode == Opcode::CallExtern {
+                continue;
+            }
+            let has = |attribute| opcode.attributes().contains(attribute);
+            let effects: Vec<_> = stacks.iter().map(|s| step_once(opcode, s)).collect();
+            let continued: Vec<_> = effects
+                .iter()
+                .filter(|e| matches!(e.result, Ok(outcome) if outcome != StepOutcome::Halted))
+                .collect();
+
+            let jumps = continued.iter().any(|e| e.snapshot.pc == STEP_TARGET);
+            assert_eq!(has(OpcodeAttributes::IS_BRANCH), jumps, "{}", opcode);
+
+            // Calls resume at the next instruction once they return.
+            let next = 1 + opcode.operand_len();
+            let falls_through = continued
+                .iter()
+                .any(|e| e.snapshot.pc == next || e.snapshot.return_stack.last() == Some(&next));
+            assert_eq!(
+                has(OpcodeAttributes::IS_TERMINATOR),
+                !falls_through,
+                "{}",
+                opcode
+            );
+
+            let reads = effects.iter().zip(&stacks).any(|(e, stack)| {
+                e.snapshot.stack.len() == stack.len() + 1
+                    && matches!(e.snapshot.stack.last(), Some(7 | 97))
+            });
+            assert_eq!(has(OpcodeAttributes::READS_INPUT), reads, "{}", opcode);
+
+            let writes = effects.iter().any(|e| {
+                !e.output.is_empty() || !e.byte_output.is_empty() || !e.channel_output.is_empty()
+            });
+            assert_eq!(has(OpcodeAttributes::WRITES_OUTPUT), writes, "{}", opcode);
+
+            let modifies_aux = effects
+                .iter()
+                .any(|e| e.snapshot.regs != [0; REGISTER_COUNT]);
+            assert_eq!(
+                has(OpcodeAttributes::MODIFIES_AUX),
+                modifies_aux,
+                "{}",
+                opcode
+            );
+
+            // Swapping the two topmost elements changes nothing.
+            let commutes = [(1, 2), (3, 7), (0, 5)]
+                .iter()
+                .all(|&(a, b)| step_once(opcode, &[9, a, b]) == step_once(opcode, &[9, b, a]));
+            assert_eq!(
+                has(OpcodeAttributes::IS_COMMUTATIVE),
+                commutes,
+                "{}",
+                opcode
+            );
+        }
+    }
+
+    #[test]
+    fn single_stepping_decrypter_matches_run() {
+        let program = crate::asm::assemble(&crate::programs::decrypter()).unwrap();
+        for input in ["", "abc", "thequickbrownfoxjumpsoverthelazydog"] {
+            let mut vm = VmBuilder::new(&program).input(input).build();
+            let mut output = String::new();
+            loop {
+                match vm.step().unwrap() {
+                    StepOutcome::Halted => break,
+                    StepOutcome::WroteOutput(ch) => output.push(ch),
+                    StepOutcome::Continue => (),
+                    outcome => panic!("unexpected {:?}", outcome),
+                }
+            }
+            assert_eq!(output, run(&program, input).unwrap());
+            assert_eq!(vm.output(), &output);
+            assert_eq!(vm.step(), Ok(StepOutcome::Halted));
+        }
+    }
+
+    #[test]
+    fn state_agrees_with_trace_while_stepping() {
+        let program = crate::asm::assemble(&crate::programs::decrypter()).unwrap();
+        let mut vm = VmBuilder::new(&program).input("ab").build().with_trace();
+        for _ in 0..20 {
+            let before = vm.state();
+            vm.step().unwrap();
+            let Some(TraceEvent::Exec { pc, stack, aux, .. }) = vm.trace().unwrap().last() else {
+                panic!("no trace event");
+            };
+            assert_eq!((*pc, stack, *aux), (before.pc, &before.stack, before.aux));
+        }
+        let state = vm.state();
+        assert!(state.pc < program.len());
+        assert_eq!(state.output_so_far, vm.output().as_str());
+    }
+
+    #[test]
+    fn channels_are_kept_apart() {
+        let source = [
+            Insn::new(Opcode::InN).set_value(1),
+            Insn::new(Opcode::OutN).set_value(0),
+            Insn::new(Opcode::In),
+            Insn::new(Opcode::OutN).set_value(1),
+            Insn::new(Opcode::InN).set_value(0),
+            Insn::new(Opcode::Out),
+            Insn::new(Opcode::InN).set_value(1),
+            Insn::new(Opcode::OutN).set_value(2),
+            Insn::new(Opcode::Exit),
+        ];
+        let program = crate::asm::assemble(&source).unwrap();
+        let opts = VmOptions {
+            input_channels: vec!["xy".to_string()],
+            ..VmOptions::default()
+        };
+        let mut vm = VmBuilder::new(&program)
+            .input("ab")
+            .build()
+            .with_options(&opts);
+        vm.run().unwrap();
+        assert_eq!(vm.channel_output(0), "xb");
+        assert_eq!(vm.output().as_str(), "xb");
+        assert_eq!(vm.channel_output(1), "a");
+        assert_eq!(vm.channel_output(2), "y");
+        assert_eq!(vm.channel_output(3), "");
+    }
+
+    #[test]
+    fn fixed_vm_calls_within_its_return_stack() {
+        let program = parse("call f\npush 'b'\nout\nexit\nf: push 'a'\nout\nret").unwrap();
+        let mut output = String::new();
+        VmDefault::new().run(&program, "", &mut output).unwrap();
+        assert_eq!(output, "ab");
+
+        let program = parse("f: call f").unwrap();
+        let mut vm = VmFixed::<4, 2>::new();
+        assert_eq!(
+            vm.run(&program, "", String::new()),
+            Err(VmError::ReturnStackOverflow { at_pc: 0 })
+        );
+    }
+
+    #[test]
+    fn fixed_vm_rejects_opcodes_needing_more_state() {
+        for (source, opcode) in [
+            ("push 0\nload", Opcode::Load),
+            ("push 0\npush 0\nstore", Opcode::Store),
+            ("inbyte", Opcode::InByte),
+            ("inn 1", Opcode::InN),
+        ] {
+            let program = parse(&format!("{}\nexit", source)).unwrap();
+            assert_eq!(
+                VmDefault::new().run(&program, "", String::new()),
+                Err(VmError::Unimplemented(opcode)),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn flamegraph_nests_frames_for_calls() {
+        let program = parse("call f\nexit\nf: call g\nret\ng: nop\nret").unwrap();
+        let (_, events) = run_traced(&program, "").unwrap();
+        assert_eq!(
+            trace_to_flamegraph(&events),
+            "main;call@0000 1\n\
+             main;exit@0002 1\n\
+             main;fn@0003;call@0003 1\n\
+             main;fn@0003;fn@0006;nop@0006 1\n\
+             main;fn@0003;fn@0006;ret@0007 1\n\
+             main;fn@0003;ret@0005 1\n"
+        );
+    }
+
+    #[test]
+    fn jump_outside_program_is_invalid_target() {
+        let program = [Opcode::Jmp as u8, 40];
+        assert_eq!(
+            exec(&program),
+            Err(VmError::InvalidJumpTarget {
+                at_pc: 0,
+                target: 40
+            })
+        );
+        let program = [Opcode::Push0 as u8, Opcode::Beqz as u8, 3];
+        assert_eq!(
+            exec(&program),
+            Err(VmError::InvalidJumpTarget {
+                at_pc: 1,
+                target: 3
+            })
+        );
+        let program = [Opcode::Push1 as u8, Opcode::Beqz as u8, 3];
+        assert_eq!(
+            exec(&program),
+            Err(VmError::PcOutOfBounds {
+                pc: 3,
+                program_len: 3
+            })
+        );
+    }
+
+    #[cfg(feature = "web")]
+    #[test]
+    fn errors_map_to_http_status_codes() {
+        use http::StatusCode;
+
+        let cases = [
+            (
+                VmError::StackUnderflow { at_pc: 0 },
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                VmError::InvalidJumpTarget {
+                    at_pc: 0,
+                    target: 9,
+                },
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                VmError::StepLimitExceeded { limit: 10 },
+                StatusCode::REQUEST_TIMEOUT,
+            ),
+            (
+                VmError::PcOutOfBounds {
+                    pc: 9,
+                    program_len: 2,
+                },
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        ];
+        for (error, status) in cases {
+            assert_eq!(StatusCode::from(error), status);
+        }
+    }
 }
@@ -0,0 +1,45 @@
+//! JavaScript bindings for running the VM in a browser
+
+use serde::Deserialize;
+use wasm_bindgen::prelude::*;
+
+use crate::asm::{self, Insn};
+use crate::vm::{self, Opcode};
+
+/// Instruction as described in the JSON accepted by `assemble_wasm()`.
+///
+/// Branch operands are absolute code offsets rather than labels.
+#[derive(Deserialize)]
+struct JsonInsn {
+    opcode: String,
+    value: Option<u32>,
+}
+
+fn to_js_error(err: impl std::fmt::Display) -> JsValue {
+    JsValue::from_str(&err.to_string())
+}
+
+/// Runs `bytecode` with `input` and returns its output.
+#[wasm_bindgen]
+pub fn run_wasm(bytecode: &[u8], input: &str) -> Result<String, JsValue> {
+    vm::run(bytecode, input).map_err(to_js_error)
+}
+
+/// Assembles a JSON array of `{"opcode": "push", "value": 4}` objects into
+/// bytecode.
+#[wasm_bindgen]
+pub fn assemble_wasm(json: &str) -> Result<Vec<u8>, JsValue> {
+    let source: Vec<JsonInsn> = serde_json::from_str(json).map_err(to_js_error)?;
+    let insns = source
+        .iter()
+        .map(|insn| {
+            let opcode = Opcode::try_from_mnemonic(&insn.opcode)?;
+            Ok(match insn.value {
+                Some(value) => Insn::new(opcode).set_value(value),
+                None => Insn::new(opcode),
+            })
+        })
+        .collect::<Result<Vec<_>, asm::AsmError>>()
+        .map_err(to_js_error)?;
+    asm::assemble(&insns).map_err(to_js_error)
+}